@@ -0,0 +1,10 @@
+use crate::error::Result;
+
+impl super::BackupConfig {
+    pub(super) fn execute(&self) -> Result<()> {
+        log::info!("Backup ...");
+        self.storage.create_checkpoint(&self.output)?;
+        println!("the snapshot is created in [{}]", self.output.display());
+        Ok(())
+    }
+}
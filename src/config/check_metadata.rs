@@ -0,0 +1,35 @@
+use ckb_types::{prelude::*, H256};
+
+use crate::error::{Error, Result};
+
+impl super::CheckMetadataConfig {
+    pub(super) fn execute(&self) -> Result<()> {
+        log::info!("Check metadata ...");
+        let mut metadata = self.storage.get_metadata()?;
+        metadata.keystore_password = self.keystore_password.clone();
+        let accounts = metadata.all_accounts()?;
+        let mut failed = 0;
+        for (index, account) in accounts.iter().enumerate() {
+            match metadata.lock_script(account) {
+                Ok(script) => {
+                    let hash: H256 = script.calc_script_hash().unpack();
+                    println!("account#{} [{}]: {:#x}", index, account.lock_id, hash);
+                }
+                Err(err) => {
+                    failed += 1;
+                    println!("account#{} [{}]: FAILED, {}", index, account.lock_id, err);
+                }
+            }
+        }
+        if failed > 0 {
+            let errmsg = format!(
+                "failed to build the lock scripts for {} of {} accounts",
+                failed,
+                accounts.len()
+            );
+            Err(Error::config(errmsg))
+        } else {
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,46 @@
+use std::{fs, io};
+
+use ckb_jsonrpc_types as rpc;
+use ckb_types::H256;
+use serde::Serialize;
+
+use crate::error::{Error, Result};
+
+#[derive(Serialize)]
+struct DumpedCell {
+    #[serde(flatten)]
+    out_point: rpc::OutPoint,
+    capacity: rpc::Capacity,
+    lock_hash: H256,
+}
+
+impl super::DumpCellsConfig {
+    pub(super) fn execute(&self) -> Result<()> {
+        log::info!("Dump cells ...");
+        let cells = self
+            .storage
+            .load_cells()?
+            .into_iter()
+            .map(|cell| DumpedCell {
+                out_point: cell.out_point.into(),
+                capacity: cell.cell_info.capacity.into(),
+                lock_hash: cell.cell_info.lock_hash,
+            })
+            .collect::<Vec<_>>();
+        log::info!("dump {} cells", cells.len());
+        if let Some(ref path) = self.output {
+            let file = fs::File::create(path).map_err(|err| {
+                let errmsg = format!(
+                    "failed to create the file [{}] to dump cells since {}",
+                    path.display(),
+                    err
+                );
+                Error::config(errmsg)
+            })?;
+            serde_json::to_writer_pretty(io::BufWriter::new(file), &cells)
+        } else {
+            serde_json::to_writer_pretty(io::stdout(), &cells)
+        }
+        .map_err(Error::runtime)
+    }
+}
@@ -0,0 +1,90 @@
+use std::{collections::HashMap, env, fs, time};
+
+use ckb_types::{core, H256};
+
+use super::run::{block_changes, min_inputs_shannons};
+use crate::{
+    error::{Error, Result},
+    storage::Storage,
+    types::LockInfo,
+};
+
+impl super::EstimateSyncConfig {
+    pub(super) fn execute(&self) -> Result<()> {
+        log::info!("Estimate sync ...");
+        let mut metadata = self.storage.get_metadata()?;
+        metadata.keystore_password = self.keystore_password.clone();
+        let accounts = metadata.accounts()?;
+        let next_num = self
+            .storage
+            .get_next_number()?
+            .unwrap_or(metadata.start_block()?.number);
+        let tip_num = self.client.get_tip_block_number()?;
+        if next_num > tip_num {
+            println!("already synchronized to block#{}", next_num - 1);
+            return Ok(());
+        }
+        let total_blocks = tip_num - next_num + 1;
+        let samples = self.samples.max(1).min(total_blocks);
+        let step = total_blocks / samples;
+        // The sampled blocks are applied to a scratch storage, since the stored one is only
+        // read, then the writes are timed as well.
+        let scratch_dir = env::temp_dir().join(format!(
+            "ckb-simulator-estimate-sync-{}",
+            std::process::id()
+        ));
+        let elapsed = Storage::init(&scratch_dir, &Default::default()).and_then(|scratch| {
+            let nums = (0..samples).map(|i| next_num + i * step);
+            self.sample_blocks(&scratch, &accounts, nums)
+        });
+        if let Err(err) = fs::remove_dir_all(&scratch_dir) {
+            log::warn!(
+                "failed to remove the scratch storage [{}] since {}",
+                scratch_dir.display(),
+                err
+            );
+        }
+        let elapsed = elapsed?;
+        let per_block_secs = elapsed.as_secs_f64() / samples as f64;
+        println!(
+            "blocks to synchronize: {} (from block#{} to block#{})",
+            total_blocks, next_num, tip_num
+        );
+        println!(
+            "sampled {} blocks in {:.3}s, {:.3}ms per block",
+            samples,
+            elapsed.as_secs_f64(),
+            per_block_secs * 1000.0
+        );
+        println!(
+            "estimated duration: {:.0}s",
+            per_block_secs * total_blocks as f64
+        );
+        Ok(())
+    }
+
+    // Fetch the blocks and apply their changes as the synchronization does, returns the time it
+    // takes.
+    fn sample_blocks<I>(
+        &self,
+        scratch: &Storage,
+        accounts: &HashMap<H256, LockInfo>,
+        nums: I,
+    ) -> Result<time::Duration>
+    where
+        I: Iterator<Item = core::BlockNumber>,
+    {
+        let min_shannons = min_inputs_shannons(&self.config.generator);
+        let started_at = time::Instant::now();
+        for num in nums {
+            let block = self.client.get_block_by_number(num)?.ok_or_else(|| {
+                let errmsg = format!("block#{} should exists but CKB node returns None", num);
+                Error::runtime(errmsg)
+            })?;
+            let (adds, removes, _) = block_changes(&block, accounts, min_shannons);
+            log::trace!("block#{} has {} cells for the accounts", num, adds.len());
+            scratch.apply_block_changes(adds, removes, num, block.header.hash)?;
+        }
+        Ok(started_at.elapsed())
+    }
+}
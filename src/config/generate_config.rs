@@ -0,0 +1,256 @@
+use std::{collections::HashMap, fs, path::Path, str::FromStr};
+
+use ckb_jsonrpc_types as rpc;
+use ckb_types::H256;
+use rand::{thread_rng, Rng as _};
+
+use crate::{
+    error::{Error, Result},
+    types::{
+        Account, BlockMeta, CellDep, DepType, LockScriptId, MetaData, MultisigConfig, OutPoint,
+        RunEnv, Script, ScriptHashType, SecretKey, StartBlock,
+    },
+};
+
+const METADATA_FILE: &str = "metadata.yaml";
+const RUN_FILE: &str = "run.yaml";
+
+// The comments of the top-level fields, the fields which are not listed have no comments.
+const METADATA_COMMENTS: &[(&str, &str)] = &[
+    (
+        "start_block",
+        "Collect available cells from this block, replace it with a block of your chain.",
+    ),
+    (
+        "lock_scripts",
+        "The lock script of the accounts, with the cell deps of the testnet.",
+    ),
+    (
+        "accounts",
+        "The secret key is generated randomly, fund its address before running.",
+    ),
+];
+const RUN_COMMENTS: &[(&str, &str)] = &[
+    (
+        "delay_blocks",
+        "Only synchronize the blocks which are this count behind the tip, to avoid forks.",
+    ),
+    (
+        "skip_empty_blocks",
+        "Skip the blocks which have no outputs for accounts.",
+    ),
+    (
+        "max_sync_blocks_per_round",
+        "Synchronize at most this count of blocks in each round.",
+    ),
+    (
+        "min_tip_age_secs",
+        "Do not synchronize the blocks which are created in this count of seconds.",
+    ),
+    (
+        "cell_source",
+        "Where to find the cells of accounts: \"block_sync\" or \"indexer\".",
+    ),
+    ("rng_seed", "Replay the same random choices when it is set."),
+    (
+        "metrics_addr",
+        "Serve the metrics in the Prometheus format on this address.",
+    ),
+    (
+        "pause_epochs",
+        "Only synchronize but not send transactions in these epochs.",
+    ),
+    (
+        "max_transactions",
+        "Stop after sent this count of transactions successfully.",
+    ),
+    (
+        "max_live_cells",
+        "Consolidate the smallest cells when more cells than this are tracked.",
+    ),
+    ("storage", "Tune the RocksDB of the storage."),
+    ("generator", "How to construct transactions."),
+    ("client", "How to send transactions to the CKB node."),
+];
+
+impl super::GenerateConfigConfig {
+    pub(super) fn execute(&self) -> Result<()> {
+        log::info!("Generate config ...");
+        let metadata = example_metadata(self.lock_id)?;
+        let mut run_env = RunEnv::from_profile(&self.profile, "")?;
+        run_env.generator.locks_weights.insert(self.lock_id, 1);
+        // The name of the lock script as it is in the config files.
+        let lock_name = serde_yaml::to_value(self.lock_id).map_err(Error::runtime)?;
+        let header = format!(
+            "# Generated for the lock script \"{}\" and the profile \"{}\".\n\
+            # All options are documented in the \"configs\" directory of the repository.\n",
+            lock_name.as_str().unwrap_or_default(),
+            self.profile
+        );
+        let metadata_yaml = annotate(&header, &metadata.to_string(), METADATA_COMMENTS);
+        let run_yaml = annotate(&header, &run_env.to_string(), RUN_COMMENTS);
+        // Never write a scaffold which can not be loaded.
+        MetaData::from_str(&metadata_yaml).map_err(Error::runtime)?;
+        RunEnv::from_str(&run_yaml).map_err(Error::runtime)?;
+        fs::create_dir_all(&self.output_dir).map_err(|err| {
+            let errmsg = format!(
+                "failed to create the directory [{}] since {}",
+                self.output_dir.display(),
+                err
+            );
+            Error::config(errmsg)
+        })?;
+        let metadata_path = self.output_dir.join(METADATA_FILE);
+        let run_path = self.output_dir.join(RUN_FILE);
+        if !self.force {
+            for path in &[&metadata_path, &run_path] {
+                if path.exists() {
+                    let errmsg = format!(
+                        "the file [{}] exists, pass --force to overwrite it",
+                        path.display()
+                    );
+                    return Err(Error::config(errmsg));
+                }
+            }
+        }
+        write_file(&metadata_path, &metadata_yaml)?;
+        write_file(&run_path, &run_yaml)?;
+        println!("{}", metadata_path.display());
+        println!("{}", run_path.display());
+        Ok(())
+    }
+}
+
+fn example_metadata(lock_id: LockScriptId) -> Result<MetaData> {
+    let start_block = BlockMeta {
+        number: 1_256_000,
+        hash: h256("a67aeff9e3a5978db1902c13a6a31bc9de517af4bd4c245aabd0036c02efc8ea")?,
+    };
+    let mut lock_scripts = HashMap::new();
+    lock_scripts.insert(lock_id, example_lock_script(lock_id)?);
+    let multisig = if lock_id == LockScriptId::Secp256K1Multisig {
+        Some(MultisigConfig {
+            require_first_n: 0,
+            threshold: 1,
+            other_secret_keys: Vec::new(),
+            other_pubkey_hashes: Vec::new(),
+        })
+    } else {
+        None
+    };
+    let account = Account {
+        secret_key: SecretKey::Inline(rpc::JsonBytes::from_vec(random_secret_key())),
+        lock_id,
+        multisig,
+    };
+    Ok(MetaData {
+        start_block: StartBlock::Block(start_block),
+        lock_scripts,
+        accounts: vec![account],
+        derived_accounts: Vec::new(),
+        storage: Default::default(),
+        keystore_password: None,
+    })
+}
+
+// The same lock scripts as the examples in "configs/init.yaml".
+fn example_lock_script(lock_id: LockScriptId) -> Result<Script> {
+    let secp_dep_group = cell_dep(
+        "f8de3bb47d055cdf460d93a2a6e1b05f7432f9777c8c474abf4eec1d4aee5d37",
+        0,
+        DepType::DepGroup,
+    )?;
+    let (code_hash, cell_deps) = match lock_id {
+        LockScriptId::Secp256K1Blake160 => (
+            "9bd7e06f3ecf4be0f2fcd2188b23f1b9fcc88e5d4b65a8637b17723bbda3cce8",
+            vec![secp_dep_group],
+        ),
+        LockScriptId::PwLockK1Acpl => (
+            "58c5f491aba6d61678b7cf7edf4910b1f5e00ec0cde2f42e0abb4fd9aff25a63",
+            vec![
+                cell_dep(
+                    "57a62003daeab9d54aa29b944fc3b451213a5ebdf2e232216a3cfed0dde61b38",
+                    0,
+                    DepType::Code,
+                )?,
+                secp_dep_group,
+            ],
+        ),
+        LockScriptId::Secp256K1Multisig => (
+            "5c5069eb0857efc65e1bca0c07df34c31663b3622fd3876c876320fc9634e2a8",
+            vec![cell_dep(
+                "f8de3bb47d055cdf460d93a2a6e1b05f7432f9777c8c474abf4eec1d4aee5d37",
+                1,
+                DepType::DepGroup,
+            )?],
+        ),
+        LockScriptId::AnyoneCanPay => (
+            "d369597ff47f29fbc0d47d2e3775370d1250b85140c670e4718af712983a2354",
+            vec![cell_dep(
+                "4153a2014952d7cac45f285ce9a7c5c0c0e1b21f2d378b82ac1433cb11c25c4d",
+                0,
+                DepType::DepGroup,
+            )?],
+        ),
+    };
+    Ok(Script {
+        code_hash: h256(code_hash)?,
+        hash_type: ScriptHashType::Type,
+        cell_deps,
+        acp: None,
+    })
+}
+
+fn cell_dep(tx_hash: &str, index: u32, dep_type: DepType) -> Result<CellDep> {
+    Ok(CellDep {
+        out_point: OutPoint {
+            tx_hash: h256(tx_hash)?,
+            index,
+        },
+        dep_type,
+    })
+}
+
+fn h256(hex: &str) -> Result<H256> {
+    H256::from_str(hex).map_err(Error::runtime)
+}
+
+fn random_secret_key() -> Vec<u8> {
+    let mut rng = thread_rng();
+    loop {
+        let bytes: [u8; 32] = rng.gen();
+        if secp256k1::SecretKey::from_slice(&bytes).is_ok() {
+            return bytes.to_vec();
+        }
+    }
+}
+
+// Put the comments before the top-level fields.
+fn annotate(header: &str, yaml: &str, comments: &[(&str, &str)]) -> String {
+    let mut output = header.to_owned();
+    for line in yaml.lines() {
+        let comment = comments
+            .iter()
+            .find(|(key, _)| {
+                line.strip_prefix(key)
+                    .map(|rest| rest.starts_with(':'))
+                    .unwrap_or(false)
+            })
+            .map(|(_, comment)| comment);
+        if let Some(comment) = comment {
+            output.push_str("# ");
+            output.push_str(comment);
+            output.push('\n');
+        }
+        output.push_str(line);
+        output.push('\n');
+    }
+    output
+}
+
+fn write_file(path: &Path, content: &str) -> Result<()> {
+    fs::write(path, content).map_err(|err| {
+        let errmsg = format!("failed to write [{}] since {}", path.display(), err);
+        Error::config(errmsg)
+    })
+}
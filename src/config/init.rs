@@ -1,10 +1,165 @@
-use crate::error::Result;
+use ckb_jsonrpc_types as rpc;
+use ckb_types::{bytes, packed, prelude::*};
+
+use crate::{
+    client::Client,
+    error::{Error, Result},
+    types::{BlockMeta, DepType, LockScriptId, MetaData, Script, ScriptHashType, StartBlock},
+};
 
 impl super::InitConfig {
     pub(super) fn execute(&self) -> Result<()> {
         log::info!("Init ...");
-        let _ = self.config.accounts()?;
-        self.storage.put_metadata(&self.config)?;
+        let mut config = self.config.clone();
+        match config.start_block {
+            StartBlock::Epoch(ref epoch) => {
+                let cli = self.client.as_ref().ok_or_else(|| {
+                    Error::config("the start block is specified by epoch, requires a JSON-RPC url")
+                })?;
+                let block = resolve_epoch(cli, epoch.epoch)?;
+                log::info!(
+                    "the start block of epoch#{} is block#{} ({:#x})",
+                    epoch.epoch,
+                    block.number,
+                    block.hash
+                );
+                config.start_block = StartBlock::Block(block);
+            }
+            // Check the start block only when the JSON-RPC is provided.
+            StartBlock::Block(ref block) => {
+                if let Some(ref cli) = self.client {
+                    check_start_block(cli, block)?;
+                }
+            }
+        }
+        let _ = config.accounts()?;
+        if self.check_dep_groups {
+            let cli = self
+                .client
+                .as_ref()
+                .ok_or_else(|| Error::config("checking the dep groups requires a JSON-RPC url"))?;
+            check_dep_groups(cli, &config)?;
+        }
+        self.storage.put_metadata(&config)?;
         Ok(())
     }
 }
+
+fn check_start_block(cli: &Client, block: &BlockMeta) -> Result<()> {
+    let header = cli.get_header_by_number(block.number)?.ok_or_else(|| {
+        let errmsg = format!("block#{} is not found in the CKB node", block.number);
+        Error::runtime(errmsg)
+    })?;
+    if header.hash != block.hash {
+        let errmsg = format!(
+            "the hash of the start block#{} should be {:#x} in the CKB node, but got {:#x}",
+            block.number, header.hash, block.hash
+        );
+        return Err(Error::config(errmsg));
+    }
+    Ok(())
+}
+
+// Expand the dep groups of each lock script which has any, check that they are vectors of out
+// points, and the code of the lock script is in the expanded cell deps.
+fn check_dep_groups(cli: &Client, config: &MetaData) -> Result<()> {
+    let mut ids = config.lock_scripts.keys().copied().collect::<Vec<_>>();
+    ids.sort();
+    for id in ids {
+        let script = &config.lock_scripts[&id];
+        if !script
+            .cell_deps
+            .iter()
+            .any(|cell_dep| cell_dep.dep_type == DepType::DepGroup)
+        {
+            continue;
+        }
+        let mut code_found = false;
+        for cell_dep in &script.cell_deps {
+            let out_point = cell_dep.out_point.pack();
+            let out_points = match cell_dep.dep_type {
+                DepType::Code => vec![out_point],
+                DepType::DepGroup => {
+                    let (_, data) = get_live_cell(cli, id, &out_point)?;
+                    let out_points = packed::OutPointVec::from_slice(&data).map_err(|err| {
+                        let errmsg = format!(
+                            "the dep group {} of lock script [{}] is not a vector of out points \
+                            since {}",
+                            out_point, id, err
+                        );
+                        Error::config(errmsg)
+                    })?;
+                    log::info!(
+                        "the dep group {} of lock script [{}] has {} cells",
+                        out_point,
+                        id,
+                        out_points.len()
+                    );
+                    out_points.into_iter().collect()
+                }
+            };
+            for out_point in out_points {
+                let (output, data) = get_live_cell(cli, id, &out_point)?;
+                code_found |= is_code_of(script, &output, &data);
+            }
+        }
+        if !code_found {
+            let errmsg = format!(
+                "the code of lock script [{}] is not found in its cell deps, \
+                the dep groups may point at the wrong cells",
+                id
+            );
+            return Err(Error::config(errmsg));
+        }
+    }
+    Ok(())
+}
+
+fn get_live_cell(
+    cli: &Client,
+    id: LockScriptId,
+    out_point: &packed::OutPoint,
+) -> Result<(packed::CellOutput, bytes::Bytes)> {
+    let cell = cli.get_live_cell(out_point.clone().into(), true)?;
+    match cell.cell {
+        Some(rpc::CellInfo {
+            output,
+            data: Some(data),
+        }) if cell.status == "live" => Ok((output.into(), data.content.into_bytes())),
+        _ => {
+            let errmsg = format!(
+                "the cell dep {} of lock script [{}] is not a live cell, its status is {}",
+                out_point, id, cell.status
+            );
+            Err(Error::config(errmsg))
+        }
+    }
+}
+
+fn is_code_of(script: &Script, output: &packed::CellOutput, data: &bytes::Bytes) -> bool {
+    let code_hash = script.code_hash.pack();
+    match script.hash_type {
+        ScriptHashType::Data => packed::CellOutput::calc_data_hash(data) == code_hash,
+        ScriptHashType::Type => output
+            .type_()
+            .to_opt()
+            .map(|type_script| type_script.calc_script_hash() == code_hash)
+            .unwrap_or(false),
+    }
+}
+
+fn resolve_epoch(cli: &Client, epoch_number: u64) -> Result<BlockMeta> {
+    let epoch = cli.get_epoch_by_number(epoch_number)?.ok_or_else(|| {
+        let errmsg = format!("epoch#{} is not found in the CKB node", epoch_number);
+        Error::runtime(errmsg)
+    })?;
+    let number = epoch.start_number.into();
+    let header = cli.get_header_by_number(number)?.ok_or_else(|| {
+        let errmsg = format!("block#{} is not found in the CKB node", number);
+        Error::runtime(errmsg)
+    })?;
+    Ok(BlockMeta {
+        number,
+        hash: header.hash,
+    })
+}
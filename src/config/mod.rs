@@ -1,41 +1,153 @@
 use std::{
     convert::TryFrom, fmt::Display, fs::OpenOptions, io::Read as _, path::PathBuf, str::FromStr,
+    sync::Arc,
 };
 
+use ckb_jsonrpc_types as rpc;
+use ckb_types::H256;
+
 use crate::{
-    client::Client,
+    client::{self, Client, FailoverClient, DEFAULT_MAX_RETRIES, DEFAULT_REQUEST_TIMEOUT},
     error::{Error, Result},
+    logger::LogFormat,
     storage::Storage,
-    types::{MetaData, RunEnv},
+    telemetry::Telemetry,
+    types::{CsvAccount, KeystorePassword, LockScriptId, MetaData, RunEnv},
 };
 
+mod backup;
+mod check_metadata;
+mod dump_cells;
+mod estimate_sync;
+mod generate_config;
 mod init;
+mod reindex;
+mod replay;
+mod restore;
 mod run;
+mod status;
+mod validate;
 
+#[allow(clippy::large_enum_variant)]
 pub(crate) enum AppConfig {
     Init(InitConfig),
     Run(RunConfig),
+    CheckMetadata(CheckMetadataConfig),
+    EstimateSync(EstimateSyncConfig),
+    Validate(ValidateConfig),
+    Status(StatusConfig),
+    DumpCells(DumpCellsConfig),
+    Backup(BackupConfig),
+    Restore(RestoreConfig),
+    Reindex(ReindexConfig),
+    Replay(ReplayConfig),
+    GenerateConfig(GenerateConfigConfig),
 }
 
 pub(crate) struct InitConfig {
     pub(crate) storage: Storage,
     pub(crate) config: MetaData,
+    pub(crate) client: Option<Client>,
+    pub(crate) check_dep_groups: bool,
 }
 
-pub(crate) struct RunConfig {
+pub(crate) struct CheckMetadataConfig {
+    pub(crate) storage: Storage,
+    pub(crate) keystore_password: Option<KeystorePassword>,
+}
+
+pub(crate) struct EstimateSyncConfig {
     pub(crate) storage: Storage,
     pub(crate) client: Client,
     pub(crate) config: RunEnv,
+    pub(crate) samples: u64,
+    pub(crate) keystore_password: Option<KeystorePassword>,
+}
+
+pub(crate) struct StatusConfig {
+    pub(crate) storage: Storage,
+}
+
+pub(crate) struct BackupConfig {
+    pub(crate) storage: Storage,
+    pub(crate) output: PathBuf,
+}
+
+pub(crate) struct RestoreConfig {
+    // The snapshot, it is never written.
+    pub(crate) backup: Storage,
+    pub(crate) data_dir: PathBuf,
+}
+
+pub(crate) struct ReindexConfig {
+    pub(crate) storage: Storage,
+    pub(crate) client: FailoverClient,
+    pub(crate) config: RunEnv,
+    pub(crate) keystore_password: Option<KeystorePassword>,
+}
+
+pub(crate) struct ReplayConfig {
+    pub(crate) client: FailoverClient,
+    pub(crate) tx_log: PathBuf,
+    pub(crate) outputs_validator: Option<rpc::OutputsValidator>,
+    pub(crate) interval: u64,
+    pub(crate) skip_failed: bool,
+}
+
+pub(crate) struct GenerateConfigConfig {
+    pub(crate) output_dir: PathBuf,
+    pub(crate) lock_id: LockScriptId,
+    pub(crate) profile: String,
+    pub(crate) force: bool,
+}
+
+pub(crate) struct DumpCellsConfig {
+    pub(crate) storage: Storage,
+    pub(crate) output: Option<PathBuf>,
+}
+
+#[allow(clippy::large_enum_variant)]
+pub(crate) enum ValidateConfig {
+    Init(MetaData),
+    Run(RunEnv),
+    // Check the config of run against the metadata.
+    RunWithMetadata(RunEnv, MetaData),
+}
+
+pub(crate) struct RunConfig {
+    pub(crate) storage: Storage,
+    pub(crate) client: Arc<FailoverClient>,
+    pub(crate) config: Arc<RunEnv>,
+    pub(crate) settlement_accounts: Option<Vec<H256>>,
+    pub(crate) telemetry: Arc<Telemetry>,
+    pub(crate) ledger_file: Option<PathBuf>,
+    pub(crate) record_tx_log: Option<PathBuf>,
+    pub(crate) dump_tx_bytes: Option<TxBytesDump>,
+    pub(crate) failed_tx_dir: Option<PathBuf>,
+    pub(crate) dry_run: bool,
+    pub(crate) verify_signatures: bool,
+    pub(crate) dry_run_check: bool,
+    pub(crate) verify_inputs: bool,
+    pub(crate) use_indexer: bool,
+    pub(crate) allow_ibd: bool,
+    pub(crate) keystore_password: Option<KeystorePassword>,
+}
+
+pub(crate) enum TxBytesDump {
+    Log,
+    File(PathBuf),
 }
 
 impl AppConfig {
-    pub(crate) fn load() -> Result<Self> {
+    // The logger is initialized before the config is loaded, since loading may log.
+    pub(crate) fn load<F: FnOnce(LogFormat)>(init_logger: F) -> Result<Self> {
         let yaml = clap::load_yaml!("cli.yaml");
         let matches = clap::App::from_yaml(yaml)
             .version(clap::crate_version!())
-            .author(clap::crate_authors!("\n"))
+            .author(clap::crate_authors!())
             .about(clap::crate_description!())
             .get_matches();
+        init_logger(parse_from_str(&matches, "log-format")?);
         Self::try_from(&matches)
     }
 
@@ -44,6 +156,16 @@ impl AppConfig {
         match self {
             Self::Init(ref cfg) => cfg.execute(),
             Self::Run(ref cfg) => cfg.execute(),
+            Self::CheckMetadata(ref cfg) => cfg.execute(),
+            Self::EstimateSync(ref cfg) => cfg.execute(),
+            Self::Validate(ref cfg) => cfg.execute(),
+            Self::Status(ref cfg) => cfg.execute(),
+            Self::DumpCells(ref cfg) => cfg.execute(),
+            Self::Backup(ref cfg) => cfg.execute(),
+            Self::Restore(ref cfg) => cfg.execute(),
+            Self::Reindex(ref cfg) => cfg.execute(),
+            Self::Replay(ref cfg) => cfg.execute(),
+            Self::GenerateConfig(ref cfg) => cfg.execute(),
         }
     }
 }
@@ -54,6 +176,36 @@ impl<'a> TryFrom<&'a clap::ArgMatches<'a>> for AppConfig {
         match matches.subcommand() {
             ("init", Some(submatches)) => InitConfig::try_from(submatches).map(AppConfig::Init),
             ("run", Some(submatches)) => RunConfig::try_from(submatches).map(AppConfig::Run),
+            ("check-metadata", Some(submatches)) => {
+                CheckMetadataConfig::try_from(submatches).map(AppConfig::CheckMetadata)
+            }
+            ("estimate-sync", Some(submatches)) => {
+                EstimateSyncConfig::try_from(submatches).map(AppConfig::EstimateSync)
+            }
+            ("validate", Some(submatches)) => {
+                ValidateConfig::try_from(submatches).map(AppConfig::Validate)
+            }
+            ("status", Some(submatches)) => {
+                StatusConfig::try_from(submatches).map(AppConfig::Status)
+            }
+            ("dump-cells", Some(submatches)) => {
+                DumpCellsConfig::try_from(submatches).map(AppConfig::DumpCells)
+            }
+            ("backup", Some(submatches)) => {
+                BackupConfig::try_from(submatches).map(AppConfig::Backup)
+            }
+            ("restore", Some(submatches)) => {
+                RestoreConfig::try_from(submatches).map(AppConfig::Restore)
+            }
+            ("reindex", Some(submatches)) => {
+                ReindexConfig::try_from(submatches).map(AppConfig::Reindex)
+            }
+            ("replay", Some(submatches)) => {
+                ReplayConfig::try_from(submatches).map(AppConfig::Replay)
+            }
+            ("generate-config", Some(submatches)) => {
+                GenerateConfigConfig::try_from(submatches).map(AppConfig::GenerateConfig)
+            }
             (subcmd, _) => Err(Error::config(format!("subcommand {}", subcmd))),
         }
     }
@@ -63,28 +215,312 @@ impl<'a> TryFrom<&'a clap::ArgMatches<'a>> for InitConfig {
     type Error = Error;
     fn try_from(matches: &'a clap::ArgMatches) -> Result<Self> {
         let data_dir = parse_from_str::<PathBuf>(matches, "data-dir")?;
-        let config = parse_from_file::<MetaData>(matches, "config")?;
-        let storage = Storage::init(data_dir)?;
-        Ok(Self { storage, config })
+        let mut config = parse_from_file::<MetaData>(matches, "config")?;
+        config.keystore_password = parse_keystore_password(matches);
+        if let Some(file) = matches.value_of("accounts-from-csv") {
+            let csv_accounts = CsvAccount::load_from_csv(file)?;
+            config.merge_csv_accounts(csv_accounts)?;
+        }
+        let client = matches
+            .value_of("jsonrpc-url")
+            .map(client::parse_url)
+            .transpose()?
+            .map(|url| Client::new(&url, DEFAULT_REQUEST_TIMEOUT, DEFAULT_MAX_RETRIES))
+            .transpose()?;
+        let check_dep_groups = matches.is_present("check-dep-groups");
+        let storage = Storage::init(data_dir, &config.storage)?;
+        Ok(Self {
+            storage,
+            config,
+            client,
+            check_dep_groups,
+        })
     }
 }
 
-impl<'a> TryFrom<&'a clap::ArgMatches<'a>> for RunConfig {
+impl<'a> TryFrom<&'a clap::ArgMatches<'a>> for CheckMetadataConfig {
+    type Error = Error;
+    fn try_from(matches: &'a clap::ArgMatches) -> Result<Self> {
+        let data_dir = parse_from_str::<PathBuf>(matches, "data-dir")?;
+        let keystore_password = parse_keystore_password(matches);
+        let storage = Storage::load_read_only(data_dir)?;
+        Ok(Self {
+            storage,
+            keystore_password,
+        })
+    }
+}
+
+impl<'a> TryFrom<&'a clap::ArgMatches<'a>> for StatusConfig {
+    type Error = Error;
+    fn try_from(matches: &'a clap::ArgMatches) -> Result<Self> {
+        let data_dir = parse_from_str::<PathBuf>(matches, "data-dir")?;
+        let storage = Storage::load_read_only(data_dir)?;
+        Ok(Self { storage })
+    }
+}
+
+impl<'a> TryFrom<&'a clap::ArgMatches<'a>> for BackupConfig {
     type Error = Error;
     fn try_from(matches: &'a clap::ArgMatches) -> Result<Self> {
         let data_dir = parse_from_str::<PathBuf>(matches, "data-dir")?;
-        let jsonrpc_url = parse_from_str::<url::Url>(matches, "jsonrpc-url")?;
-        let config = parse_from_file::<RunEnv>(matches, "config")?;
-        let storage = Storage::load(data_dir)?;
-        let client = Client::new(&jsonrpc_url)?;
+        let output = parse_from_str::<PathBuf>(matches, "output")?;
+        // The storage can not be locked when the simulator is running, then read it only.
+        let storage = Storage::load_unmigrated(&data_dir, &Default::default()).or_else(|err| {
+            log::info!("read the storage only since {}", err);
+            Storage::load_read_only_unmigrated(&data_dir)
+        })?;
+        Ok(Self { storage, output })
+    }
+}
+
+impl<'a> TryFrom<&'a clap::ArgMatches<'a>> for RestoreConfig {
+    type Error = Error;
+    fn try_from(matches: &'a clap::ArgMatches) -> Result<Self> {
+        let backup_dir = parse_from_str::<PathBuf>(matches, "backup")?;
+        let data_dir = parse_from_str::<PathBuf>(matches, "data-dir")?;
+        let backup = Storage::load_read_only_unmigrated(backup_dir)?;
+        Ok(Self { backup, data_dir })
+    }
+}
+
+impl<'a> TryFrom<&'a clap::ArgMatches<'a>> for ReindexConfig {
+    type Error = Error;
+    fn try_from(matches: &'a clap::ArgMatches) -> Result<Self> {
+        if !matches.is_present("confirm") {
+            return Err(Error::config(
+                "reindex removes all cells in the storage, pass --confirm to continue",
+            ));
+        }
+        let data_dir = parse_from_str::<PathBuf>(matches, "data-dir")?;
+        let jsonrpc_urls = parse_urls(matches, "jsonrpc-url")?;
+        // Synchronize as the run does, such as the delay blocks and the dust cells.
+        let mut config = if matches.is_present("config") {
+            parse_from_file::<RunEnv>(matches, "config")?
+        } else {
+            RunEnv::from_profile("light", "")?
+        };
+        let keystore_password = parse_keystore_password(matches);
+        let storage = Storage::load_with_config(data_dir, &config.storage)?;
+        if !matches.is_present("config") {
+            config.seed_locks_weights(&storage.get_metadata()?)?;
+        }
+        // The same checks as the command "run".
+        check_problems(config.problems())?;
+        let client = FailoverClient::new(
+            &jsonrpc_urls,
+            config.client.request_timeout(),
+            config.client.max_retries(),
+        )?;
         Ok(Self {
             storage,
             client,
             config,
+            keystore_password,
         })
     }
 }
 
+impl<'a> TryFrom<&'a clap::ArgMatches<'a>> for ReplayConfig {
+    type Error = Error;
+    fn try_from(matches: &'a clap::ArgMatches) -> Result<Self> {
+        let jsonrpc_urls = parse_urls(matches, "jsonrpc-url")?;
+        let tx_log = parse_from_str::<PathBuf>(matches, "tx-log")?;
+        let outputs_validator = match matches.value_of("outputs-validator") {
+            Some("default") => Some(rpc::OutputsValidator::Default),
+            Some("passthrough") => Some(rpc::OutputsValidator::Passthrough),
+            Some(validator) => {
+                let errmsg = format!("unknown outputs validator {}", validator);
+                return Err(Error::config(errmsg));
+            }
+            None => None,
+        };
+        let interval = parse_from_str::<u64>(matches, "interval")?;
+        let skip_failed = matches.is_present("skip-failed");
+        let client =
+            FailoverClient::new(&jsonrpc_urls, DEFAULT_REQUEST_TIMEOUT, DEFAULT_MAX_RETRIES)?;
+        Ok(Self {
+            client,
+            tx_log,
+            outputs_validator,
+            interval,
+            skip_failed,
+        })
+    }
+}
+
+impl<'a> TryFrom<&'a clap::ArgMatches<'a>> for GenerateConfigConfig {
+    type Error = Error;
+    fn try_from(matches: &'a clap::ArgMatches) -> Result<Self> {
+        let output_dir = parse_from_str::<PathBuf>(matches, "output-dir")?;
+        let lock_id = parse_from_str::<LockScriptId>(matches, "lock")?;
+        let profile = parse_from_str::<String>(matches, "profile")?;
+        let force = matches.is_present("force");
+        Ok(Self {
+            output_dir,
+            lock_id,
+            profile,
+            force,
+        })
+    }
+}
+
+impl<'a> TryFrom<&'a clap::ArgMatches<'a>> for DumpCellsConfig {
+    type Error = Error;
+    fn try_from(matches: &'a clap::ArgMatches) -> Result<Self> {
+        let data_dir = parse_from_str::<PathBuf>(matches, "data-dir")?;
+        let output = matches.value_of("output").map(PathBuf::from);
+        let storage = Storage::load_read_only(data_dir)?;
+        Ok(Self { storage, output })
+    }
+}
+
+impl<'a> TryFrom<&'a clap::ArgMatches<'a>> for EstimateSyncConfig {
+    type Error = Error;
+    fn try_from(matches: &'a clap::ArgMatches) -> Result<Self> {
+        let data_dir = parse_from_str::<PathBuf>(matches, "data-dir")?;
+        let jsonrpc_url = matches
+            .value_of("jsonrpc-url")
+            .ok_or_else(|| Error::argument_should_exist("jsonrpc-url"))
+            .and_then(client::parse_url)?;
+        let samples = parse_from_str::<u64>(matches, "samples")?;
+        // Skip the dust cells as the run does.
+        let config = if matches.is_present("config") {
+            parse_from_file::<RunEnv>(matches, "config")?
+        } else {
+            RunEnv::from_profile("light", "")?
+        };
+        let keystore_password = parse_keystore_password(matches);
+        let storage = Storage::load_read_only(data_dir)?;
+        let client = Client::new(&jsonrpc_url, DEFAULT_REQUEST_TIMEOUT, DEFAULT_MAX_RETRIES)?;
+        Ok(Self {
+            storage,
+            client,
+            config,
+            samples,
+            keystore_password,
+        })
+    }
+}
+
+impl<'a> TryFrom<&'a clap::ArgMatches<'a>> for ValidateConfig {
+    type Error = Error;
+    fn try_from(matches: &'a clap::ArgMatches) -> Result<Self> {
+        let keystore_password = parse_keystore_password(matches);
+        match matches.value_of("kind") {
+            Some("init") => {
+                if matches.is_present("metadata") {
+                    return Err(Error::config("argument metadata is only for the kind run"));
+                }
+                let mut metadata = parse_from_file::<MetaData>(matches, "config")?;
+                metadata.keystore_password = keystore_password;
+                Ok(Self::Init(metadata))
+            }
+            Some("run") => {
+                let run_env = parse_from_file::<RunEnv>(matches, "config")?;
+                if matches.is_present("metadata") {
+                    let mut metadata = parse_from_file::<MetaData>(matches, "metadata")?;
+                    metadata.keystore_password = keystore_password;
+                    Ok(Self::RunWithMetadata(run_env, metadata))
+                } else {
+                    Ok(Self::Run(run_env))
+                }
+            }
+            Some(kind) => Err(Error::config(format!("unknown config kind {}", kind))),
+            None => Err(Error::argument_should_exist("kind")),
+        }
+    }
+}
+
+impl<'a> TryFrom<&'a clap::ArgMatches<'a>> for RunConfig {
+    type Error = Error;
+    fn try_from(matches: &'a clap::ArgMatches) -> Result<Self> {
+        let data_dir = parse_from_str::<PathBuf>(matches, "data-dir")?;
+        let jsonrpc_urls = parse_urls(matches, "jsonrpc-url")?;
+        let mut config = if let Some(profile) = matches.value_of("profile") {
+            let overrides = read_file(matches, "config")?.unwrap_or_default();
+            RunEnv::from_profile(profile, &overrides)?
+        } else {
+            parse_from_file::<RunEnv>(matches, "config")?
+        };
+        let dry_run = matches.is_present("dry-run");
+        // The dry run still synchronizes the blocks, but into a copy of the storage.
+        let storage = if dry_run {
+            Storage::load_temporary_copy(data_dir, &config.storage)?
+        } else {
+            Storage::load_with_config(data_dir, &config.storage)?
+        };
+        if matches.is_present("profile") {
+            config.seed_locks_weights(&storage.get_metadata()?)?;
+        }
+        if matches.is_present("max-transactions") {
+            config.max_transactions = Some(parse_from_str(matches, "max-transactions")?);
+        }
+        // The same checks as the command "validate".
+        check_problems(config.problems())?;
+        let settlement_accounts = parse_hashes(matches, "settlement-accounts")?;
+        let otlp_endpoint = matches
+            .value_of("otlp-endpoint")
+            .map(|url| url::Url::parse(url).map_err(Error::config))
+            .transpose()?;
+        let telemetry = Telemetry::new(otlp_endpoint.as_ref())?;
+        let ledger_file = matches.value_of("ledger-file").map(PathBuf::from);
+        let record_tx_log = matches.value_of("record-tx-log").map(PathBuf::from);
+        let dump_tx_bytes = if matches.is_present("dump-tx-bytes") {
+            let dump = matches
+                .value_of("dump-tx-bytes")
+                .map(|path| TxBytesDump::File(PathBuf::from(path)))
+                .unwrap_or(TxBytesDump::Log);
+            Some(dump)
+        } else {
+            None
+        };
+        let failed_tx_dir = matches.value_of("failed-tx-dir").map(PathBuf::from);
+        let verify_signatures = matches.is_present("verify-signatures");
+        let dry_run_check = matches.is_present("dry-run-check");
+        let verify_inputs = matches.is_present("verify-inputs");
+        let use_indexer = matches.is_present("use-indexer");
+        let allow_ibd = matches.is_present("allow-ibd");
+        let keystore_password = parse_keystore_password(matches);
+        let client = FailoverClient::new(
+            &jsonrpc_urls,
+            config.client.request_timeout(),
+            config.client.max_retries(),
+        )?;
+        Ok(Self {
+            storage,
+            client: Arc::new(client),
+            config: Arc::new(config),
+            settlement_accounts,
+            telemetry: Arc::new(telemetry),
+            ledger_file,
+            record_tx_log,
+            dump_tx_bytes,
+            failed_tx_dir,
+            dry_run,
+            verify_signatures,
+            dry_run_check,
+            verify_inputs,
+            use_indexer,
+            allow_ibd,
+            keystore_password,
+        })
+    }
+}
+
+fn check_problems(problems: Vec<String>) -> Result<()> {
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        let errmsg = format!(
+            "found {} problems in the config: {}",
+            problems.len(),
+            problems.join("; ")
+        );
+        Err(Error::config(errmsg))
+    }
+}
+
 fn parse_from_str<T: FromStr>(matches: &clap::ArgMatches, name: &str) -> Result<T>
 where
     <T as FromStr>::Err: Display,
@@ -96,10 +532,59 @@ where
         .ok_or_else(|| Error::argument_should_exist(name))
 }
 
-fn parse_from_file<T: FromStr>(matches: &clap::ArgMatches, name: &str) -> Result<T>
+fn parse_keystore_password(matches: &clap::ArgMatches) -> Option<KeystorePassword> {
+    matches
+        .value_of("keystore-password")
+        .map(|password| KeystorePassword::new(password.to_owned()))
+}
+
+fn parse_hashes(matches: &clap::ArgMatches, name: &str) -> Result<Option<Vec<H256>>> {
+    matches
+        .values_of(name)
+        .map(|values| {
+            values
+                .map(|value| {
+                    H256::from_str(value.trim_start_matches("0x")).map_err(|err| {
+                        Error::config(format!("failed to parse hash {} since {}", value, err))
+                    })
+                })
+                .collect()
+        })
+        .transpose()
+}
+
+fn parse_urls(matches: &clap::ArgMatches, name: &str) -> Result<Vec<url::Url>> {
+    matches
+        .values_of(name)
+        .ok_or_else(|| Error::argument_should_exist(name))?
+        .map(client::parse_url)
+        .collect()
+}
+
+// The error includes the path of the file, and the position in it if the YAML is malformed.
+fn parse_from_file<T>(matches: &clap::ArgMatches, name: &str) -> Result<T>
 where
-    <T as FromStr>::Err: Display,
+    T: FromStr<Err = serde_yaml::Error>,
 {
+    let data = read_file(matches, name)?.ok_or_else(|| Error::argument_should_exist(name))?;
+    T::from_str(&data).map_err(|err| {
+        let file = matches.value_of(name).unwrap_or_default();
+        let errmsg = if let Some(location) = err.location() {
+            format!(
+                "failed to parse {}:{}:{} since {}",
+                file,
+                location.line(),
+                location.column(),
+                err
+            )
+        } else {
+            format!("failed to parse {} since {}", file, err)
+        };
+        Error::config(errmsg)
+    })
+}
+
+fn read_file(matches: &clap::ArgMatches, name: &str) -> Result<Option<String>> {
     matches
         .value_of(name)
         .map(|file| {
@@ -115,8 +600,6 @@ where
                         })
                         .map(|_| buffer)
                 })
-                .and_then(|data| T::from_str(&data).map_err(Error::config))
         })
-        .transpose()?
-        .ok_or_else(|| Error::argument_should_exist(name))
+        .transpose()
 }
@@ -0,0 +1,52 @@
+use crate::error::Result;
+
+use super::run::{synchronize, SyncStats, TipCache};
+
+impl super::ReindexConfig {
+    pub(super) fn execute(&self) -> Result<()> {
+        log::info!("Reindex ...");
+        let stg = &self.storage;
+        let cli = &self.client;
+        let mut metadata = stg.get_metadata()?;
+        metadata.keystore_password = self.keystore_password.clone();
+        let accounts = metadata.accounts()?;
+        let start_block = metadata.start_block()?;
+        log::info!("checking the chain ...");
+        self.config.check_delay_blocks(start_block.number)?;
+        cli.check_chain(start_block)?;
+
+        let removed = stg.reset_cells()?;
+        log::info!(
+            "removed {} cells, synchronize from block#{} again",
+            removed,
+            start_block.number
+        );
+        let mut tip_cache = TipCache::default();
+        let mut total_stats = SyncStats::default();
+        loop {
+            let (skip_sync, stats) = synchronize(
+                cli,
+                stg,
+                &accounts,
+                start_block.number,
+                &self.config,
+                &mut tip_cache,
+            )?;
+            if skip_sync {
+                break;
+            }
+            total_stats.merge(&stats);
+            if let Some(next_num) = stg.get_next_number()? {
+                log::info!("synchronized to block#{}", next_num - 1);
+            }
+        }
+        stg.flush()?;
+        match stg.get_next_number()? {
+            Some(next_num) => println!("synchronized to block#{}", next_num - 1),
+            None => println!("not synchronized yet"),
+        }
+        println!("live cells: {}", stg.count_cells()?);
+        println!("synchronized: {}", total_stats);
+        Ok(())
+    }
+}
@@ -0,0 +1,44 @@
+use std::{thread, time};
+
+use crate::{error::Result, tx_log};
+
+impl super::ReplayConfig {
+    pub(super) fn execute(&self) -> Result<()> {
+        log::info!("Replay ...");
+        let cli = &self.client;
+        let mut sent = 0;
+        let mut failed = 0;
+        for item in tx_log::read(&self.tx_log)? {
+            let (line_number, tx) = item?;
+            match cli.send_transaction(tx, self.outputs_validator.clone()) {
+                Ok(tx_hash) => {
+                    log::debug!("sent tx {:#x} at line {}", tx_hash, line_number);
+                    sent += 1;
+                }
+                Err(err) if self.skip_failed => {
+                    log::warn!(
+                        "failed to send the tx at line {} since {}, skip it",
+                        line_number,
+                        err
+                    );
+                    failed += 1;
+                }
+                Err(err) => {
+                    log::error!(
+                        "failed to send the tx at line {} since {}, stop replaying",
+                        line_number,
+                        err
+                    );
+                    println!("sent: {}", sent);
+                    return Err(err);
+                }
+            }
+            if self.interval > 0 {
+                thread::sleep(time::Duration::from_millis(self.interval));
+            }
+        }
+        println!("sent: {}", sent);
+        println!("failed: {}", failed);
+        Ok(())
+    }
+}
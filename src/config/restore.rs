@@ -0,0 +1,13 @@
+use crate::{error::Result, storage::Storage};
+
+impl super::RestoreConfig {
+    pub(super) fn execute(&self) -> Result<()> {
+        log::info!("Restore ...");
+        // Copy the snapshot, so the snapshot is kept as it is.
+        self.backup.create_checkpoint(&self.data_dir)?;
+        // Migrate the data if the snapshot is created by an older version.
+        Storage::load(&self.data_dir)?;
+        println!("the storage is restored into [{}]", self.data_dir.display());
+        Ok(())
+    }
+}
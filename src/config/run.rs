@@ -1,302 +1,2092 @@
-use std::{collections::HashMap, thread, time, vec::IntoIter};
+use std::{
+    cmp,
+    collections::{HashMap, HashSet, VecDeque},
+    fmt, fs,
+    io::Write as _,
+    iter,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc,
+    },
+    thread, time,
+};
 
 use ckb_jsonrpc_types as rpc;
 use ckb_types::{bytes, core, packed, prelude::*, H256};
+use parking_lot::Mutex;
+use rayon::prelude::*;
+use tokio::runtime::Runtime;
 
 use crate::{
-    client::Client,
-    error::{Error, Result},
-    storage::Storage,
-    types::{BlockMeta, CellInfo, InputInfo, LockGenerator, LockInfo, LockScriptId},
+    client::{self, FailoverClient},
+    error::{Error, RejectReason, Result},
+    ledger::Ledger,
+    logger::log_event,
+    metrics::Metrics,
+    storage::{Storage, MAX_ROLLBACK_BLOCKS},
+    telemetry::{Telemetry, TxSpan},
+    tx_log::TxLog,
+    types::{
+        parse_version, BlockMeta, CapacityJitter, CapacitySplit, CellInfo, CellSelectionStrategy,
+        CellSource, ChangeLock, ChangeMinimum, DaoDepositConfig, FeePolicy, GeneratorConfig,
+        GeneratorMode, IndexerConfig, InputInfo, LockGenerator, LockInfo, LockScriptId,
+        OutputTypeScript, ReconnectConfig, RunEnv, SimRng, WitnessPadding,
+    },
 };
 
 const BYTE_SHANNONS: u64 = 100_000_000;
+const DEFAULT_PARALLEL_SIGN_THRESHOLD: usize = 4;
+const MAX_FEE_MULTIPLIER: u64 = 16;
+const BOOTSTRAP_PAGE_SIZE: u32 = 100;
+const MAX_POOL_FULL_BACKOFF_MILLIS: u64 = 60_000;
 
 impl super::RunConfig {
     pub(super) fn execute(&self) -> Result<()> {
         log::info!("Run ...");
 
+        let runtime = crate::runtime::initialize()?;
+        let ctx = Arc::new(SendContext {
+            cli: Arc::clone(&self.client),
+            tel: Arc::clone(&self.telemetry),
+            cfg: Arc::clone(&self.config),
+            verify_signatures: self.verify_signatures,
+            dry_run_check: self.dry_run_check,
+        });
+        let pool = match self.config.client.send_concurrency()? {
+            Some(concurrency) if !self.dry_run => {
+                Some(SendPool::start(&runtime, concurrency, &ctx))
+            }
+            _ => None,
+        };
+        self.run(&runtime, &ctx, pool)
+    }
+
+    fn run(&self, runtime: &Runtime, ctx: &SendContext, mut pool: Option<SendPool>) -> Result<()> {
         let stg = &self.storage;
-        let cli = &self.client;
-        let cfg = &self.config;
+        let cli: &FailoverClient = &self.client;
+        let cfg: &RunEnv = &self.config;
+        let tel: &Telemetry = &self.telemetry;
+        let ledger = Ledger::new(self.ledger_file.as_deref())?;
+        let tx_log = TxLog::new(self.record_tx_log.as_deref())?;
+        let tx_bytes_file = match self.dump_tx_bytes {
+            Some(super::TxBytesDump::File(ref path)) => {
+                let file = fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .map_err(|err| {
+                        let errmsg = format!(
+                            "failed to open the file [{}] to dump transactions since {}",
+                            path.display(),
+                            err
+                        );
+                        Error::config(errmsg)
+                    })?;
+                Some(file)
+            }
+            _ => None,
+        };
+        if let Some(ref dir) = self.failed_tx_dir {
+            fs::create_dir_all(dir).map_err(|err| {
+                let errmsg = format!(
+                    "failed to create the directory [{}] for failed transactions since {}",
+                    dir.display(),
+                    err
+                );
+                Error::config(errmsg)
+            })?;
+        }
 
-        let metadata = stg.get_metadata()?;
-        let accounts = metadata.accounts()?;
+        let mut metadata = stg.get_metadata()?;
+        metadata.keystore_password = self.keystore_password.clone();
+        let accounts = Arc::new(metadata.accounts()?);
+        super::check_problems(metadata.run_env_problems(cfg, &accounts))?;
         let lock_deps_dict = metadata.lock_deps_dict();
-        let input_size_generator = cfg.generator.input_size_generator()?;
-        let lock_generator = cfg.generator.lock_generator(&accounts)?;
+        let rng = SimRng::new(cfg.rng_seed);
+        let input_size_generator = cfg.generator.input_size_generator(&rng)?;
+        let lock_generator = cfg.generator.lock_generator(&accounts, &rng)?;
+
+        if let Some(ref lock_hashes) = self.settlement_accounts {
+            for lock_hash in lock_hashes {
+                if !accounts.contains_key(lock_hash) {
+                    let errmsg = format!("settlement account {:#x} is not found", lock_hash);
+                    return Err(Error::config(errmsg));
+                }
+            }
+        }
+
+        let zero_weight_accounts = cfg.generator.zero_weight_accounts(&accounts);
+        {
+            let cells = stg.load_cells()?;
+            for lock_hash in &zero_weight_accounts {
+                let count = cells
+                    .iter()
+                    .filter(|cell| &cell.cell_info.lock_hash == lock_hash)
+                    .count();
+                if count > 0 {
+                    log::warn!(
+                        "account {:#x} [{}] has {} cells but zero weight, it never receives outputs",
+                        lock_hash,
+                        accounts[lock_hash].id,
+                        count
+                    );
+                }
+            }
+        }
 
+        log::info!("checking the node ...");
+        cli.check_node(cfg.client.min_node_version()?)?;
+        cli.check_ibd(self.allow_ibd)?;
         log::info!("checking the chain ...");
-        cli.check_chain(&metadata.start_block)?;
+        cli.check_chain(metadata.start_block()?)?;
+
+        let indexer_cli = match cfg.cell_source {
+            CellSource::Indexer(IndexerConfig {
+                url: Some(ref url), ..
+            }) => {
+                let url = client::parse_url(url)?;
+                let indexer_cli = FailoverClient::new(
+                    &[url],
+                    cfg.client.request_timeout(),
+                    cfg.client.max_retries(),
+                )?;
+                Some(indexer_cli)
+            }
+            _ => None,
+        };
+
+        let mut total_sync_stats = SyncStats::default();
+        let mut caught_up = false;
+        let mut tip_cache = TipCache::default();
+        if cfg.client.subscribe_new_tip {
+            tip_cache.subscribe(cli);
+        }
+        if self.use_indexer {
+            if let CellSource::BlockSync = cfg.cell_source {
+                if stg.get_next_number()?.is_none() {
+                    bootstrap_from_indexer(
+                        cli,
+                        stg,
+                        &accounts,
+                        metadata.start_block()?.number,
+                        cfg,
+                        &mut tip_cache,
+                    )?;
+                }
+            } else {
+                log::warn!("the cells are always loaded from the indexer, ignore --use-indexer");
+            }
+        }
+        let metrics = Arc::new(Metrics::default());
+        let mut rate_limiter = cfg.client.target_interval()?.map(RateLimiter::new);
+        if let Some(addr) = cfg.metrics_addr {
+            metrics.serve(runtime, addr)?;
+        }
+
+        let shutdown = Arc::new(AtomicBool::new(false));
+        {
+            let shutdown = Arc::clone(&shutdown);
+            ctrlc::set_handler(move || {
+                log::info!("received the interrupt signal, stop after the current transaction");
+                shutdown.store(true, Ordering::SeqCst);
+            })
+            .map_err(Error::runtime)?;
+        }
+        let mut recorder = SendRecorder {
+            stg,
+            cfg,
+            metrics: Arc::clone(&metrics),
+            shutdown: Arc::clone(&shutdown),
+            ledger,
+            tx_log,
+            dump_tx_bytes: self.dump_tx_bytes.is_some(),
+            tx_bytes_file,
+            failed_tx_dir: self.failed_tx_dir.as_deref(),
+            pending_txs: HashSet::new(),
+            lock_stats: LockStats::default(),
+            fee_multiplier: 1,
+            pool_full_backoff: 0,
+            sent_count: 0,
+            resync: false,
+        };
 
         loop {
+            if shutdown.load(Ordering::SeqCst) {
+                break;
+            }
             log::info!("synchroning the blocks ...");
-            let skip_sync = synchronize(
-                &cli,
-                &stg,
-                &accounts,
-                metadata.start_block.number,
-                cfg.delay_blocks,
-            )?;
+            let (skip_sync, sync_stats) = match cfg.cell_source {
+                CellSource::BlockSync => synchronize(
+                    cli,
+                    stg,
+                    &accounts,
+                    metadata.start_block()?.number,
+                    cfg,
+                    &mut tip_cache,
+                )?,
+                CellSource::Indexer(ref indexer) => synchronize_from_indexer(
+                    indexer_cli.as_ref().unwrap_or(cli),
+                    stg,
+                    &accounts,
+                    metadata.start_block()?.number,
+                    cfg,
+                    indexer.page_size,
+                    &mut tip_cache,
+                )?,
+            };
+            let synced_num = stg.get_next_number()?.map(|next_num| next_num - 1);
+            if let Some(synced_num) = synced_num {
+                metrics.set_synced_block_number(synced_num);
+            }
+            if !skip_sync {
+                total_sync_stats.merge(&sync_stats);
+                log_event!(
+                    Debug,
+                    "synchronized",
+                    block_number = synced_num,
+                    blocks = sync_stats.blocks,
+                    blocks_skipped = sync_stats.blocks_skipped,
+                    cells_added = sync_stats.cells_added,
+                    cells_removed = sync_stats.cells_removed,
+                    elapsed_ms = sync_stats.elapsed.as_millis() as u64,
+                );
+                log::debug!("synchronized in total: {}", total_sync_stats);
+            }
+            if skip_sync && !caught_up {
+                caught_up = true;
+                if let Some(cooldown) = cfg.client.after_sync_cooldown_ms {
+                    log::info!("caught up to the tip, cool down for {} ms", cooldown);
+                    sleep_millis(cooldown);
+                }
+            }
+
+            if !cfg.pause_epochs.is_empty() {
+                let epoch = cli.get_current_epoch()?;
+                if cfg.pause_epochs.contains(&epoch.number()) {
+                    log::info!(
+                        "pause sending in epoch {} ({}/{})",
+                        epoch.number(),
+                        epoch.index(),
+                        epoch.length()
+                    );
+                    if skip_sync && !shutdown.load(Ordering::SeqCst) {
+                        tip_cache.wait(cfg.client.idle_interval, &shutdown);
+                    }
+                    continue;
+                }
+            }
 
             log::debug!("sending transactions ...");
             {
-                let mut cells_iter = stg.load_cells()?.into_iter();
+                let mut cells = stg.load_cells()?;
+                // Too many cells are tracked, consolidate the smallest ones in this round.
+                let mut excess_cells = None;
+                let consolidating_generator;
+                let generator = match cfg.max_live_cells {
+                    Some(max) if cells.len() > max => {
+                        log::info!(
+                            "{} live cells are more than {}, consolidate the smallest cells",
+                            cells.len(),
+                            max
+                        );
+                        excess_cells = Some(cells.len() - max);
+                        consolidating_generator = cfg.generator.consolidating();
+                        &consolidating_generator
+                    }
+                    _ => &cfg.generator,
+                };
+                sort_cells(&mut cells, generator.cell_selection, &rng);
+                let mut settlement_cells = self
+                    .settlement_accounts
+                    .as_ref()
+                    .map(|_| group_cells_by_lock(&cells));
+                if generator.exclude_zero_weight_inputs {
+                    cells.retain(|cell| !zero_weight_accounts.contains(&cell.cell_info.lock_hash));
+                }
+                let mut cells_queue = cells.into_iter().collect::<VecDeque<_>>();
                 let mut total_inputs = Vec::new();
                 let mut loop_counter = 0;
                 let mut expected_input_size = 0;
+                let mut select_started_at = None;
+                recorder.resync = false;
+                if let Some(ref mut limiter) = rate_limiter {
+                    limiter.reset();
+                }
                 loop {
-                    if expected_input_size == 0 {
-                        expected_input_size = input_size_generator.generate();
+                    if shutdown.load(Ordering::SeqCst) {
+                        break;
                     }
-                    log::trace!(
-                        "try fetch inputs {} -> {}",
-                        total_inputs.len(),
-                        expected_input_size
-                    );
-                    match fetch_more_inputs(
-                        &mut cells_iter,
-                        &mut total_inputs,
-                        cfg.generator.inputs_limit,
-                        cfg.generator.output_min_capacity,
-                        expected_input_size,
-                    )? {
-                        FetchInputsResult::Lack => break,
-                        FetchInputsResult::Next => continue,
-                        FetchInputsResult::Enough => {
-                            expected_input_size = 0;
+                    let in_flight = pool.as_ref().map(SendPool::in_flight).unwrap_or(0);
+                    if let Some(max) = cfg.max_transactions {
+                        if recorder.sent_count + in_flight as u64 >= max {
+                            break;
+                        }
+                    }
+                    if excess_cells == Some(0) {
+                        log::info!("the live cells are consolidated under the cap");
+                        break;
+                    }
+                    if let Some(depth) = cfg.client.pending_depth {
+                        if recorder.pending_txs.len() + in_flight >= depth {
+                            cli.refresh_pending_txs(stg, &mut recorder.pending_txs)?;
+                            let pending = recorder.pending_txs.len() + in_flight;
+                            if pending >= depth {
+                                log::trace!("waiting for {} pending txs", pending);
+                                break;
+                            }
+                        }
+                    }
+                    if select_started_at.is_none() {
+                        select_started_at = Some(time::SystemTime::now());
+                    }
+                    if let (Some(lock_hashes), Some(cells_by_lock)) =
+                        (&self.settlement_accounts, &mut settlement_cells)
+                    {
+                        match select_settlement_inputs(
+                            cells_by_lock,
+                            &mut total_inputs,
+                            lock_hashes,
+                            min_inputs_shannons(generator),
+                        )? {
+                            FetchInputsResult::Enough => {}
+                            _ => break,
+                        }
+                    } else {
+                        if expected_input_size == 0 {
+                            expected_input_size = match generator.mode {
+                                GeneratorMode::Split => input_size_generator.generate(),
+                                GeneratorMode::Consolidate => generator.inputs_limit,
+                            };
+                        }
+                        log::trace!(
+                            "try fetch inputs {} -> {}",
+                            total_inputs.len(),
+                            expected_input_size
+                        );
+                        match fetch_more_inputs(
+                            &mut cells_queue,
+                            &mut total_inputs,
+                            generator,
+                            expected_input_size,
+                        )? {
+                            FetchInputsResult::Lack => break,
+                            FetchInputsResult::Next => {
+                                if let Some(max) = generator.max_selecting_inputs {
+                                    if total_inputs.len() >= max {
+                                        // The cells are kept in the storage for next rounds.
+                                        log::warn!(
+                                            "selected {} inputs but the capacity is still not \
+                                            enough, skip them",
+                                            total_inputs.len()
+                                        );
+                                        total_inputs.clear();
+                                        select_started_at = None;
+                                    }
+                                }
+                                continue;
+                            }
+                            FetchInputsResult::Enough => {
+                                expected_input_size = 0;
+                            }
                         }
                     }
                     loop_counter += 1;
                     log::trace!("selected {} inputs", total_inputs.len());
 
+                    if self.verify_inputs {
+                        let (live_inputs, dead_inputs) =
+                            cli.split_dead_inputs(std::mem::take(&mut total_inputs))?;
+                        if !dead_inputs.is_empty() {
+                            log::warn!("skip {} dead inputs", dead_inputs.len());
+                            for input in &dead_inputs {
+                                stg.rm_cell(&input.out_point)?;
+                            }
+                            if settlement_cells.is_some() {
+                                break;
+                            }
+                            total_inputs = live_inputs;
+                            continue;
+                        }
+                        total_inputs = live_inputs;
+                    }
                     let (lock_hashes, inputs) = prepare_inputs(&mut total_inputs);
+                    let started_at = select_started_at
+                        .take()
+                        .unwrap_or_else(time::SystemTime::now);
+                    let tx_span = tel.start_tx(started_at);
+                    tel.record_stage(&tx_span, "select", started_at);
 
-                    let rtx = construct_raw_transaction(
-                        &inputs,
-                        &accounts,
-                        &lock_generator,
-                        &lock_deps_dict,
-                        cfg.generator.outputs_limit,
-                        cfg.generator.output_capacity,
-                        cfg.generator.tx_fee,
-                    )?;
-                    let stx = sign_transaction(rtx, &lock_hashes, &accounts)?;
-                    let tx_hash = stx.calc_tx_hash();
-                    let stx_json: rpc::Transaction = stx.into();
-                    match cli.send_transaction(stx_json.clone()) {
-                        Ok(_) => {
-                            log::debug!("send tx {:#x} is ok", tx_hash);
-                            sleep_millis(cfg.client.success_interval);
-                            for input in inputs {
-                                stg.spend_cell(input.out_point)?;
+                    let rtx = match tel.stage(&tx_span, "construct", || {
+                        construct_raw_transaction(
+                            &inputs,
+                            &accounts,
+                            &lock_generator,
+                            &lock_deps_dict,
+                            generator,
+                            &rng,
+                            recorder.fee_multiplier,
+                        )
+                    })? {
+                        Some(rtx) => rtx,
+                        None if settlement_cells.is_none() && !cells_queue.is_empty() => {
+                            if inputs.len() < generator.inputs_limit {
+                                log::trace!("the change is not enough, try to add more inputs");
+                                total_inputs = inputs;
+                            } else {
+                                // The cells are kept in the storage for next rounds.
+                                log::debug!(
+                                    "the change is not enough with {} inputs, skip them",
+                                    inputs.len()
+                                );
                             }
+                            continue;
+                        }
+                        None => {
+                            log::debug!("the change is not enough and no more inputs, skip it");
+                            break;
+                        }
+                    };
+                    if let Some(ref mut excess) = excess_cells {
+                        let merged = inputs.len().saturating_sub(rtx.outputs().len());
+                        *excess = excess.saturating_sub(merged);
+                    }
+                    let job = SendJob {
+                        rtx,
+                        inputs,
+                        lock_hashes,
+                        accounts: Arc::clone(&accounts),
+                        tx_span,
+                    };
+                    if let Some(ref mut pool) = pool {
+                        metrics.tx_sent();
+                        pool.submit(job)?;
+                        if let Some(ref mut limiter) = rate_limiter {
+                            limiter.wait();
                         }
-                        Err(err) => {
-                            log::error!("send tx {:#x} failed since: {}", tx_hash, err);
-                            let stx_str =
-                                serde_json::to_string_pretty(&stx_json).map_err(Error::runtime)?;
-                            log::debug!("tx {:#x} = {}", tx_hash, stx_str);
-                            sleep_millis(cfg.client.failure_interval);
+                        if !recorder.record_all(pool.finished(), &accounts, tip_cache.number)? {
                             break;
                         }
+                        continue;
                     }
+                    let signed = ctx.sign(job)?;
+                    recorder.dump(&signed.stx)?;
+                    if self.dry_run {
+                        let tx_hash = signed.stx.calc_tx_hash();
+                        let stx_json: rpc::Transaction = signed.stx.clone().into();
+                        signed.tx_span.end();
+                        let stx_str =
+                            serde_json::to_string_pretty(&stx_json).map_err(Error::runtime)?;
+                        log::info!("dry run, tx {:#x} = {}", tx_hash, stx_str);
+                        recorder
+                            .lock_stats
+                            .record(&output_lock_ids(&signed.stx, &accounts));
+                        continue;
+                    }
+                    metrics.tx_sent();
+                    let outcome = ctx.send(signed);
+                    if outcome.result.is_ok() {
+                        if let Some(ref mut limiter) = rate_limiter {
+                            limiter.wait();
+                        } else {
+                            sleep_millis(cfg.client.success_interval);
+                        }
+                    }
+                    if !recorder.record(outcome, &accounts, tip_cache.number)? {
+                        break;
+                    }
+                }
+                // Record all sent transactions before the next synchronization.
+                if let Some(ref mut pool) = pool {
+                    recorder.record_all(pool.drain(), &accounts, tip_cache.number)?;
+                }
+                if loop_counter > 0 {
+                    log::debug!(
+                        "locks used in total: {}",
+                        recorder.lock_stats.report(&cfg.generator.locks_weights)
+                    );
                 }
-                if skip_sync && loop_counter == 0 {
+                // Wait for new blocks if there is nothing to send, or the cells are stale.
+                if skip_sync
+                    && (loop_counter == 0 || recorder.resync)
+                    && !shutdown.load(Ordering::SeqCst)
+                {
                     log::trace!(
                         "waiting {} ms for new blocks and unspent cells ...",
                         cfg.client.idle_interval
                     );
-                    sleep_millis(cfg.client.idle_interval);
+                    tip_cache.wait(cfg.client.idle_interval, &shutdown);
                 }
             }
+            // No cells are spent, so the next round would construct the same transactions.
+            if self.dry_run {
+                log::info!("dry run is done");
+                break;
+            }
         }
+
+        log::info!("synchronized in total: {}", total_sync_stats);
+        log::info!(
+            "locks used in total: {}",
+            recorder.lock_stats.report(&cfg.generator.locks_weights)
+        );
+        stg.flush()?;
+        log::info!("shutting down, flushed {} cells", stg.count_cells()?);
+        Ok(())
     }
 }
 
-fn sleep_millis(interval: u64) {
-    thread::sleep(time::Duration::from_millis(interval));
+// The parts of the run config which are shared with the workers to send transactions.
+struct SendContext {
+    cli: Arc<FailoverClient>,
+    tel: Arc<Telemetry>,
+    cfg: Arc<RunEnv>,
+    verify_signatures: bool,
+    dry_run_check: bool,
 }
 
-impl Client {
-    fn check_chain(&self, start_meta: &BlockMeta) -> Result<()> {
-        let start_header = self
-            .get_header_by_number(start_meta.number)?
-            .ok_or_else(|| Error::runtime("the provided node doesn't have enough chain data"))?;
-        if start_header.hash != start_meta.hash {
-            let errmsg = format!(
-                "the provided node isn't the expected chain, block#{}'s hash should be {:#x}, but got {:#x}",
-                start_meta.number, start_meta.hash, start_header.hash
-            );
-            Err(Error::runtime(errmsg))
+// A constructed transaction, its inputs are owned by it until it is recorded.
+struct SendJob {
+    rtx: packed::RawTransaction,
+    inputs: Vec<InputInfo>,
+    lock_hashes: Vec<H256>,
+    accounts: Arc<HashMap<H256, LockInfo>>,
+    tx_span: TxSpan,
+}
+
+struct SignedTx {
+    stx: packed::Transaction,
+    inputs: Vec<InputInfo>,
+    tx_span: TxSpan,
+}
+
+struct SendOutcome {
+    stx: packed::Transaction,
+    inputs: Vec<InputInfo>,
+    result: Result<()>,
+}
+
+// Sign and send transactions in several workers, which are blocking tasks in the runtime.
+//
+// Only the main thread selects cells, and the inputs of each transaction are moved into one job,
+// so two workers never spend the same cell. The cells of in-flight jobs are still in the storage,
+// so all outcomes are recorded before the cells are loaded again, see "drain".
+struct SendPool {
+    jobs: mpsc::SyncSender<SendJob>,
+    outcomes: mpsc::Receiver<Result<SendOutcome>>,
+    in_flight: usize,
+}
+
+// The results of sending transactions.
+struct SendRecorder<'a> {
+    stg: &'a Storage,
+    cfg: &'a RunEnv,
+    metrics: Arc<Metrics>,
+    shutdown: Arc<AtomicBool>,
+    ledger: Ledger,
+    tx_log: TxLog,
+    dump_tx_bytes: bool,
+    tx_bytes_file: Option<fs::File>,
+    failed_tx_dir: Option<&'a Path>,
+    pending_txs: HashSet<H256>,
+    lock_stats: LockStats,
+    // Raised when the node rejects transactions since the fee rate is too low, and reset once a
+    // transaction is accepted.
+    fee_multiplier: u64,
+    // The pause in milliseconds when the pool of the node is full, it grows while the pool is
+    // still full and shrinks when transactions are accepted again.
+    pool_full_backoff: u64,
+    sent_count: u64,
+    // Some cells are stale, synchronize before the next round.
+    resync: bool,
+}
+
+impl SendContext {
+    fn sign(&self, job: SendJob) -> Result<SignedTx> {
+        let SendJob {
+            rtx,
+            inputs,
+            lock_hashes,
+            accounts,
+            tx_span,
+        } = job;
+        let generator = &self.cfg.generator;
+        let stx = self.tel.stage(&tx_span, "sign", || {
+            sign_transaction(
+                rtx,
+                &inputs,
+                &lock_hashes,
+                &accounts,
+                generator.witness_padding,
+                generator
+                    .parallel_sign_threshold
+                    .unwrap_or(DEFAULT_PARALLEL_SIGN_THRESHOLD),
+            )
+        })?;
+        if self.verify_signatures {
+            verify_signatures(&stx, &inputs, &lock_hashes, &accounts)?;
+        }
+        tx_span.set_tx_hash(&stx.calc_tx_hash().unpack());
+        Ok(SignedTx {
+            stx,
+            inputs,
+            tx_span,
+        })
+    }
+
+    fn send(&self, signed: SignedTx) -> SendOutcome {
+        let stx_json: rpc::Transaction = signed.stx.clone().into();
+        let checked = if self.dry_run_check {
+            self.tel.stage(&signed.tx_span, "dry_run", || {
+                self.dry_run(&signed.stx, stx_json.clone())
+            })
         } else {
             Ok(())
+        };
+        let result = checked.and_then(|()| {
+            self.tel.stage(&signed.tx_span, "send", || {
+                self.cli
+                    .send_transaction(stx_json, self.cfg.client.outputs_validator.clone())
+                    .map(|_| ())
+            })
+        });
+        signed.tx_span.end();
+        SendOutcome {
+            stx: signed.stx,
+            inputs: signed.inputs,
+            result,
+        }
+    }
+
+    // A transaction which fails to dry run is not sent, since it is constructed wrongly.
+    fn dry_run(&self, stx: &packed::Transaction, stx_json: rpc::Transaction) -> Result<()> {
+        let tx_hash = stx.calc_tx_hash();
+        match self.cli.dry_run_transaction(stx_json) {
+            Ok(result) => {
+                let cycles: u64 = result.cycles.into();
+                log::debug!("dry run tx {:#x}, cycles: {}", tx_hash, cycles);
+                Ok(())
+            }
+            // The node is unreachable, it is not a problem of the transaction.
+            Err(err @ Error::Transport(_)) => Err(err),
+            Err(err) => {
+                log::error!(
+                    "failed to dry run tx {:#x} since {}, it is constructed wrongly",
+                    tx_hash,
+                    err
+                );
+                Err(err)
+            }
         }
     }
+
+    fn work(&self, job: SendJob) -> Result<SendOutcome> {
+        let outcome = self.send(self.sign(job)?);
+        // The rate limiter is in the main thread, otherwise each worker pauses by itself.
+        if outcome.result.is_ok() && self.cfg.client.target_tps.is_none() {
+            sleep_millis(self.cfg.client.success_interval);
+        }
+        Ok(outcome)
+    }
 }
 
-fn synchronize(
-    cli: &Client,
-    stg: &Storage,
-    accounts: &HashMap<H256, LockInfo>,
-    start_block: core::BlockNumber,
-    delay_blocks: core::BlockNumber,
-) -> Result<bool> {
-    let next_num = stg.get_next_number()?.unwrap_or(start_block);
-    let tip_num = cli.get_tip_block_number()?;
-    let search_when_num = next_num + delay_blocks;
-    log::trace!(
-        "current tip: {}, next to search: {}, search when {}",
-        tip_num,
-        next_num,
-        search_when_num
-    );
-    let skip_sync = next_num + delay_blocks >= tip_num;
-    if !skip_sync {
-        log::debug!("synchronizing to block#{} ...", tip_num - delay_blocks);
-        for num in next_num..=(tip_num - delay_blocks) {
-            log::trace!("fetching block#{} ...", num);
-            let block = cli.get_block_by_number(num)?.ok_or_else(|| {
-                let errmsg = format!("block#{} should exists but CKB node returns None", num);
-                Error::runtime(errmsg)
-            })?;
-            for tx in &block.transactions {
-                for (index, output_json) in tx.inner.outputs.iter().enumerate() {
-                    let output: packed::CellOutput = output_json.clone().into();
-                    for (hash, lock_info) in accounts {
-                        if output.lock() == lock_info.script {
-                            log::trace!("found a new cell {:#x}.{}", tx.hash, index);
-                            let out_point = packed::OutPoint::new_builder()
-                                .tx_hash(tx.hash.pack())
-                                .index(index.pack())
-                                .build();
-                            let output_cap = output.capacity();
-                            let cell_info = CellInfo::new(output_cap.unpack(), hash.clone());
-                            stg.add_cell(out_point, cell_info)?;
-                        }
-                    }
+impl SendPool {
+    fn start(runtime: &Runtime, concurrency: usize, ctx: &Arc<SendContext>) -> Self {
+        log::info!("send transactions in {} workers", concurrency);
+        // No buffer, a job is submitted only when a worker is idle.
+        let (jobs, jobs_receiver) = mpsc::sync_channel::<SendJob>(0);
+        let jobs_receiver = Arc::new(Mutex::new(jobs_receiver));
+        let (outcomes_sender, outcomes) = mpsc::channel();
+        for _ in 0..concurrency {
+            let jobs_receiver = Arc::clone(&jobs_receiver);
+            let outcomes_sender = outcomes_sender.clone();
+            let ctx = Arc::clone(ctx);
+            // Signing is CPU-bound and sending blocks on the requests, so the workers are not
+            // async tasks.
+            runtime.spawn_blocking(move || loop {
+                let job = match jobs_receiver.lock().recv() {
+                    Ok(job) => job,
+                    // The pool is dropped.
+                    Err(_) => break,
+                };
+                if outcomes_sender.send(ctx.work(job)).is_err() {
+                    break;
+                }
+            });
+        }
+        Self {
+            jobs,
+            outcomes,
+            in_flight: 0,
+        }
+    }
+
+    fn in_flight(&self) -> usize {
+        self.in_flight
+    }
+
+    // Block until a worker takes the job.
+    fn submit(&mut self, job: SendJob) -> Result<()> {
+        self.jobs
+            .send(job)
+            .map_err(|_| Error::runtime("the workers to send transactions are stopped"))?;
+        self.in_flight += 1;
+        Ok(())
+    }
+
+    // The outcomes which are ready, without blocking.
+    fn finished(&mut self) -> Vec<Result<SendOutcome>> {
+        let mut outcomes = Vec::new();
+        while let Ok(outcome) = self.outcomes.try_recv() {
+            self.in_flight -= 1;
+            outcomes.push(outcome);
+        }
+        outcomes
+    }
+
+    // Wait for the outcomes of all in-flight jobs.
+    fn drain(&mut self) -> Vec<Result<SendOutcome>> {
+        let mut outcomes = Vec::new();
+        while self.in_flight > 0 {
+            match self.outcomes.recv() {
+                Ok(outcome) => {
+                    self.in_flight -= 1;
+                    outcomes.push(outcome);
                 }
-                for input in &tx.inner.inputs {
-                    let out_point: packed::OutPoint = input.previous_output.clone().into();
-                    stg.rm_cell(out_point)?;
+                Err(_) => {
+                    // All workers are stopped, the outcomes of the in-flight jobs never come.
+                    self.in_flight = 0;
+                    let errmsg = "the workers to send transactions are stopped";
+                    outcomes.push(Err(Error::runtime(errmsg)));
                 }
             }
-            stg.put_prev_number(num)?;
         }
-    };
-    Ok(skip_sync)
+        outcomes
+    }
 }
 
-enum FetchInputsResult {
-    Lack,
-    Next,
-    Enough,
-}
+impl SendRecorder<'_> {
+    fn dump(&mut self, stx: &packed::Transaction) -> Result<()> {
+        self.tx_log.record(stx)?;
+        if !self.dump_tx_bytes {
+            return Ok(());
+        }
+        let tx_hash = stx.calc_tx_hash();
+        let tx_hex = faster_hex::hex_string(stx.as_slice()).map_err(Error::runtime)?;
+        if let Some(ref mut file) = self.tx_bytes_file {
+            writeln!(file, "{:#x} 0x{}", tx_hash, tx_hex).map_err(Error::runtime)?;
+        } else {
+            log::info!("tx {:#x} bytes = 0x{}", tx_hash, tx_hex);
+        }
+        Ok(())
+    }
 
-fn fetch_more_inputs(
-    cells_iter: &mut IntoIter<InputInfo>,
-    inputs: &mut Vec<InputInfo>,
-    inputs_limit: usize,
-    output_min_bytes: u32,
-    expected_input_size: usize,
-) -> Result<FetchInputsResult> {
-    let mut last = false;
-    if let Some(cell) = cells_iter.next() {
-        inputs.push(cell);
-    } else {
-        if inputs.is_empty() {
-            return Ok(FetchInputsResult::Lack);
+    // Record the outcomes from the workers, return false if any transaction failed.
+    //
+    // All outcomes are recorded before the first error is returned, otherwise the inputs of the
+    // sent transactions would be selected again.
+    fn record_all(
+        &mut self,
+        outcomes: Vec<Result<SendOutcome>>,
+        accounts: &HashMap<H256, LockInfo>,
+        tip_number: core::BlockNumber,
+    ) -> Result<bool> {
+        let mut keep_sending = true;
+        let mut first_error = None;
+        for outcome in outcomes {
+            let result = outcome.and_then(|outcome| {
+                let dumped = self.dump(&outcome.stx);
+                let recorded = self.record(outcome, accounts, tip_number);
+                dumped.and(recorded)
+            });
+            match result {
+                Ok(recorded) => keep_sending &= recorded,
+                Err(err) if first_error.is_none() => first_error = Some(err),
+                Err(err) => log::error!("failed to record a transaction since {}", err),
+            }
+        }
+        match first_error {
+            Some(err) => Err(err),
+            None => Ok(keep_sending),
         }
-        last = true;
     }
 
-    let total = inputs
-        .iter()
-        .map(|input| input.cell_info.capacity)
-        .try_fold(core::Capacity::zero(), |total, cap| total.safe_add(cap))
-        .map_err(Error::runtime)?
-        .as_u64();
+    // Return false if the transaction failed, then stop sending in this round.
+    fn record(
+        &mut self,
+        outcome: SendOutcome,
+        accounts: &HashMap<H256, LockInfo>,
+        tip_number: core::BlockNumber,
+    ) -> Result<bool> {
+        let SendOutcome {
+            stx,
+            inputs,
+            result,
+        } = outcome;
+        let tx_hash = stx.calc_tx_hash();
+        let result = match result {
+            // The node has the transaction already, e.g. it was sent to another node before
+            // failing over, so the inputs are spent.
+            Err(err) if err.reject_reason() == Some(RejectReason::Duplicated) => {
+                log::debug!("tx {:#x} is sent already since {}", tx_hash, err);
+                Ok(())
+            }
+            result => result,
+        };
+        match result {
+            Ok(()) => {
+                self.ledger.record(&stx, &inputs)?;
+                let spent = inputs
+                    .iter()
+                    .map(|input| input.out_point.clone())
+                    .collect::<Vec<_>>();
+                self.stg
+                    .record_sent_tx(tx_hash.unpack(), &spent, tip_number)?;
+                self.metrics.send_succeeded(inputs.len());
+                let outputs_capacity = stx
+                    .raw()
+                    .outputs()
+                    .into_iter()
+                    .map(|output| Unpack::<u64>::unpack(&output.capacity()))
+                    .fold(0u64, u64::saturating_add);
+                self.stg.spend_cells(&spent, outputs_capacity)?;
+                log_event!(
+                    Debug,
+                    "sent a transaction",
+                    tx_hash = format!("{:#x}", tx_hash),
+                    capacity = outputs_capacity,
+                    inputs = inputs.len(),
+                );
+                if self.cfg.client.pending_depth.is_some() {
+                    self.pending_txs.insert(tx_hash.unpack());
+                }
+                self.lock_stats.record(&output_lock_ids(&stx, accounts));
+                self.shrink_pool_full_backoff();
+                if self.fee_multiplier > 1 {
+                    log::info!("the fee rate is accepted, reset the fee multiplier");
+                    self.fee_multiplier = 1;
+                }
+                self.sent_count += 1;
+                if self.cfg.max_transactions == Some(self.sent_count) {
+                    log::info!(
+                        "sent {} transactions, locks used in total: {}",
+                        self.sent_count,
+                        self.lock_stats.report(&self.cfg.generator.locks_weights)
+                    );
+                    self.shutdown.store(true, Ordering::SeqCst);
+                }
+                Ok(true)
+            }
+            Err(err) => {
+                log_event!(
+                    Error,
+                    "failed to send a transaction",
+                    tx_hash = format!("{:#x}", tx_hash),
+                    error = err.to_string(),
+                    reject_reason = err.reject_reason().map(|reason| reason.to_string()),
+                );
+                self.metrics.send_failed();
+                let stx_json: rpc::Transaction = stx.into();
+                let stx_str = serde_json::to_string_pretty(&stx_json).map_err(Error::runtime)?;
+                if let Some(dir) = self.failed_tx_dir {
+                    save_failed_tx(dir, &tx_hash.unpack(), &stx_str, &err)?;
+                } else {
+                    log::debug!("tx {:#x} = {}", tx_hash, stx_str);
+                }
+                match err.reject_reason() {
+                    Some(reason @ RejectReason::DeadCell)
+                    | Some(reason @ RejectReason::UnknownCell) => {
+                        // The rejected cells are never selected again, and the other stored cells
+                        // are stale, synchronize before next round.
+                        let rejected = rejected_out_points(&err, &inputs);
+                        log::warn!(
+                            "rejected since {}, remove {} cells and re-synchronize",
+                            reason,
+                            rejected.len()
+                        );
+                        for out_point in rejected {
+                            self.stg.rm_cell(out_point)?;
+                        }
+                        self.resync = true;
+                    }
+                    Some(RejectReason::LowFeeRate) if self.fee_multiplier < MAX_FEE_MULTIPLIER => {
+                        self.fee_multiplier *= 2;
+                        log::warn!(
+                            "the fee rate is too low, multiply the fee by {}",
+                            self.fee_multiplier
+                        );
+                    }
+                    Some(RejectReason::PoolIsFull) => {
+                        self.grow_pool_full_backoff();
+                        log::warn!(
+                            "the pool of the node is full, pause for {} ms",
+                            self.pool_full_backoff
+                        );
+                        sleep_millis(self.pool_full_backoff);
+                    }
+                    _ => sleep_millis(self.cfg.client.failure_interval),
+                }
+                Ok(false)
+            }
+        }
+    }
 
-    if total < (u64::from(output_min_bytes) + 1) * BYTE_SHANNONS {
-        let res = if last {
-            FetchInputsResult::Lack
+    fn grow_pool_full_backoff(&mut self) {
+        let backoff = if self.pool_full_backoff == 0 {
+            self.cfg.client.failure_interval.max(1)
         } else {
-            FetchInputsResult::Next
+            self.pool_full_backoff.saturating_mul(2)
         };
-        return Ok(res);
+        self.pool_full_backoff = cmp::min(
+            backoff,
+            cmp::max(
+                MAX_POOL_FULL_BACKOFF_MILLIS,
+                self.cfg.client.failure_interval,
+            ),
+        );
     }
 
-    if last || inputs.len() >= std::cmp::min(expected_input_size, inputs_limit) {
-        Ok(FetchInputsResult::Enough)
-    } else {
-        Ok(FetchInputsResult::Next)
+    fn shrink_pool_full_backoff(&mut self) {
+        if self.pool_full_backoff > 0 {
+            self.pool_full_backoff /= 2;
+            if self.pool_full_backoff < self.cfg.client.failure_interval {
+                log::info!("the pool of the node is not full any more");
+                self.pool_full_backoff = 0;
+            }
+        }
     }
 }
 
-fn prepare_inputs(total_inputs: &mut Vec<InputInfo>) -> (Vec<H256>, Vec<InputInfo>) {
-    total_inputs.sort_by_key(|input| input.cell_info.lock_hash.clone());
-    let mut inputs_ext = total_inputs
-        .drain(..)
-        .fold((Vec::new(), None), |(mut result, prev), input| {
-            let curr = Some(input.cell_info.lock_hash.clone());
-            let is_first = prev != curr;
-            result.push((is_first, input));
-            (result, curr)
-        })
-        .0;
-    inputs_ext.sort_by_key(|(is_first, input)| (!is_first, input.cell_info.lock_hash.clone()));
-    let lock_hashes = inputs_ext
+// The node reports the unresolvable out points in the error, e.g. "Dead(OutPoint(0x...))".
+fn rejected_out_points<'a>(err: &Error, inputs: &'a [InputInfo]) -> Vec<&'a packed::OutPoint> {
+    let errmsg = err.to_string();
+    inputs
         .iter()
-        .filter(|(is_first, _)| *is_first)
-        .map(|(_, input)| input.cell_info.lock_hash.to_owned())
-        .collect::<Vec<_>>();
-    let inputs = inputs_ext
-        .into_iter()
-        .map(|(_, input)| input)
-        .collect::<Vec<_>>();
-    (lock_hashes, inputs)
+        .map(|input| &input.out_point)
+        .filter(|out_point| errmsg.contains(&format!("{:?}", out_point)))
+        .collect()
 }
 
-fn calculate_outputs_count(
-    outputs_limit: usize,
-    total_shannons: u64,
-    output_shannons: u64,
-    fee_shannons: u64,
-) -> usize {
-    match ((total_shannons - fee_shannons) / output_shannons) as usize {
-        0 => 1,
-        x if x < outputs_limit => x,
-        _ => outputs_limit,
-    }
+// Save the failed transaction and the error, for debugging after the run.
+fn save_failed_tx(dir: &Path, tx_hash: &H256, stx_str: &str, err: &Error) -> Result<()> {
+    let path = dir.join(format!("{:#x}.json", tx_hash));
+    fs::write(&path, stx_str)
+        .and_then(|_| fs::write(path.with_extension("err"), err.to_string()))
+        .map_err(|err| {
+            let errmsg = format!(
+                "failed to save the failed tx {:#x} into [{}] since {}",
+                tx_hash,
+                dir.display(),
+                err
+            );
+            Error::runtime(errmsg)
+        })
 }
 
-fn construct_raw_transaction(
-    inputs_info: &[InputInfo],
+fn output_lock_ids(
+    stx: &packed::Transaction,
     accounts: &HashMap<H256, LockInfo>,
-    lock_generator: &LockGenerator,
-    lock_deps_dict: &HashMap<LockScriptId, Vec<packed::CellDep>>,
-    outputs_limit: usize,
-    output_bytes: u32,
-    fee_shannons: u64,
-) -> Result<packed::RawTransaction> {
-    let inputs = inputs_info
-        .iter()
-        .map(|input| {
-            packed::CellInput::new_builder()
-                .previous_output(input.out_point.clone())
-                .build()
-        })
-        .collect::<Vec<_>>();
-    let inputs_cap = inputs_info
-        .iter()
-        .try_fold(core::Capacity::zero(), |total, next| {
-            total.safe_add(next.cell_info.capacity)
+) -> Vec<LockScriptId> {
+    stx.raw()
+        .outputs()
+        .into_iter()
+        .filter_map(|output| {
+            let hash: H256 = output.lock().calc_script_hash().unpack();
+            accounts.get(&hash).map(|lock_info| lock_info.id)
         })
-        .map_err(Error::runtime)?;
-    let cell_deps = inputs_info
-        .iter()
-        .try_fold(Vec::new(), |mut ids, input| {
-            accounts.get(&input.cell_info.lock_hash).map(|account| {
+        .collect()
+}
+
+fn sleep_millis(interval: u64) {
+    thread::sleep(time::Duration::from_millis(interval));
+}
+
+impl FailoverClient {
+    fn check_node(&self, min_version: Option<(u64, u64, u64)>) -> Result<()> {
+        let node_info = self.local_node_info()?;
+        log::info!("the version of the node is {}", node_info.version);
+        if let Some(min_version) = min_version {
+            let version = parse_version(&node_info.version).ok_or_else(|| {
+                let errmsg = format!("unrecognized version {} of the node", node_info.version);
+                Error::client(errmsg)
+            })?;
+            if version < min_version {
+                let errmsg = format!(
+                    "the version of the node is {}, but {}.{}.{} at least is required",
+                    node_info.version, min_version.0, min_version.1, min_version.2
+                );
+                return Err(Error::client(errmsg));
+            }
+        }
+        Ok(())
+    }
+
+    fn check_ibd(&self, allow_ibd: bool) -> Result<()> {
+        let chain_info = self.get_blockchain_info()?;
+        if chain_info.is_initial_block_download {
+            if !allow_ibd {
+                let errmsg = format!(
+                    "the node is in the initial block download of the chain {}, \
+                    the transactions may be rejected or never committed",
+                    chain_info.chain
+                );
+                return Err(Error::runtime(errmsg));
+            }
+            log::warn!(
+                "the node is in the initial block download of the chain {}, \
+                the transactions may be rejected or never committed",
+                chain_info.chain
+            );
+        }
+        Ok(())
+    }
+
+    pub(super) fn check_chain(&self, start_meta: &BlockMeta) -> Result<()> {
+        let start_header = self
+            .get_header_by_number(start_meta.number)?
+            .ok_or_else(|| Error::runtime("the provided node doesn't have enough chain data"))?;
+        if start_header.hash != start_meta.hash {
+            let errmsg = format!(
+                "the provided node isn't the expected chain, block#{}'s hash should be {:#x}, but got {:#x}",
+                start_meta.number, start_meta.hash, start_header.hash
+            );
+            Err(Error::runtime(errmsg))
+        } else {
+            Ok(())
+        }
+    }
+
+    // Split the inputs into the live ones and the dead ones, one request for each input.
+    fn split_dead_inputs(
+        &self,
+        inputs: Vec<InputInfo>,
+    ) -> Result<(Vec<InputInfo>, Vec<InputInfo>)> {
+        let mut live = Vec::with_capacity(inputs.len());
+        let mut dead = Vec::new();
+        for input in inputs {
+            let cell = self.get_live_cell(input.out_point.clone().into(), false)?;
+            if cell.status == "live" {
+                live.push(input);
+            } else {
+                log::debug!("input {} is {}", input.out_point, cell.status);
+                dead.push(input);
+            }
+        }
+        Ok((live, dead))
+    }
+
+    fn refresh_pending_txs(&self, stg: &Storage, pending_txs: &mut HashSet<H256>) -> Result<()> {
+        let mut finished = Vec::new();
+        for tx_hash in pending_txs.iter() {
+            let tx = match self.get_transaction(tx_hash.clone()) {
+                Ok(tx) => tx,
+                Err(err) => {
+                    // Keep it pending, and check it again in the next refresh.
+                    log::warn!("failed to get the tx {:#x} since {}", tx_hash, err);
+                    continue;
+                }
+            };
+            match tx {
+                Some(tx) if tx.tx_status.status == rpc::Status::Committed => {
+                    if let Some(sent_tx) = stg.get_sent_tx(tx_hash)? {
+                        log::trace!(
+                            "tx {:#x} is committed, it was sent at block#{}",
+                            tx_hash,
+                            sent_tx.sent_at
+                        );
+                    } else {
+                        log::trace!("tx {:#x} is committed", tx_hash);
+                    }
+                    finished.push(tx_hash.clone());
+                }
+                Some(_) => {}
+                None => {
+                    log::debug!("tx {:#x} is not found in the node", tx_hash);
+                    finished.push(tx_hash.clone());
+                }
+            }
+        }
+        for tx_hash in finished {
+            pending_txs.remove(&tx_hash);
+        }
+        Ok(())
+    }
+
+    fn get_block_with_retries(
+        &self,
+        block_number: core::BlockNumber,
+        reconnect: Option<&ReconnectConfig>,
+    ) -> Result<Option<rpc::BlockView>> {
+        let mut retries = 0;
+        loop {
+            match self.get_block_by_number(block_number) {
+                Ok(block) => return Ok(block),
+                Err(err) => {
+                    let cfg = match reconnect {
+                        Some(cfg) if retries < cfg.retries => cfg,
+                        _ => return Err(err),
+                    };
+                    retries += 1;
+                    log::warn!(
+                        "failed to fetch block#{} since {}, reconnect and retry ({}/{})",
+                        block_number,
+                        err,
+                        retries,
+                        cfg.retries
+                    );
+                    sleep_millis(cfg.interval);
+                    if let Err(err) = self.reconnect() {
+                        log::warn!("failed to reconnect since {}", err);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+pub(super) struct SyncStats {
+    blocks: u64,
+    blocks_skipped: u64,
+    cells_added: u64,
+    cells_removed: u64,
+    rpc_calls: u64,
+    elapsed: time::Duration,
+}
+
+impl SyncStats {
+    pub(super) fn merge(&mut self, other: &Self) {
+        self.blocks += other.blocks;
+        self.blocks_skipped += other.blocks_skipped;
+        self.cells_added += other.cells_added;
+        self.cells_removed += other.cells_removed;
+        self.rpc_calls += other.rpc_calls;
+        self.elapsed += other.elapsed;
+    }
+
+    fn blocks_per_second(&self) -> f64 {
+        let secs = self.elapsed.as_secs_f64();
+        if secs > 0.0 {
+            self.blocks as f64 / secs
+        } else {
+            0.0
+        }
+    }
+}
+
+impl fmt::Display for SyncStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} blocks ({} skipped) in {:.3}s ({:.2} blocks/s), \
+            {} cells added, {} cells removed, {} RPC calls",
+            self.blocks,
+            self.blocks_skipped,
+            self.elapsed.as_secs_f64(),
+            self.blocks_per_second(),
+            self.cells_added,
+            self.cells_removed,
+            self.rpc_calls
+        )
+    }
+}
+
+#[derive(Default)]
+struct LockStats {
+    txs: HashMap<LockScriptId, u64>,
+    outputs: HashMap<LockScriptId, u64>,
+}
+
+impl LockStats {
+    fn record(&mut self, output_lock_ids: &[LockScriptId]) {
+        for id in output_lock_ids {
+            *self.outputs.entry(*id).or_insert(0) += 1;
+        }
+        let mut ids = output_lock_ids.to_vec();
+        ids.sort();
+        ids.dedup();
+        for id in ids {
+            *self.txs.entry(id).or_insert(0) += 1;
+        }
+    }
+
+    fn report(&self, locks_weights: &HashMap<LockScriptId, usize>) -> String {
+        let total_outputs: u64 = self.outputs.values().sum();
+        let total_weights: usize = locks_weights.values().sum();
+        let mut ids = locks_weights
+            .keys()
+            .chain(self.outputs.keys())
+            .copied()
+            .collect::<Vec<_>>();
+        ids.sort();
+        ids.dedup();
+        ids.into_iter()
+            .map(|id| {
+                let outputs = self.outputs.get(&id).copied().unwrap_or(0);
+                let weight = locks_weights.get(&id).copied().unwrap_or(0);
+                format!(
+                    "{}: {} txs, {} outputs ({:.1}% observed, {:.1}% configured)",
+                    id,
+                    self.txs.get(&id).copied().unwrap_or(0),
+                    outputs,
+                    percent(outputs as f64, total_outputs as f64),
+                    percent(weight as f64, total_weights as f64),
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("; ")
+    }
+}
+
+fn percent(part: f64, total: f64) -> f64 {
+    if total > 0.0 {
+        part * 100.0 / total
+    } else {
+        0.0
+    }
+}
+
+#[derive(Default)]
+pub(super) struct TipCache {
+    number: core::BlockNumber,
+    fetched_at: Option<time::Instant>,
+    // The new tips are pushed by the node, then the tip is only polled once.
+    subscription: Option<mpsc::Receiver<Result<rpc::HeaderView>>>,
+}
+
+impl TipCache {
+    fn subscribe(&mut self, cli: &FailoverClient) {
+        self.subscription = cli.subscribe_new_tip();
+        if self.subscription.is_some() {
+            log::info!("subscribed the new tips");
+        } else {
+            log::warn!("only WebSocket could subscribe the new tips, poll the tip instead");
+        }
+    }
+
+    // Receive the pushed tips, wait for the first one if the timeout is set, return whether a
+    // new tip is received.
+    fn receive(&mut self, mut timeout: Option<time::Duration>) -> bool {
+        let mut received = false;
+        while let Some(ref subscription) = self.subscription {
+            // The error is whether the subscription is closed.
+            let item = match timeout.take() {
+                Some(timeout) => subscription
+                    .recv_timeout(timeout)
+                    .map_err(|err| err == mpsc::RecvTimeoutError::Disconnected),
+                None => subscription
+                    .try_recv()
+                    .map_err(|err| err == mpsc::TryRecvError::Disconnected),
+            };
+            match item {
+                Ok(Ok(header)) => {
+                    self.number = header.inner.number.into();
+                    self.fetched_at = Some(time::Instant::now());
+                    log::trace!("received the new tip block#{}", self.number);
+                    received = true;
+                }
+                Ok(Err(err)) => {
+                    log::warn!("the subscription of new tips failed since {}", err);
+                    self.subscription = None;
+                }
+                Err(true) => {
+                    log::warn!("the subscription of new tips is closed, poll the tip instead");
+                    self.subscription = None;
+                }
+                Err(false) => break,
+            }
+        }
+        received
+    }
+
+    // Wait for new blocks, wake up once a new tip is pushed if subscribed.
+    fn wait(&mut self, idle_interval: u64, shutdown: &AtomicBool) {
+        if self.subscription.is_none() {
+            sleep_millis(idle_interval);
+            return;
+        }
+        let timeout = time::Duration::from_millis(idle_interval);
+        while self.subscription.is_some() && !shutdown.load(Ordering::SeqCst) {
+            if self.receive(Some(timeout)) {
+                break;
+            }
+        }
+    }
+
+    fn get(
+        &mut self,
+        cli: &FailoverClient,
+        min_interval: Option<u64>,
+        stats: &mut SyncStats,
+    ) -> Result<core::BlockNumber> {
+        self.receive(None);
+        // Poll the tip for the first time, the later ones are pushed.
+        if self.subscription.is_some() && self.fetched_at.is_some() {
+            return Ok(self.number);
+        }
+        let expired = match (self.fetched_at, min_interval) {
+            (Some(fetched_at), Some(interval)) => {
+                fetched_at.elapsed() >= time::Duration::from_millis(interval)
+            }
+            _ => true,
+        };
+        if expired {
+            self.number = cli.get_tip_block_number()?;
+            self.fetched_at = Some(time::Instant::now());
+            stats.rpc_calls += 1;
+        } else {
+            log::trace!("use the cached tip block#{}", self.number);
+        }
+        Ok(self.number)
+    }
+}
+
+// Hold the interval between two successful transactions, the time to construct and send a
+// transaction is included.
+struct RateLimiter {
+    interval: time::Duration,
+    last_at: Option<time::Instant>,
+    lagging: bool,
+}
+
+impl RateLimiter {
+    fn new(interval: time::Duration) -> Self {
+        Self {
+            interval,
+            last_at: None,
+            lagging: false,
+        }
+    }
+
+    // Forget the last transaction, the pauses between rounds are not counted.
+    fn reset(&mut self) {
+        self.last_at = None;
+    }
+
+    fn wait(&mut self) {
+        if let Some(last_at) = self.last_at {
+            let elapsed = last_at.elapsed();
+            if elapsed < self.interval {
+                thread::sleep(self.interval - elapsed);
+                self.lagging = false;
+            } else if !self.lagging {
+                log::warn!(
+                    "can not keep up with the target TPS, a transaction takes {} ms, \
+                    but the target interval is {} ms",
+                    elapsed.as_millis(),
+                    self.interval.as_millis()
+                );
+                self.lagging = true;
+            }
+        }
+        self.last_at = Some(time::Instant::now());
+    }
+}
+
+// Load the live cells from the indexer on the first run, then search the blocks after them.
+fn bootstrap_from_indexer(
+    cli: &FailoverClient,
+    stg: &Storage,
+    accounts: &HashMap<H256, LockInfo>,
+    start_block: core::BlockNumber,
+    cfg: &RunEnv,
+    tip_cache: &mut TipCache,
+) -> Result<()> {
+    log::info!("bootstrapping the cells from the indexer ...");
+    let result = synchronize_from_indexer(
+        cli,
+        stg,
+        accounts,
+        start_block,
+        cfg,
+        BOOTSTRAP_PAGE_SIZE,
+        tip_cache,
+    );
+    match result {
+        Ok((_, stats)) => {
+            log::info!("bootstrapped from the indexer: {}", stats);
+            Ok(())
+        }
+        // The node returns an error when the indexer is not enabled.
+        Err(Error::Client(errmsg, _)) => {
+            log::warn!(
+                "failed to bootstrap from the indexer since {}, search all blocks instead",
+                errmsg
+            );
+            Ok(())
+        }
+        Err(err) => Err(err),
+    }
+}
+
+// The block number to synchronize to, which is "delay_blocks" behind the tip.
+//
+// Return `None` if it is not ahead of the next number, which means the cells are caught up, even
+// if the tip is lower than "delay_blocks".
+fn delayed_target(
+    next_num: core::BlockNumber,
+    tip_num: core::BlockNumber,
+    delay_blocks: u64,
+) -> Option<core::BlockNumber> {
+    tip_num
+        .checked_sub(delay_blocks)
+        .filter(|target_num| *target_num > next_num)
+}
+
+// The last block number to synchronize in a round, at least one block is synchronized.
+fn sync_end_number(
+    next_num: core::BlockNumber,
+    target_num: core::BlockNumber,
+    max_blocks: Option<u64>,
+) -> core::BlockNumber {
+    max_blocks
+        .map(|max_blocks| cmp::min(target_num, next_num.saturating_add(max_blocks.max(1) - 1)))
+        .unwrap_or(target_num)
+}
+
+// Load the live cells of the accounts from the indexer instead of searching all blocks.
+fn synchronize_from_indexer(
+    cli: &FailoverClient,
+    stg: &Storage,
+    accounts: &HashMap<H256, LockInfo>,
+    start_block: core::BlockNumber,
+    cfg: &RunEnv,
+    page_size: u32,
+    tip_cache: &mut TipCache,
+) -> Result<(bool, SyncStats)> {
+    let delay_blocks = cfg.delay_blocks;
+    let started_at = time::Instant::now();
+    let mut stats = SyncStats::default();
+    let next_num = stg.get_next_number()?.unwrap_or(start_block);
+    let tip_num = cli
+        .get_indexer_tip()?
+        .map(|tip| tip.block_number.into())
+        .unwrap_or(0);
+    stats.rpc_calls += 1;
+    // Only keep the number, the indexer tip is not cached.
+    tip_cache.number = tip_num;
+    log::trace!("indexer tip: {}, next to search: {}", tip_num, next_num);
+    let target_num = delayed_target(next_num, tip_num, delay_blocks);
+    let skip_sync = target_num.is_none();
+    if let Some(end_num) = target_num {
+        log::debug!("synchronizing to block#{} from the indexer ...", end_num);
+        let mut live_cells = HashSet::new();
+        let min_shannons = min_inputs_shannons(&cfg.generator);
+        let mut dust_cells = 0;
+        for (hash, lock_info) in accounts {
+            let mut after_cursor = None;
+            loop {
+                let page = cli.get_live_cells_by_lock(
+                    lock_info.script.clone().into(),
+                    page_size,
+                    after_cursor,
+                )?;
+                stats.rpc_calls += 1;
+                let count = page.objects.len();
+                for cell in page.objects {
+                    let output: packed::CellOutput = cell.output.into();
+                    // The indexer matches the prefix of the lock args.
+                    if output.lock() != lock_info.script || output.type_().is_some() {
+                        continue;
+                    }
+                    let out_point: packed::OutPoint = cell.out_point.into();
+                    live_cells.insert(out_point.clone());
+                    let num: core::BlockNumber = cell.block_number.into();
+                    if num < start_block || num > end_num || stg.has_cell(&out_point)? {
+                        continue;
+                    }
+                    let output_cap: core::Capacity = output.capacity().unpack();
+                    if output_cap.as_u64() < min_shannons {
+                        log::trace!(
+                            "skip a dust cell {} ({} shannons)",
+                            out_point,
+                            output_cap.as_u64()
+                        );
+                        dust_cells += 1;
+                        continue;
+                    }
+                    log::trace!("found a new cell {}", out_point);
+                    let cell_info = CellInfo::new(output_cap, hash.clone(), num);
+                    stg.add_cell(out_point, cell_info)?;
+                    stats.cells_added += 1;
+                }
+                if count < page_size as usize {
+                    break;
+                }
+                after_cursor = Some(page.last_cursor);
+            }
+        }
+        warn_dust_cells(dust_cells, min_shannons);
+        stats.cells_removed += stg.retain_cells(&live_cells)?;
+        stg.put_prev_number(end_num)?;
+        stats.blocks = end_num + 1 - next_num;
+    }
+    stats.elapsed = started_at.elapsed();
+    Ok((skip_sync, stats))
+}
+
+pub(super) fn synchronize(
+    cli: &FailoverClient,
+    stg: &Storage,
+    accounts: &HashMap<H256, LockInfo>,
+    start_block: core::BlockNumber,
+    cfg: &RunEnv,
+    tip_cache: &mut TipCache,
+) -> Result<(bool, SyncStats)> {
+    let delay_blocks = cfg.delay_blocks;
+    let started_at = time::Instant::now();
+    let mut stats = SyncStats::default();
+    let next_num = stg.get_next_number()?.unwrap_or(start_block);
+    let tip_num = tip_cache.get(cli, cfg.client.tip_poll_min_interval_ms, &mut stats)?;
+    let search_when_num = next_num.saturating_add(delay_blocks);
+    log::trace!(
+        "current tip: {}, next to search: {}, search when {}",
+        tip_num,
+        next_num,
+        search_when_num
+    );
+    let mut target_num = delayed_target(next_num, tip_num, delay_blocks).unwrap_or(next_num);
+    let mut skip_sync = target_num == next_num;
+    if !skip_sync {
+        if let Some(min_age) = cfg.min_tip_age_secs {
+            match stable_block_number(cli, next_num, target_num, min_age, &mut stats)? {
+                Some(num) => target_num = num,
+                None => {
+                    log::trace!("no new blocks are older than {} seconds", min_age);
+                    skip_sync = true;
+                }
+            }
+        }
+    }
+    if !skip_sync {
+        // Leave the rest blocks to the next rounds, they are not skipped since the next
+        // number is still behind the target.
+        let end_num = sync_end_number(next_num, target_num, cfg.max_sync_blocks_per_round);
+        log::debug!(
+            "synchronizing to block#{} (target: block#{}) ...",
+            end_num,
+            target_num
+        );
+        let account_scripts = accounts
+            .values()
+            .map(|lock_info| lock_info.script.clone().into())
+            .collect::<HashSet<rpc::Script>>();
+        let min_shannons = min_inputs_shannons(&cfg.generator);
+        let mut dust_cells = 0;
+        let mut last_skipped = None;
+        let mut parent_hash = match next_num.checked_sub(1) {
+            Some(prev_num) if next_num > start_block => stg.get_block_hash(prev_num)?,
+            _ => None,
+        };
+        for num in next_num..=end_num {
+            log::trace!("fetching block#{} ...", num);
+            let block = cli
+                .get_block_with_retries(num, cfg.client.reconnect.as_ref())?
+                .ok_or_else(|| {
+                    let errmsg = format!("block#{} should exists but CKB node returns None", num);
+                    Error::runtime(errmsg)
+                })?;
+            stats.rpc_calls += 1;
+            if let Some(ref hash) = parent_hash {
+                if &block.header.inner.parent_hash != hash {
+                    if let Some((prev_num, prev_hash)) = last_skipped.take() {
+                        stg.put_prev_block(prev_num, prev_hash)?;
+                    }
+                    rollback(cli, stg, start_block, num, delay_blocks, &mut stats)?;
+                    break;
+                }
+            }
+            parent_hash = Some(block.header.hash.clone());
+            if cfg.skip_empty_blocks && !is_relevant_block(&block, &account_scripts) {
+                // Delay to save the block number until a relevant block or the end.
+                last_skipped = Some((num, block.header.hash));
+                stats.blocks += 1;
+                stats.blocks_skipped += 1;
+                continue;
+            }
+            let (adds, removes, dust) = block_changes(&block, accounts, min_shannons);
+            dust_cells += dust;
+            stats.cells_added += adds.len() as u64;
+            stats.cells_removed +=
+                stg.apply_block_changes(adds, removes, num, block.header.hash)?;
+            last_skipped = None;
+            stats.blocks += 1;
+        }
+        if let Some((num, hash)) = last_skipped {
+            stg.put_prev_block(num, hash)?;
+        }
+        warn_dust_cells(dust_cells, min_shannons);
+    };
+    stats.elapsed = started_at.elapsed();
+    Ok((skip_sync, stats))
+}
+
+// Find the new cells of the accounts and the spent cells in a block, the cells with a type
+// script and the dust cells are skipped.
+//
+// Returns the added cells, the removed cells and the count of the dust cells.
+pub(super) fn block_changes(
+    block: &rpc::BlockView,
+    accounts: &HashMap<H256, LockInfo>,
+    min_shannons: u64,
+) -> (
+    Vec<(packed::OutPoint, CellInfo)>,
+    Vec<packed::OutPoint>,
+    usize,
+) {
+    let num: core::BlockNumber = block.header.inner.number.into();
+    let mut adds = Vec::new();
+    let mut removes = Vec::new();
+    let mut dust_cells = 0;
+    for tx in &block.transactions {
+        for (index, output_json) in tx.inner.outputs.iter().enumerate() {
+            let output: packed::CellOutput = output_json.clone().into();
+            if output.type_().is_some() {
+                // Cells with a type script, such as DAO deposits, can not be spent as normal
+                // inputs.
+                continue;
+            }
+            for (hash, lock_info) in accounts {
+                if output.lock() == lock_info.script {
+                    let output_cap: core::Capacity = output.capacity().unpack();
+                    if output_cap.as_u64() < min_shannons {
+                        log::trace!(
+                            "skip a dust cell {:#x}.{} ({} shannons)",
+                            tx.hash,
+                            index,
+                            output_cap.as_u64()
+                        );
+                        dust_cells += 1;
+                        continue;
+                    }
+                    log::trace!("found a new cell {:#x}.{}", tx.hash, index);
+                    let out_point = packed::OutPoint::new_builder()
+                        .tx_hash(tx.hash.pack())
+                        .index(index.pack())
+                        .build();
+                    let cell_info = CellInfo::new(output_cap, hash.clone(), num);
+                    adds.push((out_point, cell_info));
+                }
+            }
+        }
+        for input in &tx.inner.inputs {
+            removes.push(input.previous_output.clone().into());
+        }
+    }
+    (adds, removes, dust_cells)
+}
+
+// Walk back from the target to find the last block which is old enough, return None if even
+// the next block to search is too new.
+fn stable_block_number(
+    cli: &FailoverClient,
+    next_num: core::BlockNumber,
+    target_num: core::BlockNumber,
+    min_age_secs: u64,
+    stats: &mut SyncStats,
+) -> Result<Option<core::BlockNumber>> {
+    let now_millis = time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .map_err(Error::runtime)?
+        .as_millis() as u64;
+    let max_timestamp = now_millis.saturating_sub(min_age_secs * 1000);
+    for num in (next_num..=target_num).rev() {
+        let header = cli.get_header_by_number(num)?.ok_or_else(|| {
+            let errmsg = format!("header#{} should exists but CKB node returns None", num);
+            Error::runtime(errmsg)
+        })?;
+        stats.rpc_calls += 1;
+        let timestamp: u64 = header.inner.timestamp.into();
+        if timestamp <= max_timestamp {
+            if num < target_num {
+                log::trace!(
+                    "block#{} is too new, synchronize to block#{}",
+                    target_num,
+                    num
+                );
+            }
+            return Ok(Some(num));
+        }
+    }
+    Ok(None)
+}
+
+// The block is not a child of the last synchronized block, walk back to find the fork point,
+// then roll back all blocks after it.
+fn rollback(
+    cli: &FailoverClient,
+    stg: &Storage,
+    start_block: core::BlockNumber,
+    orphan_child: core::BlockNumber,
+    delay_blocks: u64,
+    stats: &mut SyncStats,
+) -> Result<()> {
+    let min_num = cmp::max(
+        start_block,
+        orphan_child.saturating_sub(MAX_ROLLBACK_BLOCKS),
+    );
+    let mut fork_num = None;
+    for num in (min_num..orphan_child).rev() {
+        // The hashes of the skipped empty blocks are not stored.
+        if let Some(stored_hash) = stg.get_block_hash(num)? {
+            let header = cli.get_header_by_number(num)?;
+            stats.rpc_calls += 1;
+            if header.map(|header| header.hash) == Some(stored_hash) {
+                fork_num = Some(num + 1);
+                break;
+            }
+        }
+    }
+    let fork_num = match fork_num {
+        Some(num) => num,
+        None if min_num == start_block => start_block,
+        None => {
+            let errmsg = format!(
+                "the chain is reorganized deeper than {} blocks before block#{}, \
+                please initialize the data again",
+                MAX_ROLLBACK_BLOCKS, orphan_child
+            );
+            return Err(Error::runtime(errmsg));
+        }
+    };
+    let depth = orphan_child - fork_num;
+    if depth > delay_blocks {
+        log::warn!(
+            "the chain is reorganized since block#{}, {} blocks are orphaned, \
+            it's deeper than the delay blocks {}",
+            fork_num,
+            depth,
+            delay_blocks
+        );
+    } else {
+        log::info!(
+            "the chain is reorganized since block#{}, {} blocks are orphaned",
+            fork_num,
+            depth
+        );
+    }
+    let (removed, restored) = stg.rollback_blocks(fork_num)?;
+    log::info!(
+        "rolled back to block#{}, removed {} cells and restored {} cells",
+        fork_num,
+        removed,
+        restored
+    );
+    stats.cells_removed += removed;
+    stats.cells_added += restored;
+    Ok(())
+}
+
+// A block is relevant if it has any transaction except the cellbase, or any output for accounts.
+fn is_relevant_block(block: &rpc::BlockView, account_scripts: &HashSet<rpc::Script>) -> bool {
+    block.transactions.len() > 1
+        || block.transactions.iter().any(|tx| {
+            tx.inner
+                .outputs
+                .iter()
+                .any(|output| output.type_.is_none() && account_scripts.contains(&output.lock))
+        })
+}
+
+enum FetchInputsResult {
+    Lack,
+    Next,
+    Enough,
+}
+
+fn fetch_more_inputs(
+    cells_queue: &mut VecDeque<InputInfo>,
+    inputs: &mut Vec<InputInfo>,
+    cfg: &GeneratorConfig,
+    expected_input_size: usize,
+) -> Result<FetchInputsResult> {
+    let inputs_limit = cfg.inputs_limit;
+    let min_shannons = min_inputs_shannons(cfg);
+    let mut last = false;
+    let mut lock_hashes = inputs
+        .iter()
+        .map(|input| input.cell_info.lock_hash.clone())
+        .collect::<Vec<_>>();
+    lock_hashes.sort();
+    lock_hashes.dedup();
+    let locks_limited = cfg
+        .max_locks_per_tx
+        .and_then(|max_locks| {
+            cells_queue.front().map(|cell| {
+                lock_hashes.len() >= max_locks && !lock_hashes.contains(&cell.cell_info.lock_hash)
+            })
+        })
+        .unwrap_or(false);
+    if locks_limited {
+        // Keep the cell for the next transaction, unless the current inputs are not enough.
+        log::trace!("the count of locks reaches the limit");
+        last = true;
+    } else if let Some(cell) = cells_queue.pop_front() {
+        inputs.push(cell);
+    } else {
+        if inputs.is_empty() {
+            return Ok(FetchInputsResult::Lack);
+        }
+        last = true;
+    }
+
+    let total = inputs
+        .iter()
+        .map(|input| input.cell_info.capacity)
+        .try_fold(core::Capacity::zero(), |total, cap| total.safe_add(cap))
+        .map_err(Error::runtime)?
+        .as_u64();
+
+    if total < min_shannons {
+        if locks_limited {
+            // Take a later cell with the same locks, the cells before it are kept in order.
+            let index = cells_queue
+                .iter()
+                .position(|cell| lock_hashes.contains(&cell.cell_info.lock_hash));
+            if let Some(cell) = index.and_then(|index| cells_queue.remove(index)) {
+                inputs.push(cell);
+            } else {
+                // The cells are kept in the storage for next rounds.
+                log::debug!(
+                    "no more cells for the locks of {} inputs, skip them",
+                    inputs.len()
+                );
+                inputs.clear();
+            }
+            return Ok(FetchInputsResult::Next);
+        }
+        let res = if last {
+            FetchInputsResult::Lack
+        } else {
+            FetchInputsResult::Next
+        };
+        return Ok(res);
+    }
+
+    if last || inputs.len() >= std::cmp::min(expected_input_size, inputs_limit) {
+        Ok(FetchInputsResult::Enough)
+    } else {
+        Ok(FetchInputsResult::Next)
+    }
+}
+
+// The minimum capacity of inputs, which should be enough for an output and the maximum fee.
+pub(super) fn min_inputs_shannons(cfg: &GeneratorConfig) -> u64 {
+    ((cfg.output_min_bytes() + 1) * BYTE_SHANNONS).saturating_add(cfg.tx_fee_jitter.unwrap_or(0))
+}
+
+// A dust cell can not pay for an output and the fee, so it is not stored; otherwise, it would be
+// selected and dropped again and again when the other cells are not enough.
+fn warn_dust_cells(count: usize, min_shannons: u64) {
+    if count > 0 {
+        log::warn!(
+            "skip {} cells whose capacity is less than {} shannons",
+            count,
+            min_shannons
+        );
+    }
+}
+
+// Sort the cells before selecting, the order is kept in the groups of the settlement cells.
+fn sort_cells(cells: &mut [InputInfo], strategy: CellSelectionStrategy, rng: &SimRng) {
+    match strategy {
+        CellSelectionStrategy::StorageOrder => {}
+        CellSelectionStrategy::SmallestFirst => {
+            cells.sort_by_key(|cell| cell.cell_info.capacity.as_u64());
+        }
+        CellSelectionStrategy::LargestFirst => {
+            cells.sort_by_key(|cell| cmp::Reverse(cell.cell_info.capacity.as_u64()));
+        }
+        CellSelectionStrategy::Random => rng.shuffle(cells),
+    }
+}
+
+fn group_cells_by_lock(cells: &[InputInfo]) -> HashMap<H256, Vec<InputInfo>> {
+    cells
+        .iter()
+        .fold(HashMap::new(), |mut cells_by_lock, cell| {
+            cells_by_lock
+                .entry(cell.cell_info.lock_hash.clone())
+                .or_insert_with(Vec::new)
+                .push(cell.clone());
+            cells_by_lock
+        })
+}
+
+fn select_settlement_inputs(
+    cells_by_lock: &mut HashMap<H256, Vec<InputInfo>>,
+    inputs: &mut Vec<InputInfo>,
+    lock_hashes: &[H256],
+    min_shannons: u64,
+) -> Result<FetchInputsResult> {
+    if lock_hashes.iter().any(|lock_hash| {
+        cells_by_lock
+            .get(lock_hash)
+            .map(Vec::is_empty)
+            .unwrap_or(true)
+    }) {
+        log::trace!("no more cells for some settlement accounts");
+        return Ok(FetchInputsResult::Lack);
+    }
+    for lock_hash in lock_hashes {
+        if let Some(cell) = cells_by_lock.get_mut(lock_hash).and_then(Vec::pop) {
+            inputs.push(cell);
+        }
+    }
+    let total = inputs
+        .iter()
+        .map(|input| input.cell_info.capacity)
+        .try_fold(core::Capacity::zero(), |total, cap| total.safe_add(cap))
+        .map_err(Error::runtime)?
+        .as_u64();
+    if total < min_shannons {
+        log::trace!("the capacity of the settlement inputs is not enough");
+        inputs.clear();
+        return Ok(FetchInputsResult::Lack);
+    }
+    Ok(FetchInputsResult::Enough)
+}
+
+fn prepare_inputs(total_inputs: &mut Vec<InputInfo>) -> (Vec<H256>, Vec<InputInfo>) {
+    total_inputs.sort_by_key(|input| input.cell_info.lock_hash.clone());
+    let mut inputs_ext = total_inputs
+        .drain(..)
+        .fold((Vec::new(), None), |(mut result, prev), input| {
+            let curr = Some(input.cell_info.lock_hash.clone());
+            let is_first = prev != curr;
+            result.push((is_first, input));
+            (result, curr)
+        })
+        .0;
+    inputs_ext.sort_by_key(|(is_first, input)| (!is_first, input.cell_info.lock_hash.clone()));
+    let lock_hashes = inputs_ext
+        .iter()
+        .filter(|(is_first, _)| *is_first)
+        .map(|(_, input)| input.cell_info.lock_hash.to_owned())
+        .collect::<Vec<_>>();
+    let inputs = inputs_ext
+        .into_iter()
+        .map(|(_, input)| input)
+        .collect::<Vec<_>>();
+    (lock_hashes, inputs)
+}
+
+// Draw the capacities of the outputs except the first one, until the outputs are enough or the
+// rest capacity for the first output would be less than the drawn one.
+fn draw_output_capacities(
+    outputs_limit: usize,
+    total_shannons: u64,
+    range_shannons: (u64, u64),
+    rng: &SimRng,
+) -> Vec<u64> {
+    let (min, max) = range_shannons;
+    let mut rest = total_shannons;
+    let mut capacities = Vec::new();
+    while capacities.len() + 1 < outputs_limit {
+        let capacity = rng.gen_between(min, max);
+        match rest.checked_sub(capacity) {
+            Some(new_rest) if new_rest >= capacity => {
+                rest = new_rest;
+                capacities.push(capacity);
+            }
+            _ => break,
+        }
+    }
+    capacities
+}
+
+// The capacity overflows u64 or underflows zero.
+fn capacity_out_of_range(what: &str) -> Error {
+    Error::runtime(format!("out of range when calculating the {}", what))
+}
+
+// Split the capacity (without the fee) into the change output and the drawn outputs, the change
+// output is the first one, until it is placed by the split policy.
+fn split_capacity(
+    split: CapacitySplit,
+    total_shannons: u64,
+    drawn_capacities: Vec<u64>,
+) -> Result<Vec<u64>> {
+    let outputs_count = drawn_capacities.len() + 1;
+    let count = outputs_count as u64;
+    let capacities = match split {
+        CapacitySplit::ChangeFirst => {
+            let change = drawn_capacities
+                .iter()
+                .try_fold(0u64, |sum, capacity| sum.checked_add(*capacity))
+                .and_then(|others| total_shannons.checked_sub(others))
+                .ok_or_else(|| capacity_out_of_range("change capacity"))?;
+            iter::once(change).chain(drawn_capacities).collect()
+        }
+        // The change output takes the remainder, it is placed as the last one.
+        CapacitySplit::EvenWithRemainderLast => {
+            let mut capacities = vec![total_shannons / count; outputs_count];
+            capacities[0] = capacities[0]
+                .checked_add(total_shannons % count)
+                .ok_or_else(|| capacity_out_of_range("change capacity"))?;
+            capacities
+        }
+    };
+    Ok(capacities)
+}
+
+// Jitter all outputs except the change output (the first one), it absorbs the differences.
+fn jitter_capacities(
+    capacities: &mut [u64],
+    jitter: &CapacityJitter,
+    min_shannons: u64,
+    rng: &SimRng,
+) -> Result<()> {
+    for index in 1..capacities.len() {
+        let jittered = cmp::max(jitter.apply(capacities[index], rng), min_shannons);
+        if jittered > capacities[index] {
+            let available = capacities[0].saturating_sub(min_shannons);
+            let increase = cmp::min(jittered - capacities[index], available);
+            capacities[0] -= increase;
+            capacities[index] = capacities[index]
+                .checked_add(increase)
+                .ok_or_else(|| capacity_out_of_range("jittered capacity"))?;
+        } else {
+            let decrease = capacities[index] - jittered;
+            capacities[0] = capacities[0]
+                .checked_add(decrease)
+                .ok_or_else(|| capacity_out_of_range("change capacity"))?;
+            capacities[index] = jittered;
+        }
+    }
+    Ok(())
+}
+
+fn construct_raw_transaction(
+    inputs_info: &[InputInfo],
+    accounts: &HashMap<H256, LockInfo>,
+    lock_generator: &LockGenerator,
+    lock_deps_dict: &HashMap<LockScriptId, Vec<packed::CellDep>>,
+    cfg: &GeneratorConfig,
+    rng: &SimRng,
+    fee_multiplier: u64,
+) -> Result<Option<packed::RawTransaction>> {
+    let inputs = inputs_info
+        .iter()
+        .map(|input| {
+            packed::CellInput::new_builder()
+                .previous_output(input.out_point.clone())
+                .build()
+        })
+        .collect::<Vec<_>>();
+    let dao_deposit = cfg
+        .dao_deposit
+        .as_ref()
+        .filter(|dao| dao.should_deposit(rng));
+    let extra_deps: Vec<packed::CellDep> = cfg
+        .output_type_script
+        .as_ref()
+        .map(|type_script| type_script.cell_deps.iter().map(Pack::pack).collect())
+        .unwrap_or_default();
+    let cell_deps = inputs_info
+        .iter()
+        .try_fold(Vec::new(), |mut ids, input| {
+            accounts.get(&input.cell_info.lock_hash).map(|account| {
                 ids.push(account.id);
                 ids
             })
@@ -308,85 +2098,1387 @@ fn construct_raw_transaction(
         })
         .ok_or_else(|| Error::runtime("a lock script doesn't set"))?
         .into_iter()
-        .try_fold(Vec::new(), |mut cell_deps, id| {
-            lock_deps_dict.get(&id).map(|ref cds| {
+        .try_fold(extra_deps, |mut cell_deps, id| {
+            lock_deps_dict.get(&id).map(|cds| {
                 cell_deps.extend_from_slice(&cds[..]);
                 cell_deps
             })
         })
         .map(|mut cell_deps| {
-            cell_deps.sort_by(|x, y| x.as_slice().partial_cmp(y.as_slice()).unwrap());
-            cell_deps.dedup();
+            sort_cell_deps(&mut cell_deps);
             cell_deps
         })
         .ok_or_else(|| Error::runtime("a lock script doesn't have cell deps"))?;
-    let outputs = {
-        let mut tmp_cap = inputs_cap.as_u64();
-        let output_shannons = u64::from(output_bytes) * BYTE_SHANNONS;
-        let outputs_count =
-            calculate_outputs_count(outputs_limit, tmp_cap, output_shannons, fee_shannons);
-        let tmp_output_cap = core::Capacity::shannons(output_shannons).pack();
-        let tmp_output = packed::CellOutput::new_builder()
-            .capacity(tmp_output_cap)
+    let build = |outputs: Vec<packed::CellOutput>, outputs_data: Vec<bytes::Bytes>| {
+        let mut cell_deps = cell_deps.clone();
+        // The deposit could be skipped when the outputs are constructed.
+        if let Some(dao) = dao_deposit {
+            let dao_type = Some(dao.type_script());
+            if outputs
+                .iter()
+                .any(|output| output.type_().to_opt() == dao_type)
+            {
+                cell_deps.extend(dao.script.cell_deps.iter().map(Pack::pack));
+                sort_cell_deps(&mut cell_deps);
+            }
+        }
+        packed::RawTransaction::new_builder()
+            .inputs(inputs.clone().pack())
+            .cell_deps(cell_deps.pack())
+            .outputs_data(outputs_data.pack())
+            .outputs(outputs.pack())
+            .build()
+    };
+    let total_fee = |base_fee: u64| {
+        base_fee
+            .checked_mul(fee_multiplier)
+            .and_then(|fee| {
+                fee.checked_add(cfg.tx_fee_jitter.map_or(0, |max| rng.gen_between(0, max)))
+            })
+            .ok_or_else(|| capacity_out_of_range("fee"))
+    };
+    let acp_returns = acp_returns(inputs_info, accounts)?;
+    let acp_shannons = acp_returns
+        .iter()
+        .try_fold(0u64, |total, (_, capacity)| total.checked_add(*capacity))
+        .ok_or_else(|| capacity_out_of_range("the returns of the anyone-can-pay inputs"))?;
+    let inputs_shannons = inputs_info
+        .iter()
+        .map(|input| u128::from(input.cell_info.capacity.as_u64()))
+        .sum::<u128>();
+    if inputs_shannons < u128::from(acp_shannons) {
+        log::trace!("the inputs are not enough to return the anyone-can-pay inputs");
+        return Ok(None);
+    }
+    let (fee_shannons, mut outputs, mut outputs_data) = match cfg.fee_policy()? {
+        FeePolicy::Fixed(fee) => {
+            let fee_shannons = total_fee(fee)?;
+            let reserved_shannons = fee_shannons
+                .checked_add(acp_shannons)
+                .ok_or_else(|| capacity_out_of_range("fee"))?;
+            if inputs_shannons < u128::from(reserved_shannons) {
+                log::trace!("the inputs are not enough to pay the fee");
+                return Ok(None);
+            }
+            match construct_outputs(
+                inputs_info,
+                accounts,
+                lock_generator,
+                cfg,
+                rng,
+                dao_deposit,
+                reserved_shannons,
+            )? {
+                Some((outputs, outputs_data)) => (fee_shannons, outputs, outputs_data),
+                None => return Ok(None),
+            }
+        }
+        FeePolicy::Rate(rate) => {
+            // The size depends on the outputs, which depend on the fee, so construct the outputs
+            // without the fee, then take the fee from the change output, which doesn't change
+            // the size.
+            let (mut outputs, mut outputs_data) = match construct_outputs(
+                inputs_info,
+                accounts,
+                lock_generator,
+                cfg,
+                rng,
+                dao_deposit,
+                acp_shannons,
+            )? {
+                Some(constructed) => constructed,
+                None => return Ok(None),
+            };
+            let draft = {
+                let mut outputs = outputs.clone();
+                let mut outputs_data = outputs_data.clone();
+                return_acp_inputs(&mut outputs, &mut outputs_data, &acp_returns)?;
+                build(outputs, outputs_data)
+            };
+            let size = estimate_tx_size(draft, inputs_info, accounts, cfg.witness_padding);
+            let fee_shannons = total_fee(calculate_fee(size, rate)?)?;
+            if !take_fee_from_change(
+                &mut outputs,
+                &mut outputs_data,
+                fee_shannons,
+                cfg.change_minimum,
+            )? {
+                return Ok(None);
+            }
+            (fee_shannons, outputs, outputs_data)
+        }
+    };
+    log::trace!("the fee of the transaction is {} shannons", fee_shannons);
+    return_acp_inputs(&mut outputs, &mut outputs_data, &acp_returns)?;
+    place_change_output(cfg.capacity_split, &mut outputs, &mut outputs_data);
+    let raw = build(outputs, outputs_data);
+    check_capacity_balance(inputs_info, &raw, fee_shannons)?;
+    check_acp_returns(&raw, &acp_returns)?;
+    Ok(Some(raw))
+}
+
+// The anyone-can-pay inputs without signatures are unlocked only if an output with the same lock
+// receives their capacity plus the minimum transfer amount, returns the lock scripts and the
+// capacities to return.
+//
+// The minimum transfer amount is 10^min_ckb shannons, or 1 shannon if "min_ckb" is not set.
+fn acp_returns(
+    inputs_info: &[InputInfo],
+    accounts: &HashMap<H256, LockInfo>,
+) -> Result<Vec<(packed::Script, u64)>> {
+    let mut returns: Vec<(&H256, packed::Script, u64)> = Vec::new();
+    for input in inputs_info {
+        let hash = &input.cell_info.lock_hash;
+        let lock_info = &accounts[hash];
+        if !lock_info.is_unsigned() {
+            continue;
+        }
+        let capacity = input.cell_info.capacity.as_u64();
+        if let Some(item) = returns.iter_mut().find(|item| item.0 == hash) {
+            item.2 = item
+                .2
+                .checked_add(capacity)
+                .ok_or_else(|| capacity_out_of_range("anyone-can-pay inputs capacity"))?;
+        } else {
+            let min_ckb = lock_info
+                .acp
+                .as_ref()
+                .and_then(|acp| acp.min_ckb)
+                .unwrap_or(0);
+            let min_shannons = 10u64
+                .checked_pow(u32::from(min_ckb))
+                .ok_or_else(|| capacity_out_of_range("the minimum transfer amount"))?;
+            let capacity = capacity
+                .checked_add(min_shannons)
+                .ok_or_else(|| capacity_out_of_range("anyone-can-pay inputs capacity"))?;
+            returns.push((hash, lock_info.script.clone(), capacity));
+        }
+    }
+    Ok(returns
+        .into_iter()
+        .map(|(_, script, capacity)| (script, capacity))
+        .collect())
+}
+
+// Return the capacities to the anyone-can-pay locks, the lock could only be used by one output.
+//
+// The capacity is added to the first output with the same lock, and the other outputs with the
+// same lock are merged into it; if there is no such output, a new output is appended.
+fn return_acp_inputs(
+    outputs: &mut Vec<packed::CellOutput>,
+    outputs_data: &mut Vec<bytes::Bytes>,
+    acp_returns: &[(packed::Script, u64)],
+) -> Result<()> {
+    for (lock, return_shannons) in acp_returns {
+        let mut capacity = *return_shannons;
+        let mut target = None;
+        let mut index = 0;
+        while index < outputs.len() {
+            if &outputs[index].lock() != lock {
+                index += 1;
+                continue;
+            }
+            let output_shannons: u64 = outputs[index].capacity().unpack();
+            capacity = capacity
+                .checked_add(output_shannons)
+                .ok_or_else(|| capacity_out_of_range("anyone-can-pay output capacity"))?;
+            if target.is_none() {
+                target = Some(index);
+                index += 1;
+            } else {
+                outputs.remove(index);
+                outputs_data.remove(index);
+            }
+        }
+        match target {
+            Some(index) => {
+                outputs[index] = outputs[index]
+                    .clone()
+                    .as_builder()
+                    .capacity(capacity.pack())
+                    .build();
+            }
+            None => {
+                let output = packed::CellOutput::new_builder()
+                    .capacity(capacity.pack())
+                    .lock(lock.clone())
+                    .build();
+                outputs.push(output);
+                outputs_data.push(bytes::Bytes::new());
+            }
+        }
+    }
+    Ok(())
+}
+
+// Each anyone-can-pay lock without signatures should have only one output, which receives the
+// capacity of its inputs plus the minimum transfer amount.
+fn check_acp_returns(
+    raw: &packed::RawTransaction,
+    acp_returns: &[(packed::Script, u64)],
+) -> Result<()> {
+    for (lock, return_shannons) in acp_returns {
+        let capacities = raw
+            .outputs()
+            .into_iter()
+            .filter(|output| &output.lock() == lock)
+            .map(|output| Unpack::<u64>::unpack(&output.capacity()))
+            .collect::<Vec<_>>();
+        match capacities[..] {
+            [capacity] if capacity >= *return_shannons => {}
+            _ => {
+                let errmsg = format!(
+                    "the anyone-can-pay lock {:#x} should have one output with at least {} \
+                    shannons, but the outputs are {:?}",
+                    lock.calc_script_hash(),
+                    return_shannons,
+                    capacities
+                );
+                return Err(Error::runtime(errmsg));
+            }
+        }
+    }
+    Ok(())
+}
+
+// The change output is the first one when the outputs are constructed, move it to where the
+// split policy puts the remainder.
+fn place_change_output(
+    split: CapacitySplit,
+    outputs: &mut [packed::CellOutput],
+    outputs_data: &mut [bytes::Bytes],
+) {
+    match split {
+        CapacitySplit::ChangeFirst => {}
+        CapacitySplit::EvenWithRemainderLast => {
+            outputs.rotate_left(1);
+            outputs_data.rotate_left(1);
+        }
+    }
+}
+
+fn sort_cell_deps(cell_deps: &mut Vec<packed::CellDep>) {
+    cell_deps.sort_by(|x, y| x.as_slice().partial_cmp(y.as_slice()).unwrap());
+    cell_deps.dedup();
+}
+
+// The outputs and the fee should spend the inputs exactly, otherwise the transaction is
+// constructed wrongly, and the node rejects it anyway.
+fn check_capacity_balance(
+    inputs_info: &[InputInfo],
+    raw: &packed::RawTransaction,
+    fee_shannons: u64,
+) -> Result<()> {
+    let inputs_shannons = inputs_info
+        .iter()
+        .map(|input| u128::from(input.cell_info.capacity.as_u64()))
+        .sum::<u128>();
+    let outputs_shannons = raw
+        .outputs()
+        .into_iter()
+        .map(|output| u128::from(Unpack::<u64>::unpack(&output.capacity())))
+        .sum::<u128>();
+    let spent_shannons = outputs_shannons + u128::from(fee_shannons);
+    if spent_shannons != inputs_shannons {
+        let errmsg = format!(
+            "the outputs ({} shannons) and the fee ({} shannons) don't balance the inputs \
+            ({} shannons), the discrepancy is {} shannons",
+            outputs_shannons,
+            fee_shannons,
+            inputs_shannons,
+            spent_shannons as i128 - inputs_shannons as i128
+        );
+        return Err(Error::runtime(errmsg));
+    }
+    Ok(())
+}
+
+fn construct_outputs(
+    inputs_info: &[InputInfo],
+    accounts: &HashMap<H256, LockInfo>,
+    lock_generator: &LockGenerator,
+    cfg: &GeneratorConfig,
+    rng: &SimRng,
+    dao_deposit: Option<&DaoDepositConfig>,
+    reserved_shannons: u64,
+) -> Result<Option<(Vec<packed::CellOutput>, Vec<bytes::Bytes>)>> {
+    let inputs_cap = inputs_info
+        .iter()
+        .try_fold(core::Capacity::zero(), |total, next| {
+            total.safe_add(next.cell_info.capacity)
+        })
+        .map_err(Error::runtime)?;
+    let mut outputs = {
+        let total_shannons = inputs_cap
+            .as_u64()
+            .checked_sub(reserved_shannons)
+            .ok_or_else(|| capacity_out_of_range("inputs capacity minus the fee"))?;
+        let (outputs_count, mut capacities) = match cfg.mode {
+            GeneratorMode::Split => {
+                let (min, max) = cfg.output_capacity_range()?;
+                let range_shannons = (
+                    u64::from(min) * BYTE_SHANNONS,
+                    u64::from(max) * BYTE_SHANNONS,
+                );
+                let drawn_capacities =
+                    draw_output_capacities(cfg.outputs_limit, total_shannons, range_shannons, rng);
+                let outputs_count = drawn_capacities.len() + 1;
+                let capacities =
+                    split_capacity(cfg.capacity_split, total_shannons, drawn_capacities)?;
+                (outputs_count, capacities)
+            }
+            // Only the change output.
+            GeneratorMode::Consolidate => (1, vec![total_shannons]),
+        };
+        if let Some(ref jitter) = cfg.output_capacity_jitter {
+            let min_shannons = cfg.output_min_bytes() * BYTE_SHANNONS;
+            jitter_capacities(&mut capacities, jitter, min_shannons, rng)?;
+        }
+        let change_lock = match cfg.change_lock {
+            ChangeLock::SameAsFirstInput => {
+                accounts[&inputs_info[0].cell_info.lock_hash].script.clone()
+            }
+            ChangeLock::Sampled => lock_generator.generate(),
+            ChangeLock::Fixed(ref script) => script.clone().into(),
+        };
+        let mut locks = (2..=outputs_count)
+            .map(|_| lock_generator.generate())
+            .collect::<Vec<_>>();
+        locks.sort_by_key(|lock| lock.as_slice().to_vec());
+        let type_script = cfg
+            .output_type_script
+            .as_ref()
+            .map(OutputTypeScript::script);
+        iter::once(change_lock)
+            .chain(locks)
+            .zip(capacities)
+            .map(|(lock, capacity)| {
+                packed::CellOutput::new_builder()
+                    .capacity(core::Capacity::shannons(capacity).pack())
+                    .lock(lock)
+                    .type_(type_script.clone().pack())
+                    .build()
+            })
+            .collect::<Vec<_>>()
+    };
+    let output_data = bytes::Bytes::from(vec![0u8; cfg.output_data_size.unwrap_or(0) as usize]);
+    let mut outputs_data = vec![output_data; outputs.len()];
+    if !ensure_change_minimum(&mut outputs, &mut outputs_data, cfg.change_minimum)? {
+        return Ok(None);
+    }
+    if let Some(dao) = dao_deposit {
+        let dao_output = outputs[0]
+            .clone()
+            .as_builder()
+            .type_(Some(dao.type_script()).pack())
             .build();
-        let mut outputs = vec![tmp_output; outputs_count];
-        tmp_cap -= fee_shannons;
-        tmp_cap -= output_shannons * (outputs_count as u64 - 1);
-        outputs[0] = outputs[0]
+        let dao_data = bytes::Bytes::from(vec![0u8; 8]);
+        let occupied = dao_output
+            .occupied_capacity(core::Capacity::bytes(dao_data.len()).map_err(Error::runtime)?)
+            .map_err(Error::runtime)?;
+        let capacity: core::Capacity = dao_output.capacity().unpack();
+        let data_allowed = cfg
+            .max_total_output_data_bytes
+            .map(|max| dao_data.len() <= max)
+            .unwrap_or(true);
+        if !data_allowed {
+            log::trace!("skip the DAO deposit since the outputs data is limited");
+        } else if capacity >= occupied {
+            outputs[0] = dao_output;
+            outputs_data[0] = dao_data;
+        } else {
+            log::trace!("skip the DAO deposit since the capacity is not enough");
+        }
+    }
+    if let Some(max_bytes) = cfg.max_total_output_data_bytes {
+        limit_outputs_data(&mut outputs, &mut outputs_data, max_bytes)?;
+    }
+    Ok(Some((outputs, outputs_data)))
+}
+
+fn calculate_fee(tx_size: usize, fee_rate: u64) -> Result<u64> {
+    let fee = (tx_size as u64)
+        .checked_mul(fee_rate)
+        .ok_or_else(|| capacity_out_of_range("fee"))?;
+    // Round up, so the fee rate is never less than the configured one.
+    Ok(fee.div_ceil(1000))
+}
+
+// Estimate the size of the transaction in a block after signed, the witnesses are filled with
+// placeholders as same size as the real ones.
+fn estimate_tx_size(
+    raw_tx: packed::RawTransaction,
+    inputs_info: &[InputInfo],
+    accounts: &HashMap<H256, LockInfo>,
+    witness_padding: WitnessPadding,
+) -> usize {
+    let mut lock_hashes = inputs_info
+        .iter()
+        .map(|input| &input.cell_info.lock_hash)
+        .collect::<Vec<_>>();
+    lock_hashes.sort();
+    lock_hashes.dedup();
+    let witnesses_count = match witness_padding {
+        WitnessPadding::None => lock_hashes.len(),
+        WitnessPadding::MatchInputs => inputs_info.len(),
+    };
+    let mut witnesses = vec![bytes::Bytes::new().pack(); witnesses_count];
+    for (witness, hash) in witnesses.iter_mut().zip(lock_hashes) {
+        if accounts[hash].is_unsigned() {
+            continue;
+        }
+        *witness = blank_witness(accounts[hash].lock_size());
+    }
+    packed::Transaction::new_builder()
+        .raw(raw_tx)
+        .witnesses(witnesses.pack())
+        .build()
+        .serialized_size_in_block()
+}
+
+// Returns false if the change output is still less than its occupied capacity.
+//
+// The other outputs which are less than their occupied capacities are merged into the change
+// output first, since more inputs only make the change output larger.
+fn ensure_change_minimum(
+    outputs: &mut Vec<packed::CellOutput>,
+    outputs_data: &mut Vec<bytes::Bytes>,
+    policy: ChangeMinimum,
+) -> Result<bool> {
+    let lacks = |outputs: &[packed::CellOutput],
+                 outputs_data: &[bytes::Bytes],
+                 index: usize|
+     -> Result<bool> {
+        let data_capacity =
+            core::Capacity::bytes(outputs_data[index].len()).map_err(Error::runtime)?;
+        let occupied = outputs[index]
+            .occupied_capacity(data_capacity)
+            .map_err(Error::runtime)?;
+        let capacity: core::Capacity = outputs[index].capacity().unpack();
+        Ok(capacity < occupied)
+    };
+    let merge_into = |outputs: &mut Vec<packed::CellOutput>,
+                      outputs_data: &mut Vec<bytes::Bytes>,
+                      from: usize,
+                      to: usize| {
+        outputs_data.remove(from);
+        let removed = outputs.remove(from);
+        let removed_cap: core::Capacity = removed.capacity().unpack();
+        let target_cap: core::Capacity = outputs[to].capacity().unpack();
+        let capacity = target_cap.safe_add(removed_cap).map_err(Error::runtime)?;
+        outputs[to] = outputs[to]
             .clone()
             .as_builder()
-            .capacity((core::Capacity::shannons(tmp_cap)).pack())
+            .capacity(capacity.pack())
             .build();
-        let locks = (1..=outputs.len())
-            .map(|_| lock_generator.generate())
-            .map(|hash| &accounts[&hash]);
-        for (index, lock) in locks.into_iter().enumerate() {
-            outputs[index] = outputs[index]
-                .clone()
-                .as_builder()
-                .lock(lock.script.to_owned())
-                .build();
-        }
-        (&mut outputs[1..]).sort_by_key(|output| output.lock().as_slice().to_vec());
-        outputs
+        Ok::<_, Error>(())
+    };
+    let mut index = 1;
+    while index < outputs.len() {
+        if lacks(outputs, outputs_data, index)? {
+            log::debug!("the output#{} is less than its occupied capacity", index);
+            merge_into(outputs, outputs_data, index, 0)?;
+        } else {
+            index += 1;
+        }
+    }
+    if !lacks(outputs, outputs_data, 0)? {
+        return Ok(true);
+    }
+    log::debug!(
+        "the change output is less than its occupied capacity, {:?}",
+        policy
+    );
+    match policy {
+        ChangeMinimum::MergeIntoOutput => {
+            if outputs.len() > 1 {
+                merge_into(outputs, outputs_data, 0, 0)?;
+            }
+        }
+        ChangeMinimum::DropOutput => {
+            while outputs.len() > 1 && lacks(outputs, outputs_data, 0)? {
+                let last = outputs.len() - 1;
+                merge_into(outputs, outputs_data, last, 0)?;
+            }
+        }
+        ChangeMinimum::AddInput => {}
+    }
+    lacks(outputs, outputs_data, 0).map(|lacks| !lacks)
+}
+
+// Take the fee from the change output (the first one), then ensure it is still not less than
+// its occupied capacity.
+//
+// Returns false if the change output is not enough.
+fn take_fee_from_change(
+    outputs: &mut Vec<packed::CellOutput>,
+    outputs_data: &mut Vec<bytes::Bytes>,
+    fee_shannons: u64,
+    policy: ChangeMinimum,
+) -> Result<bool> {
+    let change: u64 = outputs[0].capacity().unpack();
+    let change = match change.checked_sub(fee_shannons) {
+        Some(change) => change,
+        None => return Ok(false),
     };
-    let raw = packed::RawTransaction::new_builder()
-        .inputs(inputs.pack())
-        .cell_deps(cell_deps.pack())
-        .outputs_data(vec![(&[]).pack(); outputs.len()].pack())
-        .outputs(outputs.pack())
+    outputs[0] = outputs[0]
+        .clone()
+        .as_builder()
+        .capacity(change.pack())
         .build();
-    Ok(raw)
+    ensure_change_minimum(outputs, outputs_data, policy)
+}
+
+fn limit_outputs_data(
+    outputs: &mut Vec<packed::CellOutput>,
+    outputs_data: &mut Vec<bytes::Bytes>,
+    max_bytes: usize,
+) -> Result<()> {
+    let mut total_bytes: usize = outputs_data.iter().map(bytes::Bytes::len).sum();
+    if total_bytes <= max_bytes {
+        return Ok(());
+    }
+    log::debug!(
+        "the outputs data has {} bytes in total, exceeds the limit {} bytes",
+        total_bytes,
+        max_bytes
+    );
+    while total_bytes > max_bytes {
+        // Drop the last output which has data, and give its capacity back to the change output.
+        let index = outputs_data
+            .iter()
+            .rposition(|data| !data.is_empty())
+            .filter(|index| *index > 0)
+            .ok_or_else(|| {
+                let errmsg = format!(
+                    "the data of the change output is larger than the limit {} bytes",
+                    max_bytes
+                );
+                Error::runtime(errmsg)
+            })?;
+        let output = outputs.remove(index);
+        let data = outputs_data.remove(index);
+        total_bytes -= data.len();
+        let change_cap: core::Capacity = outputs[0].capacity().unpack();
+        let output_cap: core::Capacity = output.capacity().unpack();
+        let change_cap = change_cap.safe_add(output_cap).map_err(Error::runtime)?;
+        outputs[0] = outputs[0]
+            .clone()
+            .as_builder()
+            .capacity(change_cap.pack())
+            .build();
+    }
+    log::debug!(
+        "the outputs are reduced to {} to limit the outputs data to {} bytes",
+        outputs.len(),
+        total_bytes
+    );
+    Ok(())
+}
+
+// The placeholder of a witness, the lock is filled with zeros as same size as the signed one.
+fn blank_witness(lock_size: usize) -> packed::Bytes {
+    let blank_lock = bytes::Bytes::from(vec![0u8; lock_size]);
+    packed::WitnessArgs::new_builder()
+        .lock(Some(blank_lock).pack())
+        .build()
+        .as_bytes()
+        .pack()
 }
 
 fn sign_transaction(
     raw_tx: packed::RawTransaction,
+    inputs_info: &[InputInfo],
     lock_hashes: &[H256],
     accounts: &HashMap<H256, LockInfo>,
+    witness_padding: WitnessPadding,
+    parallel_sign_threshold: usize,
 ) -> Result<packed::Transaction> {
-    let blank_signature = bytes::Bytes::from(vec![0u8; 65]);
-    let witness_blank = packed::WitnessArgs::new_builder()
-        .lock(Some(blank_signature).pack())
-        .build();
+    let witnesses_count = match witness_padding {
+        WitnessPadding::None => lock_hashes.len(),
+        WitnessPadding::MatchInputs => inputs_info.len(),
+    };
+    let mut witnesses = vec![bytes::Bytes::new().pack(); witnesses_count];
+    for (witness, hash) in witnesses.iter_mut().zip(lock_hashes) {
+        *witness = blank_witness(accounts[hash].lock_size());
+    }
     let tx = packed::Transaction::new_builder()
         .raw(raw_tx)
-        .witnesses(vec![witness_blank.as_bytes().pack(); lock_hashes.len()].pack())
+        .witnesses(witnesses.clone().pack())
         .build();
 
     let tx_hash = tx.calc_tx_hash();
-    let witnesses = lock_hashes
-        .iter()
-        .map(|hash| {
-            let lock_info = &accounts[&hash];
-            let signature = lock_info.sign(&tx_hash.raw_data())?;
-            let witness_args = packed::WitnessArgs::new_builder()
-                .lock(Some(bytes::Bytes::from(signature)).pack())
-                .build()
-                .as_bytes()
-                .pack();
-            Ok(witness_args)
-        })
-        .collect::<Result<Vec<_>>>()?;
+    let sign_group = |hash: &H256| -> Result<packed::Bytes> {
+        // The padding witnesses of the other inputs in the same group are signed, too.
+        let extra_witnesses = if witness_padding == WitnessPadding::MatchInputs {
+            let count = inputs_info
+                .iter()
+                .filter(|input| &input.cell_info.lock_hash == hash)
+                .count();
+            vec![bytes::Bytes::new(); count - 1]
+        } else {
+            Vec::new()
+        };
+        let lock_info = &accounts[hash];
+        if lock_info.is_unsigned() {
+            return Ok(bytes::Bytes::new().pack());
+        }
+        let signature = lock_info.sign(&tx_hash.raw_data(), &extra_witnesses)?;
+        let witness = packed::WitnessArgs::new_builder()
+            .lock(Some(bytes::Bytes::from(signature)).pack())
+            .build()
+            .as_bytes()
+            .pack();
+        Ok(witness)
+    };
+    let signed_witnesses = if lock_hashes.len() > parallel_sign_threshold {
+        lock_hashes
+            .par_iter()
+            .map(sign_group)
+            .collect::<Result<Vec<_>>>()?
+    } else {
+        lock_hashes
+            .iter()
+            .map(sign_group)
+            .collect::<Result<Vec<_>>>()?
+    };
+    for (index, witness) in signed_witnesses.into_iter().enumerate() {
+        witnesses[index] = witness;
+    }
     let stx = tx.as_builder().witnesses(witnesses.pack()).build();
     Ok(stx)
 }
+
+// Recover the signers from the witnesses, and check them with the lock scripts of the inputs.
+fn verify_signatures(
+    tx: &packed::Transaction,
+    inputs_info: &[InputInfo],
+    lock_hashes: &[H256],
+    accounts: &HashMap<H256, LockInfo>,
+) -> Result<()> {
+    let tx_hash = tx.calc_tx_hash();
+    let witnesses = tx.witnesses();
+    for (index, hash) in lock_hashes.iter().enumerate() {
+        if accounts[hash].is_unsigned() {
+            continue;
+        }
+        let lock = witnesses
+            .get(index)
+            .and_then(|witness| packed::WitnessArgs::from_slice(&witness.raw_data()).ok())
+            .and_then(|witness_args| witness_args.lock().to_opt())
+            .ok_or_else(|| {
+                let errmsg = format!("the witness of lock {:#x} has no lock", hash);
+                Error::crypto(errmsg)
+            })?;
+        // The witnesses of the other inputs in the same group.
+        let extra_witnesses = inputs_info
+            .iter()
+            .enumerate()
+            .filter(|(i, input)| *i != index && &input.cell_info.lock_hash == hash)
+            .filter_map(|(i, _)| witnesses.get(i).map(|witness| witness.raw_data()))
+            .collect::<Vec<_>>();
+        accounts[hash]
+            .verify(&tx_hash.raw_data(), &extra_witnesses, &lock.raw_data())
+            .map_err(|err| {
+                let errmsg = format!(
+                    "failed to verify the signature of lock {:#x}: {}",
+                    hash, err
+                );
+                Error::crypto(errmsg)
+            })?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr as _;
+
+    use super::*;
+    use crate::types::MetaData;
+
+    const METADATA: &str = r#"
+start_block:
+  number: 0
+  hash: '0x0000000000000000000000000000000000000000000000000000000000000000'
+lock_scripts:
+  secp256k1_blake160:
+    code_hash: '0x9bd7e06f3ecf4be0f2fcd2188b23f1b9fcc88e5d4b65a8637b17723bbda3cce8'
+    hash_type: 'type'
+    cell_deps:
+    -
+      out_point:
+        tx_hash: '0xf8de3bb47d055cdf460d93a2a6e1b05f7432f9777c8c474abf4eec1d4aee5d37'
+        index: 0
+      dep_type: 'dep_group'
+  secp256k1_multisig:
+    code_hash: '0x5c5069eb0857efc65e1bca0c07df34c31663b3622fd3876c876320fc9634e2a8'
+    hash_type: 'type'
+    cell_deps:
+    -
+      out_point:
+        tx_hash: '0xf8de3bb47d055cdf460d93a2a6e1b05f7432f9777c8c474abf4eec1d4aee5d37'
+        index: 1
+      dep_type: 'dep_group'
+accounts:
+-
+  secret_key: '0x1111111111111111111111111111111111111111111111111111111111111111'
+  lock_id: 'secp256k1_blake160'
+-
+  secret_key: '0x2222222222222222222222222222222222222222222222222222222222222222'
+  lock_id: 'secp256k1_multisig'
+  multisig:
+    threshold: 2
+    other_secret_keys:
+    - '0x3333333333333333333333333333333333333333333333333333333333333333'
+"#;
+
+    struct Env {
+        metadata: MetaData,
+        accounts: HashMap<H256, LockInfo>,
+        cfg: RunEnv,
+        rng: SimRng,
+    }
+
+    impl Env {
+        fn new() -> Self {
+            Self::from_metadata(METADATA)
+        }
+
+        fn from_metadata(metadata: &str) -> Self {
+            let metadata = MetaData::from_str(metadata).unwrap();
+            let accounts = metadata.accounts().unwrap();
+            let mut cfg = run_env();
+            cfg.generator
+                .locks_weights
+                .insert(LockScriptId::Secp256K1Blake160, 1);
+            Self {
+                metadata,
+                accounts,
+                cfg,
+                rng: SimRng::new(Some(1)),
+            }
+        }
+
+        fn lock_hash(&self, id: LockScriptId) -> H256 {
+            self.accounts
+                .iter()
+                .find(|(_, lock_info)| lock_info.id == id)
+                .map(|(hash, _)| hash.clone())
+                .unwrap()
+        }
+
+        fn construct(&self, inputs: &[InputInfo]) -> Result<Option<packed::RawTransaction>> {
+            let generator = &self.cfg.generator;
+            let lock_generator = generator.lock_generator(&self.accounts, &self.rng)?;
+            construct_raw_transaction(
+                inputs,
+                &self.accounts,
+                &lock_generator,
+                &self.metadata.lock_deps_dict(),
+                generator,
+                &self.rng,
+                1,
+            )
+        }
+    }
+
+    impl Env {
+        fn sign(
+            &self,
+            mut total_inputs: Vec<InputInfo>,
+        ) -> (packed::Transaction, Vec<H256>, Vec<InputInfo>) {
+            let (lock_hashes, inputs) = prepare_inputs(&mut total_inputs);
+            let raw = self.construct(&inputs).unwrap().unwrap();
+            let generator = &self.cfg.generator;
+            let tx = sign_transaction(
+                raw,
+                &inputs,
+                &lock_hashes,
+                &self.accounts,
+                generator.witness_padding,
+                generator
+                    .parallel_sign_threshold
+                    .unwrap_or(DEFAULT_PARALLEL_SIGN_THRESHOLD),
+            )
+            .unwrap();
+            (tx, lock_hashes, inputs)
+        }
+    }
+
+    fn run_env() -> RunEnv {
+        RunEnv::from_profile("light", "").unwrap()
+    }
+
+    fn inputs(lock_hash: &H256, tx_seed: u8, capacities: &[u64]) -> Vec<InputInfo> {
+        capacities
+            .iter()
+            .enumerate()
+            .map(|(index, capacity)| {
+                let out_point = packed::OutPoint::new_builder()
+                    .tx_hash(H256([tx_seed; 32]).pack())
+                    .index((index as u32).pack())
+                    .build();
+                let cell_info =
+                    CellInfo::new(core::Capacity::shannons(*capacity), lock_hash.clone(), 0);
+                InputInfo::new(out_point, cell_info)
+            })
+            .collect()
+    }
+
+    fn outputs_shannons(raw: &packed::RawTransaction) -> Vec<u64> {
+        raw.outputs()
+            .into_iter()
+            .map(|output| output.capacity().unpack())
+            .collect()
+    }
+
+    fn witness_lock(tx: &packed::Transaction, index: usize) -> Option<bytes::Bytes> {
+        let witness = tx.witnesses().get(index).unwrap();
+        packed::WitnessArgs::from_slice(&witness.raw_data())
+            .ok()
+            .and_then(|witness_args| witness_args.lock().to_opt())
+            .map(|lock| lock.raw_data())
+    }
+
+    fn offline_client() -> FailoverClient {
+        let url = url::Url::parse("http://127.0.0.1:1").unwrap();
+        FailoverClient::new(&[url], time::Duration::from_secs(1), 0).unwrap()
+    }
+
+    // The tip is pushed, so it is never requested from the node.
+    fn pushed_tip(number: core::BlockNumber) -> (mpsc::Sender<Result<rpc::HeaderView>>, TipCache) {
+        let (sender, subscription) = mpsc::channel();
+        let tip_cache = TipCache {
+            number,
+            fetched_at: Some(time::Instant::now()),
+            subscription: Some(subscription),
+        };
+        (sender, tip_cache)
+    }
+
+    #[test]
+    fn delayed_target_when_tip_is_lower_than_delay_blocks() {
+        assert_eq!(delayed_target(0, 5, 30), None);
+        assert_eq!(delayed_target(0, 30, 30), None);
+        assert_eq!(delayed_target(0, 31, 30), Some(1));
+        assert_eq!(delayed_target(1, 31, 30), None);
+        assert_eq!(delayed_target(0, 0, u64::MAX), None);
+    }
+
+    #[test]
+    fn synchronize_one_block_per_round() {
+        let (mut next_num, tip_num) = (10, 20);
+        let mut rounds = 0;
+        loop {
+            let target_num = delayed_target(next_num, tip_num, 0).unwrap_or(next_num);
+            let skip_sync = target_num == next_num;
+            if skip_sync {
+                break;
+            }
+            // The node is still ahead, so the round is not skipped, but only one block is
+            // synchronized.
+            let end_num = sync_end_number(next_num, target_num, Some(1));
+            assert_eq!(end_num, next_num);
+            next_num = end_num + 1;
+            rounds += 1;
+        }
+        assert_eq!((next_num, rounds), (20, 10));
+        assert_eq!(sync_end_number(0, 5, Some(0)), 0);
+        assert_eq!(sync_end_number(u64::MAX - 1, u64::MAX, Some(5)), u64::MAX);
+        assert_eq!(sync_end_number(0, 5, Some(100)), 5);
+        assert_eq!(sync_end_number(0, 5, None), 5);
+    }
+
+    #[test]
+    fn synchronize_when_tip_is_lower_than_delay_blocks() {
+        let dir = tempfile::tempdir().unwrap();
+        let stg = Storage::init(dir.path().join("data"), &Default::default()).unwrap();
+        let cli = offline_client();
+        let mut cfg = run_env();
+        cfg.delay_blocks = 30;
+        let (_sender, mut tip_cache) = pushed_tip(5);
+        let (skip_sync, stats) =
+            synchronize(&cli, &stg, &HashMap::new(), 0, &cfg, &mut tip_cache).unwrap();
+        assert!(skip_sync);
+        assert_eq!(stats.blocks, 0);
+        assert_eq!(stats.rpc_calls, 0);
+        assert_eq!(stg.get_next_number().unwrap(), None);
+    }
+
+    #[test]
+    fn capacity_balance_with_a_single_output_and_remainder() {
+        let env = Env::new();
+        let lock_hash = env.lock_hash(LockScriptId::Secp256K1Blake160);
+        // Not enough for a second output, so the only output takes all shannons but the fee.
+        let inputs = inputs(&lock_hash, 1, &[150 * BYTE_SHANNONS + 12_345]);
+        let raw = env.construct(&inputs).unwrap().unwrap();
+        let fee = env.cfg.generator.tx_fee.unwrap();
+        assert_eq!(
+            outputs_shannons(&raw),
+            vec![150 * BYTE_SHANNONS + 12_345 - fee]
+        );
+        check_capacity_balance(&inputs, &raw, fee).unwrap();
+    }
+
+    #[test]
+    fn capacity_balance_with_many_outputs() {
+        let mut env = Env::new();
+        env.cfg.generator.outputs_limit = 10;
+        let lock_hash = env.lock_hash(LockScriptId::Secp256K1Blake160);
+        let inputs = inputs(
+            &lock_hash,
+            1,
+            &[7_000 * BYTE_SHANNONS + 1, 3_000 * BYTE_SHANNONS],
+        );
+        let raw = env.construct(&inputs).unwrap().unwrap();
+        assert_eq!(raw.outputs().len(), 10);
+        let fee = env.cfg.generator.tx_fee.unwrap();
+        check_capacity_balance(&inputs, &raw, fee).unwrap();
+    }
+
+    #[test]
+    fn capacity_balance_rejects_unbalanced_transactions() {
+        let env = Env::new();
+        let lock_hash = env.lock_hash(LockScriptId::Secp256K1Blake160);
+        let inputs = inputs(&lock_hash, 1, &[1_000 * BYTE_SHANNONS]);
+        let raw = env.construct(&inputs).unwrap().unwrap();
+        let fee = env.cfg.generator.tx_fee.unwrap();
+        check_capacity_balance(&inputs, &raw, fee).unwrap();
+        // One shannon is created from nothing.
+        let mut outputs = raw.outputs().into_iter().collect::<Vec<_>>();
+        let capacity: u64 = outputs[0].capacity().unpack();
+        outputs[0] = outputs[0]
+            .clone()
+            .as_builder()
+            .capacity((capacity + 1).pack())
+            .build();
+        let unbalanced = raw.clone().as_builder().outputs(outputs.pack()).build();
+        assert!(check_capacity_balance(&inputs, &unbalanced, fee).is_err());
+        // Or the fee is not paid by the inputs.
+        assert!(check_capacity_balance(&inputs, &raw, fee * 2).is_err());
+    }
+
+    #[test]
+    fn capacities_near_u64_max() {
+        let env = Env::new();
+        let lock_hash = env.lock_hash(LockScriptId::Secp256K1Blake160);
+        let fee = env.cfg.generator.tx_fee.unwrap();
+        let max_inputs = inputs(&lock_hash, 1, &[u64::MAX]);
+        let raw = env.construct(&max_inputs).unwrap().unwrap();
+        check_capacity_balance(&max_inputs, &raw, fee).unwrap();
+        let overflowed_inputs = inputs(&lock_hash, 2, &[u64::MAX, 1]);
+        assert!(env.construct(&overflowed_inputs).is_err());
+        let rng = SimRng::new(Some(1));
+        let jitter = CapacityJitter::Shannons(100 * BYTE_SHANNONS);
+        let overflows = |capacities: &[u64]| {
+            (0..100).any(|_| {
+                let mut jittered = capacities.to_vec();
+                let result = jitter_capacities(&mut jittered, &jitter, 61 * BYTE_SHANNONS, &rng);
+                if result.is_ok() {
+                    let total = |capacities: &[u64]| {
+                        capacities.iter().copied().map(u128::from).sum::<u128>()
+                    };
+                    assert_eq!(total(&jittered), total(capacities));
+                }
+                result.is_err()
+            })
+        };
+        // The decrease of the second output overflows the first output.
+        assert!(overflows(&[u64::MAX - 1, 200 * BYTE_SHANNONS]));
+        // The jittered capacity is saturated, so the increase never overflows.
+        assert!(!overflows(&[1_000 * BYTE_SHANNONS, u64::MAX - 1]));
+    }
+
+    #[test]
+    fn parse_rejected_out_points() {
+        let lock_hash = H256([1; 32]);
+        let inputs = inputs(&lock_hash, 7, &[100 * BYTE_SHANNONS; 3]);
+        let dead = format!("{:?}", inputs[1].out_point);
+        assert!(dead.starts_with("OutPoint(0x0707"));
+        let errmsg = format!(
+            "TransactionFailedToResolve: Resolve failed Dead({}) (-301): \"Dead({})\"",
+            dead, dead
+        );
+        let err = Error::rejected(errmsg, RejectReason::DeadCell);
+        assert_eq!(
+            rejected_out_points(&err, &inputs),
+            vec![&inputs[1].out_point]
+        );
+        let err = Error::rejected("Resolve failed Unknown", RejectReason::UnknownCell);
+        assert!(rejected_out_points(&err, &inputs).is_empty());
+    }
+
+    #[test]
+    fn change_lock_modes() {
+        let mut env = Env::new();
+        let single_hash = env.lock_hash(LockScriptId::Secp256K1Blake160);
+        let multisig_hash = env.lock_hash(LockScriptId::Secp256K1Multisig);
+        // Only the single-sig account is sampled.
+        let inputs = inputs(&multisig_hash, 1, &[1_000 * BYTE_SHANNONS]);
+        let change_lock = |env: &Env| {
+            let raw = env.construct(&inputs).unwrap().unwrap();
+            raw.outputs().get(0).unwrap().lock()
+        };
+        env.cfg.generator.change_lock = ChangeLock::SameAsFirstInput;
+        assert_eq!(change_lock(&env), env.accounts[&multisig_hash].script);
+        env.cfg.generator.change_lock = ChangeLock::Sampled;
+        assert_eq!(change_lock(&env), env.accounts[&single_hash].script);
+        let fixed = packed::Script::new_builder()
+            .code_hash(H256([7; 32]).pack())
+            .args(bytes::Bytes::from(vec![8; 20]).pack())
+            .build();
+        env.cfg.generator.change_lock = ChangeLock::Fixed(fixed.clone().into());
+        assert_eq!(change_lock(&env), fixed);
+    }
+
+    #[test]
+    fn change_output_placed_by_capacity_split() {
+        let mut env = Env::new();
+        env.cfg.generator.outputs_limit = 4;
+        env.cfg.generator.capacity_split = CapacitySplit::EvenWithRemainderLast;
+        env.cfg.generator.output_capacity_jitter = Some(CapacityJitter::Percent(10));
+        let fixed = packed::Script::new_builder()
+            .code_hash(H256([7; 32]).pack())
+            .args(bytes::Bytes::from(vec![8; 20]).pack())
+            .build();
+        env.cfg.generator.change_lock = ChangeLock::Fixed(fixed.clone().into());
+        let lock_hash = env.lock_hash(LockScriptId::Secp256K1Blake160);
+        let inputs = inputs(&lock_hash, 1, &[100_000 * BYTE_SHANNONS + 3]);
+        let raw = env.construct(&inputs).unwrap().unwrap();
+        let outputs = raw.outputs().into_iter().collect::<Vec<_>>();
+        let last = outputs.len() - 1;
+        assert!(last > 0);
+        // The change output is the last one, it has the change lock and absorbs the jitter.
+        assert_eq!(outputs[last].lock(), fixed);
+        assert!(outputs[..last].iter().all(|output| output.lock() != fixed));
+        let fee = env.cfg.generator.tx_fee.unwrap();
+        check_capacity_balance(&inputs, &raw, fee).unwrap();
+        env.cfg.generator.output_capacity_jitter = None;
+        let raw = env.construct(&inputs).unwrap().unwrap();
+        let capacities = outputs_shannons(&raw);
+        let (change, others) = capacities.split_last().unwrap();
+        assert!(others.iter().all(|capacity| *capacity == others[0]));
+        let remainder = (100_000 * BYTE_SHANNONS + 3 - fee) % capacities.len() as u64;
+        assert_eq!(*change, others[0] + remainder);
+    }
+
+    #[test]
+    fn dao_deposit_cell_deps() {
+        let mut env = Env::new();
+        let lock_hash = env.lock_hash(LockScriptId::Secp256K1Blake160);
+        let inputs = inputs(&lock_hash, 1, &[1_000 * BYTE_SHANNONS]);
+        let dao: DaoDepositConfig = serde_yaml::from_str(
+            r#"
+script:
+  code_hash: '0x82d76d1b75fe2fd9a27dfbaa65a039221a380d76c926f378d3f81cf3e7e13f2e'
+  hash_type: 'type'
+  cell_deps:
+  -
+    out_point:
+      tx_hash: '0xe2fb199810d49a4d8beec56718ba2593b665db9d52299a0f9e6e75416d73ff5c'
+      index: 2
+    dep_type: 'code'
+ratio: 100
+"#,
+        )
+        .unwrap();
+        let dao_dep: packed::CellDep = dao.script.cell_deps[0].pack();
+        let dao_type = Some(dao.type_script());
+        env.cfg.generator.dao_deposit = Some(dao);
+        let raw = env.construct(&inputs).unwrap().unwrap();
+        assert_eq!(raw.outputs().get(0).unwrap().type_().to_opt(), dao_type);
+        assert!(raw.cell_deps().into_iter().any(|dep| dep == dao_dep));
+        // The deposit is skipped since its data exceeds the limit, so does the cell dep.
+        env.cfg.generator.max_total_output_data_bytes = Some(0);
+        let raw = env.construct(&inputs).unwrap().unwrap();
+        assert!(raw
+            .outputs()
+            .into_iter()
+            .all(|output| output.type_().to_opt() != dao_type));
+        assert!(raw.cell_deps().into_iter().all(|dep| dep != dao_dep));
+        assert_eq!(raw.cell_deps().len(), 1);
+    }
+
+    #[test]
+    fn change_minimum_policies() {
+        let lock = packed::Script::new_builder()
+            .args(bytes::Bytes::from(vec![0; 20]).pack())
+            .build();
+        let outputs = [50, 100, 200, 300]
+            .iter()
+            .map(|capacity| {
+                packed::CellOutput::new_builder()
+                    .capacity(core::Capacity::bytes(*capacity).unwrap().pack())
+                    .lock(lock.clone())
+                    .build()
+            })
+            .collect::<Vec<_>>();
+        let empty_data = |outputs: &[packed::CellOutput]| vec![bytes::Bytes::new(); outputs.len()];
+        let ensure = |count: usize, policy: ChangeMinimum| {
+            let mut outputs = outputs[..count].to_vec();
+            let mut outputs_data = empty_data(&outputs);
+            let ensured = ensure_change_minimum(&mut outputs, &mut outputs_data, policy).unwrap();
+            assert_eq!(outputs.len(), outputs_data.len());
+            let capacities = outputs
+                .into_iter()
+                .map(|output| Unpack::<u64>::unpack(&output.capacity()) / BYTE_SHANNONS)
+                .collect::<Vec<_>>();
+            (ensured, capacities)
+        };
+        assert_eq!(
+            ensure(4, ChangeMinimum::MergeIntoOutput),
+            (true, vec![150, 200, 300])
+        );
+        assert_eq!(
+            ensure(4, ChangeMinimum::DropOutput),
+            (true, vec![350, 100, 200])
+        );
+        assert_eq!(
+            ensure(4, ChangeMinimum::AddInput),
+            (false, vec![50, 100, 200, 300])
+        );
+        for policy in &[
+            ChangeMinimum::MergeIntoOutput,
+            ChangeMinimum::DropOutput,
+            ChangeMinimum::AddInput,
+        ] {
+            assert_eq!(ensure(1, *policy), (false, vec![50]));
+        }
+        // The other outputs are merged into the change output, whatever the policy is.
+        let mut lacking = outputs[1..].to_vec();
+        lacking.insert(2, outputs[0].clone());
+        let mut lacking_data = empty_data(&lacking);
+        assert!(
+            ensure_change_minimum(&mut lacking, &mut lacking_data, ChangeMinimum::AddInput)
+                .unwrap()
+        );
+        assert_eq!(lacking.len(), 3);
+        assert_eq!(lacking_data.len(), 3);
+        assert_eq!(
+            Unpack::<u64>::unpack(&lacking[0].capacity()),
+            150 * BYTE_SHANNONS
+        );
+        let mut lacking = vec![outputs[0].clone(), outputs[0].clone()];
+        let mut lacking_data = empty_data(&lacking);
+        assert!(
+            ensure_change_minimum(&mut lacking, &mut lacking_data, ChangeMinimum::DropOutput)
+                .unwrap()
+        );
+        assert_eq!(lacking.len(), 1);
+        // The change is enough, nothing is changed.
+        let mut enough = outputs[1..].to_vec();
+        let mut enough_data = empty_data(&enough);
+        assert!(
+            ensure_change_minimum(&mut enough, &mut enough_data, ChangeMinimum::AddInput).unwrap()
+        );
+        assert_eq!(enough.len(), 3);
+        // The data of the change output is counted.
+        enough_data[0] = bytes::Bytes::from(vec![0; 40]);
+        assert!(
+            !ensure_change_minimum(&mut enough, &mut enough_data, ChangeMinimum::AddInput).unwrap()
+        );
+    }
+
+    #[test]
+    fn fee_rate_with_drawn_outputs() {
+        let mut env = Env::new();
+        env.cfg.generator.tx_fee = None;
+        env.cfg.generator.fee_rate = Some(1_000);
+        env.cfg.generator.outputs_limit = 64;
+        env.cfg.generator.output_capacity = None;
+        env.cfg.generator.output_capacity_min = Some(61);
+        env.cfg.generator.output_capacity_max = Some(1_000);
+        let lock_hash = env.lock_hash(LockScriptId::Secp256K1Blake160);
+        for seed in 0..20 {
+            let capacities = [
+                5_000 * BYTE_SHANNONS + u64::from(seed),
+                7_000 * BYTE_SHANNONS,
+            ];
+            let (tx, _, inputs) = env.sign(inputs(&lock_hash, seed, &capacities));
+            let inputs_shannons = inputs
+                .iter()
+                .map(|input| input.cell_info.capacity.as_u64())
+                .sum::<u64>();
+            let outputs_shannons = outputs_shannons(&tx.raw()).into_iter().sum::<u64>();
+            // The fee pays the size of the signed transaction, whatever the outputs are drawn.
+            let fee = inputs_shannons - outputs_shannons;
+            assert!(tx.raw().outputs().len() > 1);
+            assert_eq!(
+                fee,
+                calculate_fee(tx.serialized_size_in_block(), 1_000).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn keep_the_cell_with_a_new_lock_when_locks_are_limited() {
+        let mut cfg = run_env();
+        cfg.generator.max_locks_per_tx = Some(1);
+        let min_shannons = min_inputs_shannons(&cfg.generator);
+        let lock_a = H256([0xa; 32]);
+        let lock_b = H256([0xb; 32]);
+        let mut cells_queue = VecDeque::new();
+        cells_queue.extend(inputs(&lock_a, 1, &[min_shannons / 2]));
+        cells_queue.extend(inputs(&lock_b, 2, &[min_shannons * 2]));
+        cells_queue.extend(inputs(&lock_a, 3, &[min_shannons - min_shannons / 2]));
+        let mut selected = Vec::new();
+        let fetch = |cells_queue: &mut VecDeque<InputInfo>, selected: &mut Vec<InputInfo>| {
+            fetch_more_inputs(cells_queue, selected, &cfg.generator, 1).unwrap()
+        };
+        let mut results = Vec::new();
+        for _ in 0..3 {
+            results.push(fetch(&mut cells_queue, &mut selected));
+        }
+        assert!(matches!(
+            results[..],
+            [
+                FetchInputsResult::Next,
+                FetchInputsResult::Next,
+                FetchInputsResult::Enough
+            ]
+        ));
+        assert_eq!(selected.len(), 2);
+        assert!(selected
+            .iter()
+            .all(|input| input.cell_info.lock_hash == lock_a));
+        // The cell with the new lock is kept for the next transaction.
+        assert_eq!(cells_queue.len(), 1);
+        assert_eq!(cells_queue[0].cell_info.lock_hash, lock_b);
+        selected.clear();
+        assert!(matches!(
+            fetch(&mut cells_queue, &mut selected),
+            FetchInputsResult::Enough
+        ));
+        assert_eq!(selected.len(), 1);
+        assert!(matches!(
+            fetch(&mut cells_queue, &mut Vec::new()),
+            FetchInputsResult::Lack
+        ));
+    }
+
+    #[test]
+    fn build_and_sign_with_a_single_account() {
+        let env = Env::new();
+        let lock_hash = env.lock_hash(LockScriptId::Secp256K1Blake160);
+        let capacities = [1_000 * BYTE_SHANNONS, 2_000 * BYTE_SHANNONS];
+        let (tx, lock_hashes, inputs) = env.sign(inputs(&lock_hash, 1, &capacities));
+        assert_eq!(lock_hashes, vec![lock_hash.clone()]);
+        // Every output shares the lock of the only account.
+        let script = &env.accounts[&lock_hash].script;
+        assert!(tx.raw().outputs().len() > 1);
+        assert!(tx
+            .raw()
+            .outputs()
+            .into_iter()
+            .all(|output| &output.lock() == script));
+        // Only one witness, its lock is a recoverable signature.
+        assert_eq!(tx.witnesses().len(), 1);
+        assert_eq!(witness_lock(&tx, 0).map(|lock| lock.len()), Some(65));
+        verify_signatures(&tx, &inputs, &lock_hashes, &env.accounts).unwrap();
+    }
+
+    #[test]
+    fn sign_with_single_sig_and_multisig_locks() {
+        let mut env = Env::new();
+        let single_hash = env.lock_hash(LockScriptId::Secp256K1Blake160);
+        let multisig_hash = env.lock_hash(LockScriptId::Secp256K1Multisig);
+        let mut total_inputs = inputs(&multisig_hash, 1, &[1_000 * BYTE_SHANNONS]);
+        total_inputs.extend(inputs(&single_hash, 2, &[2_000 * BYTE_SHANNONS; 2]));
+        total_inputs.extend(inputs(&multisig_hash, 3, &[3_000 * BYTE_SHANNONS]));
+        for padding in &[WitnessPadding::None, WitnessPadding::MatchInputs] {
+            for threshold in &[0, usize::MAX] {
+                env.cfg.generator.witness_padding = *padding;
+                env.cfg.generator.parallel_sign_threshold = Some(*threshold);
+                let (tx, lock_hashes, inputs) = env.sign(total_inputs.clone());
+                assert_eq!(lock_hashes.len(), 2);
+                // The first input of each group is at the index of its witness.
+                for (index, hash) in lock_hashes.iter().enumerate() {
+                    assert_eq!(&inputs[index].cell_info.lock_hash, hash);
+                    assert_eq!(
+                        witness_lock(&tx, index).map(|lock| lock.len()),
+                        Some(env.accounts[hash].lock_size())
+                    );
+                }
+                let witnesses_count = match padding {
+                    WitnessPadding::None => 2,
+                    WitnessPadding::MatchInputs => 4,
+                };
+                assert_eq!(tx.witnesses().len(), witnesses_count);
+                for index in 2..witnesses_count {
+                    assert!(tx.witnesses().get(index).unwrap().is_empty());
+                }
+                verify_signatures(&tx, &inputs, &lock_hashes, &env.accounts).unwrap();
+                // The witnesses are not valid for the other group.
+                let swapped = {
+                    let mut witnesses = tx.witnesses().into_iter().collect::<Vec<_>>();
+                    witnesses.swap(0, 1);
+                    tx.as_builder().witnesses(witnesses.pack()).build()
+                };
+                assert!(verify_signatures(&swapped, &inputs, &lock_hashes, &env.accounts).is_err());
+            }
+        }
+    }
+
+    #[test]
+    fn return_the_unsigned_acp_inputs() {
+        let acp_lock_script = r#"
+  anyone_can_pay:
+    code_hash: '0x3419a1c09eb2567f6552ee7a8ecffd64155cffe0f1796e6e61ec088d740c1356'
+    hash_type: 'type'
+    cell_deps:
+    -
+      out_point:
+        tx_hash: '0x4f32b3e39bd1b6350d326fdfafdfe05e5221865c3098ae323096f0bfc69e0a8c'
+        index: 0
+      dep_type: 'dep_group'
+    acp:
+      min_ckb: 8
+      unsigned: true
+accounts:
+-
+  secret_key: '0x4444444444444444444444444444444444444444444444444444444444444444'
+  lock_id: 'anyone_can_pay'"#;
+        let metadata = METADATA.replacen("\naccounts:", acp_lock_script, 1);
+        let mut env = Env::from_metadata(&metadata);
+        let single_hash = env.lock_hash(LockScriptId::Secp256K1Blake160);
+        let acp_hash = env.lock_hash(LockScriptId::AnyoneCanPay);
+        let acp_lock = env.accounts[&acp_hash].script.clone();
+        let acp_inputs = inputs(&acp_hash, 1, &[100 * BYTE_SHANNONS, 200 * BYTE_SHANNONS]);
+        let min_return = 300 * BYTE_SHANNONS + 100_000_000;
+
+        // The anyone-can-pay inputs only, the returns can't be paid.
+        let (_, only_acp) = prepare_inputs(&mut acp_inputs.clone());
+        assert!(env.construct(&only_acp).unwrap().is_none());
+
+        let mut total_inputs = acp_inputs;
+        total_inputs.extend(inputs(&single_hash, 2, &[2_000 * BYTE_SHANNONS]));
+        // The anyone-can-pay lock could be drawn for the other outputs, too.
+        env.cfg
+            .generator
+            .locks_weights
+            .insert(LockScriptId::AnyoneCanPay, 1);
+        for split in &[
+            CapacitySplit::ChangeFirst,
+            CapacitySplit::EvenWithRemainderLast,
+        ] {
+            for seed in 0..10 {
+                env.rng = SimRng::new(Some(seed));
+                env.cfg.generator.capacity_split = *split;
+                let (tx, lock_hashes, inputs) = env.sign(total_inputs.clone());
+                let acp_outputs = tx
+                    .raw()
+                    .outputs()
+                    .into_iter()
+                    .filter(|output| output.lock() == acp_lock)
+                    .map(|output| Unpack::<u64>::unpack(&output.capacity()))
+                    .collect::<Vec<_>>();
+                assert_eq!(acp_outputs.len(), 1);
+                assert!(acp_outputs[0] >= min_return);
+                let acp_index = lock_hashes.iter().position(|hash| hash == &acp_hash);
+                assert!(witness_lock(&tx, acp_index.unwrap()).is_none());
+                verify_signatures(&tx, &inputs, &lock_hashes, &env.accounts).unwrap();
+            }
+        }
+    }
+}
@@ -0,0 +1,28 @@
+use crate::error::Result;
+
+impl super::StatusConfig {
+    pub(super) fn execute(&self) -> Result<()> {
+        log::info!("Status ...");
+        let metadata = self.storage.get_metadata()?;
+        let start_block = metadata.start_block()?;
+        println!(
+            "start block: #{} ({:#x})",
+            start_block.number, start_block.hash
+        );
+        match self.storage.get_next_number()? {
+            Some(next_num) => println!("synchronized to block#{}", next_num - 1),
+            None => println!("not synchronized yet"),
+        }
+        let cells = self.storage.load_cells()?;
+        let total_capacity = cells
+            .iter()
+            .map(|cell| cell.cell_info.capacity.as_u64())
+            .sum::<u64>();
+        println!("live cells: {}", cells.len());
+        println!("total capacity: {} shannons", total_capacity);
+        let stats = self.storage.get_stats()?;
+        println!("sent transactions in total: {}", stats.txs);
+        println!("moved capacity in total: {} shannons", stats.capacity);
+        Ok(())
+    }
+}
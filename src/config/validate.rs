@@ -0,0 +1,27 @@
+use crate::error::{Error, Result};
+
+impl super::ValidateConfig {
+    pub(super) fn execute(&self) -> Result<()> {
+        log::info!("Validate ...");
+        let problems = match self {
+            Self::Init(ref metadata) => metadata.problems(),
+            Self::Run(ref run_env) => run_env.problems(),
+            Self::RunWithMetadata(ref run_env, ref metadata) => {
+                let mut problems = metadata.problems();
+                problems.extend(run_env.problems());
+                problems.extend(metadata.run_env_problems(run_env, &metadata.valid_accounts()));
+                problems
+            }
+        };
+        if problems.is_empty() {
+            println!("OK");
+            Ok(())
+        } else {
+            for problem in &problems {
+                println!("- {}", problem);
+            }
+            let errmsg = format!("found {} problems in the config", problems.len());
+            Err(Error::config(errmsg))
+        }
+    }
+}
@@ -10,8 +10,11 @@ pub enum Error {
     Runtime(String),
     #[error("storage error: {0}")]
     Storage(String),
+    // The reason is set when the CKB node rejects a transaction.
     #[error("client error: {0}")]
-    Client(String),
+    Client(String, Option<RejectReason>),
+    #[error("transport error: {0}")]
+    Transport(String),
 
     #[error("url error: {0}")]
     Url(#[from] url::ParseError),
@@ -21,6 +24,19 @@ pub enum Error {
     Db(#[from] rocksdb::Error),
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RejectReason {
+    // An input is already spent.
+    DeadCell,
+    // An input or a cell dep is not found.
+    UnknownCell,
+    LowFeeRate,
+    PoolIsFull,
+    Duplicated,
+    TooManyAncestors,
+    VerificationFailed,
+}
+
 pub type Result<T> = result::Result<T, Error>;
 
 impl Error {
@@ -34,11 +50,40 @@ impl Error {
         Self::Storage(inner.to_string())
     }
     pub(crate) fn client<T: fmt::Display>(inner: T) -> Self {
-        Self::Client(inner.to_string())
+        Self::Client(inner.to_string(), None)
+    }
+    pub(crate) fn rejected<T: fmt::Display>(inner: T, reason: RejectReason) -> Self {
+        Self::Client(inner.to_string(), Some(reason))
+    }
+    pub(crate) fn transport<T: fmt::Display>(inner: T) -> Self {
+        Self::Transport(inner.to_string())
+    }
+    pub(crate) fn crypto<T: fmt::Display>(inner: T) -> Self {
+        Self::Crypto(inner.to_string())
     }
     pub(crate) fn argument_should_exist(name: &str) -> Self {
         Self::Config(format!("argument {} should exist", name))
     }
+    pub(crate) fn reject_reason(&self) -> Option<RejectReason> {
+        match self {
+            Self::Client(_, reason) => *reason,
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::DeadCell => write!(f, "dead cell"),
+            Self::UnknownCell => write!(f, "unknown cell"),
+            Self::LowFeeRate => write!(f, "low fee rate"),
+            Self::PoolIsFull => write!(f, "pool is full"),
+            Self::Duplicated => write!(f, "duplicated"),
+            Self::TooManyAncestors => write!(f, "too many ancestors"),
+            Self::VerificationFailed => write!(f, "verification failed"),
+        }
+    }
 }
 
 impl From<ckb_crypto::secp::Error> for Error {
@@ -0,0 +1,91 @@
+use std::{
+    fs,
+    io::{BufWriter, Write as _},
+    path::Path,
+};
+
+use ckb_types::{packed, prelude::*, H256};
+use serde::Serialize;
+
+use crate::{
+    error::{Error, Result},
+    types::InputInfo,
+};
+
+// Records the cells spent and created by the sent transactions, one JSON object per line.
+//
+// The lines of a transaction are flushed once it is sent, so the file is complete whenever
+// the process is stopped.
+pub(crate) struct Ledger {
+    writer: Option<BufWriter<fs::File>>,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum Entry {
+    Spent {
+        tx_hash: H256,
+        out_point_tx_hash: H256,
+        out_point_index: u32,
+        capacity: u64,
+        lock_hash: H256,
+    },
+    Created {
+        tx_hash: H256,
+        out_point_index: u32,
+        capacity: u64,
+        lock_hash: H256,
+    },
+}
+
+impl Ledger {
+    pub(crate) fn new(path: Option<&Path>) -> Result<Self> {
+        let writer = path
+            .map(|path| {
+                fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .map(BufWriter::new)
+                    .map_err(|err| {
+                        let errmsg = format!(
+                            "failed to open the ledger file [{}] since {}",
+                            path.display(),
+                            err
+                        );
+                        Error::config(errmsg)
+                    })
+            })
+            .transpose()?;
+        Ok(Self { writer })
+    }
+
+    pub(crate) fn record(&mut self, tx: &packed::Transaction, inputs: &[InputInfo]) -> Result<()> {
+        let writer = if let Some(ref mut writer) = self.writer {
+            writer
+        } else {
+            return Ok(());
+        };
+        let tx_hash: H256 = tx.calc_tx_hash().unpack();
+        let spent = inputs.iter().map(|input| Entry::Spent {
+            tx_hash: tx_hash.clone(),
+            out_point_tx_hash: input.out_point.tx_hash().unpack(),
+            out_point_index: input.out_point.index().unpack(),
+            capacity: input.cell_info.capacity.as_u64(),
+            lock_hash: input.cell_info.lock_hash.clone(),
+        });
+        let created = tx.raw().outputs().into_iter().enumerate().map(
+            |(index, output): (usize, packed::CellOutput)| Entry::Created {
+                tx_hash: tx_hash.clone(),
+                out_point_index: index as u32,
+                capacity: output.capacity().unpack(),
+                lock_hash: output.lock().calc_script_hash().unpack(),
+            },
+        );
+        for entry in spent.chain(created) {
+            serde_json::to_writer(&mut *writer, &entry).map_err(Error::runtime)?;
+            writer.write_all(b"\n").map_err(Error::runtime)?;
+        }
+        writer.flush().map_err(Error::runtime)
+    }
+}
@@ -0,0 +1,99 @@
+use std::{
+    cell::RefCell,
+    io::Write as _,
+    str::FromStr,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use serde_json::{Map, Value};
+
+use crate::error::{Error, Result};
+
+static JSON_FORMAT: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    // The fields of the event which is being logged, only for the JSON format.
+    static EVENT_FIELDS: RefCell<Option<Map<String, Value>>> = RefCell::default();
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum LogFormat {
+    Text,
+    Json,
+}
+
+// Log an event with structured fields.
+//
+// In the JSON format, the fields are put into the record; otherwise, they are appended to the
+// message.
+macro_rules! log_event {
+    ($level:ident, $message:expr, $($key:ident = $value:expr),+ $(,)?) => {
+        $crate::logger::event(
+            log::Level::$level,
+            module_path!(),
+            $message,
+            vec![$((stringify!($key), serde_json::json!($value))),+],
+        )
+    };
+}
+
+pub(crate) use log_event;
+
+impl FromStr for LogFormat {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err(Error::config(format!("unknown log format {}", s))),
+        }
+    }
+}
+
+pub(crate) fn init(format: LogFormat) {
+    let mut builder = env_logger::Builder::from_default_env();
+    if format == LogFormat::Json {
+        JSON_FORMAT.store(true, Ordering::SeqCst);
+        builder.format(|buf, record| {
+            let mut object = Map::new();
+            object.insert(
+                "timestamp".to_owned(),
+                buf.timestamp_millis().to_string().into(),
+            );
+            object.insert("level".to_owned(), record.level().as_str().into());
+            object.insert("target".to_owned(), record.target().into());
+            object.insert("message".to_owned(), record.args().to_string().into());
+            if let Some(fields) = EVENT_FIELDS.with(|fields| fields.borrow_mut().take()) {
+                object.insert("fields".to_owned(), fields.into());
+            }
+            writeln!(buf, "{}", Value::Object(object))
+        });
+    }
+    builder.init();
+}
+
+pub(crate) fn event(level: log::Level, target: &str, message: &str, fields: Vec<(&str, Value)>) {
+    if !log::log_enabled!(target: target, level) {
+        return;
+    }
+    if JSON_FORMAT.load(Ordering::SeqCst) {
+        let fields = fields
+            .into_iter()
+            .map(|(key, value)| (key.to_owned(), value))
+            .collect();
+        EVENT_FIELDS.with(|current| *current.borrow_mut() = Some(fields));
+        log::log!(target: target, level, "{}", message);
+        // In case the record is not formatted.
+        EVENT_FIELDS.with(|current| current.borrow_mut().take());
+    } else {
+        let fields = fields
+            .into_iter()
+            .map(|(key, value)| match value {
+                Value::String(value) => format!("{}: {}", key, value),
+                value => format!("{}: {}", key, value),
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        log::log!(target: target, level, "{} ({})", message, fields);
+    }
+}
@@ -1,17 +1,22 @@
 mod client;
 mod config;
 mod error;
+mod ledger;
+mod logger;
+mod metrics;
 mod runtime;
 mod storage;
+mod telemetry;
+mod tx_log;
 mod types;
 
 use config::AppConfig;
 
 fn main() -> anyhow::Result<()> {
-    env_logger::init();
+    let config = AppConfig::load(logger::init)?;
     log::info!("Starting ...");
 
-    AppConfig::load()?.execute()?;
+    config.execute()?;
 
     log::info!("Done.");
     Ok(())
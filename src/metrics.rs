@@ -0,0 +1,170 @@
+use std::{
+    fmt::Write as _,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use ckb_types::core;
+use tokio::{
+    io::{AsyncReadExt as _, AsyncWriteExt as _},
+    runtime::Runtime,
+};
+
+use crate::error::{Error, Result};
+
+#[derive(Default)]
+pub(crate) struct Metrics {
+    txs_sent: AtomicU64,
+    send_successes: AtomicU64,
+    send_failures: AtomicU64,
+    cells_spent: AtomicU64,
+    synced_block_number: AtomicU64,
+}
+
+impl Metrics {
+    // Serve the metrics by a tiny HTTP server in the runtime of the run.
+    pub(crate) fn serve(self: &Arc<Self>, runtime: &Runtime, addr: SocketAddr) -> Result<()> {
+        let listener = std::net::TcpListener::bind(addr).map_err(|err| {
+            let errmsg = format!("failed to listen on [{}] for metrics since {}", addr, err);
+            Error::config(errmsg)
+        })?;
+        listener.set_nonblocking(true).map_err(Error::runtime)?;
+        log::info!("serve metrics on [{}]", addr);
+        let metrics = Arc::clone(self);
+        runtime.spawn(async move {
+            let listener = match tokio::net::TcpListener::from_std(listener) {
+                Ok(listener) => listener,
+                Err(err) => {
+                    log::error!("failed to serve metrics since {}", err);
+                    return;
+                }
+            };
+            loop {
+                let (mut stream, _) = match listener.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(err) => {
+                        log::warn!("failed to accept a metrics connection since {}", err);
+                        continue;
+                    }
+                };
+                let body = metrics.render();
+                tokio::spawn(async move {
+                    // Any request is answered with the metrics, so the request is not parsed.
+                    let mut buf = [0u8; 1024];
+                    let _ = stream.read(&mut buf).await;
+                    let response = format!(
+                        "HTTP/1.1 200 OK\r\n\
+                        Content-Type: text/plain; version=0.0.4\r\n\
+                        Content-Length: {}\r\n\
+                        Connection: close\r\n\r\n{}",
+                        body.len(),
+                        body
+                    );
+                    if let Err(err) = stream.write_all(response.as_bytes()).await {
+                        log::debug!("failed to respond the metrics since {}", err);
+                    }
+                });
+            }
+        });
+        Ok(())
+    }
+
+    pub(crate) fn tx_sent(&self) {
+        self.txs_sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn send_succeeded(&self, cells_spent: usize) {
+        self.send_successes.fetch_add(1, Ordering::Relaxed);
+        self.cells_spent
+            .fetch_add(cells_spent as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn send_failed(&self) {
+        self.send_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn set_synced_block_number(&self, number: core::BlockNumber) {
+        self.synced_block_number.store(number, Ordering::Relaxed);
+    }
+
+    fn render(&self) -> String {
+        let items = [
+            (
+                "ckb_simulator_txs_sent_total",
+                "counter",
+                "The count of the transactions which were sent.",
+                &self.txs_sent,
+            ),
+            (
+                "ckb_simulator_send_successes_total",
+                "counter",
+                "The count of the transactions which were sent successfully.",
+                &self.send_successes,
+            ),
+            (
+                "ckb_simulator_send_failures_total",
+                "counter",
+                "The count of the transactions which were failed to send.",
+                &self.send_failures,
+            ),
+            (
+                "ckb_simulator_cells_spent_total",
+                "counter",
+                "The count of the cells which were spent by the sent transactions.",
+                &self.cells_spent,
+            ),
+            (
+                "ckb_simulator_synced_block_number",
+                "gauge",
+                "The number of the last synchronized block.",
+                &self.synced_block_number,
+            ),
+        ];
+        let mut output = String::new();
+        for (name, kind, help, value) in &items {
+            let _ = writeln!(output, "# HELP {} {}", name, help);
+            let _ = writeln!(output, "# TYPE {} {}", name, kind);
+            let _ = writeln!(output, "{} {}", name, value.load(Ordering::Relaxed));
+        }
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_the_counters() {
+        let metrics = Metrics::default();
+        metrics.tx_sent();
+        metrics.tx_sent();
+        metrics.tx_sent();
+        metrics.send_succeeded(2);
+        metrics.send_succeeded(3);
+        metrics.send_failed();
+        metrics.set_synced_block_number(100);
+        metrics.set_synced_block_number(42);
+        let rendered = metrics.render();
+        let samples = rendered
+            .lines()
+            .filter(|line| !line.starts_with('#'))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            samples,
+            vec![
+                "ckb_simulator_txs_sent_total 3",
+                "ckb_simulator_send_successes_total 2",
+                "ckb_simulator_send_failures_total 1",
+                "ckb_simulator_cells_spent_total 5",
+                "ckb_simulator_synced_block_number 42",
+            ]
+        );
+        assert!(rendered.contains("# TYPE ckb_simulator_txs_sent_total counter\n"));
+        assert!(rendered.contains("# TYPE ckb_simulator_synced_block_number gauge\n"));
+        assert_eq!(rendered.lines().count(), 15);
+    }
+}
@@ -1,69 +1,255 @@
-use std::{path::Path, str::FromStr};
+use std::{
+    collections::HashSet,
+    env, fs,
+    path::{Path, PathBuf},
+    process,
+    str::FromStr,
+};
 
-use ckb_types::{core, packed, prelude::*};
+use ckb_types::{core, packed, prelude::*, H256};
 
 use crate::{
     error::{Error, Result},
-    types::{CellInfo, InputInfo, MetaData},
+    types::{CellInfo, InputInfo, MetaData, RunStats, SentTx, StorageConfig, SyncedBlock},
 };
 
 const KEY_METADATA: &[u8] = b"metadata";
 const KEY_NEXT_BLOCK_NUMBER: &[u8] = b"next-block-number";
+const KEY_RUN_STATS: &[u8] = b"run-stats";
+const KEY_SCHEMA_VERSION: &[u8] = b"schema-version";
+
+// The version of the layout of the stored data, bump it and add a migration when the layout is
+// changed. No version was stored before the version 1.
+//
+// - 1: the cells are stored with their block numbers.
+// - 2: the added cells are indexed by the block numbers, and the removed cells of the
+//   synchronized blocks are stored with whether they were spent.
+// - 3: the sent transactions are indexed by the block numbers when they were sent.
+const SCHEMA_VERSION: u32 = 3;
+
+// Only keep the recent synchronized blocks to roll back, and the transactions which were sent
+// in the same window.
+pub(crate) const MAX_ROLLBACK_BLOCKS: u64 = 1024;
 
 pub(crate) struct Storage {
     db: rocksdb::DB,
+    read_only: bool,
+    // The fields are dropped in order, so the directory is removed after the database is closed.
+    temporary: Option<TemporaryDir>,
+}
+
+// A directory which is removed when it is dropped.
+struct TemporaryDir(PathBuf);
+
+impl Drop for TemporaryDir {
+    fn drop(&mut self) {
+        if let Err(err) = fs::remove_dir_all(&self.0) {
+            log::warn!(
+                "failed to remove the temporary directory [{}] since {}",
+                self.0.display(),
+                err
+            );
+        }
+    }
 }
 
 impl Storage {
     const CF_CACHE: &'static str = "cache";
     const CF_CELLS: &'static str = "cells";
+    const CF_TXS: &'static str = "txs";
+    const CF_BLOCKS: &'static str = "blocks";
+    const CF_STATS: &'static str = "stats";
+    // The cells which were added by the synchronized blocks, the keys are the block numbers
+    // followed by the out points, and the values are empty.
+    const CF_BLOCK_CELLS: &'static str = "block-cells";
+    // The sent transactions, the keys are the block numbers when they were sent followed by the
+    // transaction hashes, and the values are empty.
+    const CF_BLOCK_TXS: &'static str = "block-txs";
 
-    const CF_NAMES: &'static [&'static str] = &[Self::CF_CACHE, Self::CF_CELLS];
+    const CF_NAMES: &'static [&'static str] = &[
+        Self::CF_CACHE,
+        Self::CF_CELLS,
+        Self::CF_TXS,
+        Self::CF_BLOCKS,
+        Self::CF_STATS,
+        Self::CF_BLOCK_CELLS,
+        Self::CF_BLOCK_TXS,
+    ];
 
-    pub(crate) fn init<P: AsRef<Path>>(path: P) -> Result<Self> {
+    pub(crate) fn init<P: AsRef<Path>>(path: P, config: &StorageConfig) -> Result<Self> {
         let path = path.as_ref();
         if path.exists() {
             let errmsg = format!("the directory [{}] alreay exists", path.display());
             Err(Error::storage(errmsg))
         } else {
-            Self::open(path, true)
+            let storage = Self::open(path, true, config)?;
+            storage.put_schema_version()?;
+            Ok(storage)
         }
     }
 
     pub(crate) fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::load_with_config(path, &StorageConfig::default())
+    }
+
+    pub(crate) fn load_with_config<P: AsRef<Path>>(
+        path: P,
+        config: &StorageConfig,
+    ) -> Result<Self> {
+        let path = path.as_ref();
+        if !path.exists() || !path.is_dir() {
+            let errmsg = format!("the directory [{}] doesn't exists", path.display());
+            return Err(Error::storage(errmsg));
+        }
+        let storage = Self::load_unmigrated(path, config)?;
+        if storage.get_schema_version()? < SCHEMA_VERSION {
+            storage.migrate()?;
+        }
+        Ok(storage)
+    }
+
+    // Load the storage without migrating it, only to copy the data as it is.
+    pub(crate) fn load_unmigrated<P: AsRef<Path>>(path: P, config: &StorageConfig) -> Result<Self> {
         let path = path.as_ref();
         if !path.exists() || !path.is_dir() {
             let errmsg = format!("the directory [{}] doesn't exists", path.display());
             return Err(Error::storage(errmsg));
         }
-        Self::open(path, false)
+        let storage = Self::open(path, false, config)?;
+        storage.check_newer_schema_version()?;
+        Ok(storage)
+    }
+
+    pub(crate) fn load_read_only<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let storage = Self::load_read_only_unmigrated(path)?;
+        // The data can not be migrated without writing.
+        if storage.get_schema_version()? < SCHEMA_VERSION {
+            let errmsg = format!(
+                "the data in [{}] should be migrated by loading it with writing first",
+                path.display()
+            );
+            return Err(Error::storage(errmsg));
+        }
+        Ok(storage)
+    }
+
+    // Load the storage as read-only without checking whether it is migrated, only to copy the
+    // data as it is.
+    pub(crate) fn load_read_only_unmigrated<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        if !path.exists() || !path.is_dir() {
+            let errmsg = format!("the directory [{}] doesn't exists", path.display());
+            return Err(Error::storage(errmsg));
+        }
+        let opts = Self::default_dboptions(false, &StorageConfig::default());
+        let existed_cf_names = rocksdb::DB::list_cf(&opts, path)?;
+        let cf_names = Self::CF_NAMES
+            .iter()
+            .filter(|name| existed_cf_names.iter().any(|existed| existed == *name));
+        let db = rocksdb::DB::open_cf_for_read_only(&opts, path, cf_names, false)?;
+        let storage = Self {
+            db,
+            read_only: true,
+            temporary: None,
+        };
+        storage.check_newer_schema_version()?;
+        Ok(storage)
+    }
+
+    // Load a temporary copy of the storage, the copy is removed when it is dropped, and nothing
+    // is written into the original one.
+    pub(crate) fn load_temporary_copy<P: AsRef<Path>>(
+        path: P,
+        config: &StorageConfig,
+    ) -> Result<Self> {
+        let original = Self::load_read_only_unmigrated(path)?;
+        let copy_dir = env::temp_dir().join(format!("ckb-simulator-copy-{}", process::id()));
+        log::info!("copy the storage into [{}] ...", copy_dir.display());
+        let copied = original.create_checkpoint(&copy_dir);
+        // Remove the copy even if it is incomplete.
+        let temporary = TemporaryDir(copy_dir);
+        copied?;
+        let mut storage = Self::load_with_config(&temporary.0, config)?;
+        storage.temporary = Some(temporary);
+        Ok(storage)
+    }
+
+    // Create a consistent snapshot of the storage.
+    pub(crate) fn create_checkpoint<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        if path.exists() {
+            let errmsg = format!("the directory [{}] alreay exists", path.display());
+            return Err(Error::storage(errmsg));
+        }
+        if self.read_only {
+            // A read-only storage can not flush the data which are only in the WAL (which are
+            // written by another running instance) into the checkpoint, so copy all data.
+            self.copy_into(path)
+        } else {
+            rocksdb::checkpoint::Checkpoint::new(&self.db)?
+                .create_checkpoint(path)
+                .map_err(Into::into)
+        }
     }
 
-    fn open<P: AsRef<Path>>(path: P, create: bool) -> Result<Self> {
-        let opts = Self::default_dboptions(create);
-        let cfs = Self::default_column_family_descriptors();
+    fn copy_into(&self, path: &Path) -> Result<()> {
+        const BATCH_SIZE: usize = 4096;
+        let target = Self::open(path, true, &StorageConfig::default())?;
+        let mut batch = rocksdb::WriteBatch::default();
+        for (key, value) in self.db.iterator(rocksdb::IteratorMode::Start) {
+            batch.put(key, value);
+        }
+        for cf_name in Self::CF_NAMES {
+            // The column families which were added later may not exist.
+            let cf = if let Some(cf) = self.db.cf_handle(cf_name) {
+                cf
+            } else {
+                continue;
+            };
+            let target_cf = target.cf_handle(cf_name)?;
+            for (key, value) in self.db.full_iterator_cf(cf, rocksdb::IteratorMode::Start) {
+                batch.put_cf(target_cf, key, value);
+                if batch.len() >= BATCH_SIZE {
+                    target.db.write(batch)?;
+                    batch = rocksdb::WriteBatch::default();
+                }
+            }
+        }
+        target.db.write(batch)?;
+        target.flush()
+    }
+
+    fn open<P: AsRef<Path>>(path: P, create: bool, config: &StorageConfig) -> Result<Self> {
+        let opts = Self::default_dboptions(create, config);
+        let cfs = Self::default_column_family_descriptors(config);
         let db = rocksdb::DB::open_cf_descriptors(&opts, &path, cfs)?;
-        Ok(Self { db })
+        Ok(Self {
+            db,
+            read_only: false,
+            temporary: None,
+        })
     }
 
-    fn default_dboptions(create: bool) -> rocksdb::Options {
+    fn default_dboptions(create: bool, config: &StorageConfig) -> rocksdb::Options {
         let mut opts = rocksdb::Options::default();
         if create {
             opts.create_if_missing(true);
             opts.create_missing_column_families(true);
         } else {
             opts.create_if_missing(false);
-            opts.create_missing_column_families(false);
+            // For the column families which were added later.
+            opts.create_missing_column_families(true);
         }
         // DBOptions
         opts.set_bytes_per_sync(1 << 20);
-        opts.set_max_background_jobs(4);
+        opts.set_max_background_jobs(config.max_background_jobs());
         opts.set_max_total_wal_size((1 << 20) * 64);
         opts.set_keep_log_file_num(64);
-        opts.set_max_open_files(64);
+        opts.set_max_open_files(config.max_open_files());
         // CFOptions "default"
         opts.set_level_compaction_dynamic_level_bytes(true);
-        opts.set_write_buffer_size((1 << 20) * 8);
+        opts.set_write_buffer_size(config.write_buffer_size());
         opts.set_min_write_buffer_number_to_merge(1);
         opts.set_max_write_buffer_number(2);
         opts.set_max_write_buffer_size_to_maintain(-1);
@@ -80,18 +266,20 @@ impl Storage {
         opts
     }
 
-    fn default_cfoptions() -> rocksdb::Options {
+    fn default_cfoptions(config: &StorageConfig) -> rocksdb::Options {
         let mut opts = rocksdb::Options::default();
         opts.set_level_compaction_dynamic_level_bytes(true);
-        opts.set_write_buffer_size((1 << 20) * 8);
+        opts.set_write_buffer_size(config.write_buffer_size());
         opts.set_min_write_buffer_number_to_merge(1);
         opts.set_max_write_buffer_number(2);
         opts.set_max_write_buffer_size_to_maintain(-1);
         opts
     }
 
-    fn default_column_family_descriptors() -> Vec<rocksdb::ColumnFamilyDescriptor> {
-        let cfopts = Self::default_cfoptions();
+    fn default_column_family_descriptors(
+        config: &StorageConfig,
+    ) -> Vec<rocksdb::ColumnFamilyDescriptor> {
+        let cfopts = Self::default_cfoptions(config);
         Self::CF_NAMES
             .iter()
             .map(|name| rocksdb::ColumnFamilyDescriptor::new(name.to_owned(), cfopts.clone()))
@@ -107,6 +295,184 @@ impl Storage {
         })
     }
 
+    fn get_schema_version(&self) -> Result<u32> {
+        self.db
+            .get_pinned(KEY_SCHEMA_VERSION)?
+            .map(|slice| {
+                if slice.len() != 4 {
+                    let errmsg = format!(
+                        "the stored schema version should be 4 bytes, but got {} bytes",
+                        slice.len()
+                    );
+                    return Err(Error::storage(errmsg));
+                }
+                let mut version_be = [0u8; 4];
+                version_be.copy_from_slice(&slice);
+                Ok(u32::from_be_bytes(version_be))
+            })
+            .transpose()
+            .map(Option::unwrap_or_default)
+    }
+
+    fn put_schema_version(&self) -> Result<()> {
+        self.db
+            .put(KEY_SCHEMA_VERSION, SCHEMA_VERSION.to_be_bytes())
+            .map_err(Into::into)
+    }
+
+    fn check_newer_schema_version(&self) -> Result<()> {
+        let version = self.get_schema_version()?;
+        if version > SCHEMA_VERSION {
+            let errmsg = format!(
+                "the schema version of the data is {}, which is newer than {}, \
+                upgrade the simulator first",
+                version, SCHEMA_VERSION
+            );
+            return Err(Error::storage(errmsg));
+        }
+        Ok(())
+    }
+
+    /// Upgrade the data to the current schema version.
+    fn migrate(&self) -> Result<()> {
+        let version = self.get_schema_version()?;
+        if version < 1 {
+            self.migrate_cells_without_block_number()?;
+        }
+        if version < 2 {
+            self.migrate_synced_blocks()?;
+        }
+        if version < 3 {
+            self.migrate_sent_txs()?;
+        }
+        self.put_schema_version()
+    }
+
+    /// The cells which were stored without the block number get the genesis block number, since
+    /// the real block numbers are unknown, then they are never removed by a rollback.
+    fn migrate_cells_without_block_number(&self) -> Result<()> {
+        for cf_name in &[Self::CF_CELLS, Self::CF_CACHE] {
+            let cf = self.cf_handle(cf_name)?;
+            let legacy_cells = self
+                .db
+                .full_iterator_cf(cf, rocksdb::IteratorMode::Start)
+                .filter(|(_, value)| value.len() == CellInfo::LEGACY_SIZE)
+                .collect::<Vec<_>>();
+            if legacy_cells.is_empty() {
+                continue;
+            }
+            log::info!(
+                "migrate {} cells in column family {} ...",
+                legacy_cells.len(),
+                cf_name
+            );
+            for (key, value) in legacy_cells {
+                let info = CellInfo::from_legacy_slice(&value, 0);
+                self.db.put_cf(cf, key, info.to_vec())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Index the cells of the blocks which can be rolled back, and store whether the removed
+    /// cells of those blocks were spent by the sent transactions.
+    fn migrate_synced_blocks(&self) -> Result<()> {
+        let cf_txs = self.cf_handle(Self::CF_TXS)?;
+        let cf_blocks = self.cf_handle(Self::CF_BLOCKS)?;
+        let cf_block_cells = self.cf_handle(Self::CF_BLOCK_CELLS)?;
+        log::info!("migrate the synchronized blocks ...");
+        let mut batch = rocksdb::WriteBatch::default();
+        let window_start = self.window_start()?;
+        for cf_name in &[Self::CF_CELLS, Self::CF_CACHE] {
+            let cf = self.cf_handle(cf_name)?;
+            for (key, value) in self.db.full_iterator_cf(cf, rocksdb::IteratorMode::Start) {
+                let block_number = CellInfo::from_slice(&value).block_number;
+                if block_number >= window_start {
+                    batch.put_cf(cf_block_cells, block_key(block_number, &key), []);
+                }
+            }
+        }
+        let spent_by_sent_txs = self
+            .db
+            .full_iterator_cf(cf_txs, rocksdb::IteratorMode::Start)
+            .map(|(_, value)| SentTx::from_slice(&value).map(|sent_tx| sent_tx.inputs))
+            .collect::<Result<Vec<_>>>()?
+            .into_iter()
+            .flatten()
+            .collect::<HashSet<_>>();
+        for (key, value) in self
+            .db
+            .full_iterator_cf(cf_blocks, rocksdb::IteratorMode::Start)
+        {
+            let mut synced = SyncedBlock::from_legacy_slice(&value)?;
+            for (op, _, spent) in &mut synced.removed {
+                *spent = spent_by_sent_txs.contains(op);
+            }
+            batch.put_cf(cf_blocks, key, synced.to_vec());
+        }
+        self.db.write(batch).map_err(Into::into)
+    }
+
+    /// Index the sent transactions in the window by the block numbers, remove the older ones.
+    fn migrate_sent_txs(&self) -> Result<()> {
+        let cf_txs = self.cf_handle(Self::CF_TXS)?;
+        let cf_block_txs = self.cf_handle(Self::CF_BLOCK_TXS)?;
+        log::info!("migrate the sent transactions ...");
+        let mut batch = rocksdb::WriteBatch::default();
+        let window_start = self.window_start()?;
+        for (key, value) in self
+            .db
+            .full_iterator_cf(cf_txs, rocksdb::IteratorMode::Start)
+        {
+            let sent_at = SentTx::from_slice(&value)?.sent_at;
+            if sent_at >= window_start {
+                batch.put_cf(cf_block_txs, block_key(sent_at, &key), []);
+            } else {
+                batch.delete_cf(cf_txs, key);
+            }
+        }
+        self.db.write(batch).map_err(Into::into)
+    }
+
+    // The first block number in the rollback window, the data of the older blocks are pruned.
+    fn window_start(&self) -> Result<core::BlockNumber> {
+        let start = self
+            .get_next_number()?
+            .map(|next| next.saturating_sub(MAX_ROLLBACK_BLOCKS + 1))
+            .unwrap_or(0);
+        Ok(start)
+    }
+
+    // Remove the data of the blocks which fall out of the window when the block is synchronized,
+    // the older ones were removed already.
+    fn prune_into(
+        &self,
+        batch: &mut rocksdb::WriteBatch,
+        block_number: core::BlockNumber,
+    ) -> Result<()> {
+        let end = if let Some(end) = block_number.checked_sub(MAX_ROLLBACK_BLOCKS) {
+            end
+        } else {
+            return Ok(());
+        };
+        let cf_txs = self.cf_handle(Self::CF_TXS)?;
+        let from = self.window_start()?.to_be_bytes();
+        for cf_name in &[Self::CF_BLOCKS, Self::CF_BLOCK_CELLS, Self::CF_BLOCK_TXS] {
+            let cf = self.cf_handle(cf_name)?;
+            let mode = rocksdb::IteratorMode::From(&from, rocksdb::Direction::Forward);
+            for (key, _) in self.db.iterator_cf(cf, mode) {
+                if key_block_number(&key)? >= end {
+                    break;
+                }
+                if *cf_name == Self::CF_BLOCK_TXS {
+                    batch.delete_cf(cf_txs, &key[8..]);
+                }
+                batch.delete_cf(cf, key);
+            }
+        }
+        Ok(())
+    }
+
     pub(crate) fn put_metadata(&self, metadata: &MetaData) -> Result<()> {
         self.db
             .put(KEY_METADATA, metadata.to_string().as_bytes())
@@ -125,23 +491,31 @@ impl Storage {
     }
 
     pub(crate) fn put_prev_number(&self, number: core::BlockNumber) -> Result<()> {
-        let number_be = number.to_be_bytes();
-        self.db
-            .put(KEY_NEXT_BLOCK_NUMBER, &number_be)
-            .map_err(Into::into)
+        let mut batch = rocksdb::WriteBatch::default();
+        self.prune_into(&mut batch, number)?;
+        batch.put(KEY_NEXT_BLOCK_NUMBER, number.to_be_bytes());
+        self.db.write(batch).map_err(Into::into)
     }
 
     pub(crate) fn get_next_number(&self) -> Result<Option<core::BlockNumber>> {
         self.db
             .get(KEY_NEXT_BLOCK_NUMBER)
-            .map_err::<Error, _>(Into::into)
-            .map(|opt| {
-                opt.map(|slice| {
-                    let mut number_be = [0u8; 8];
-                    number_be.copy_from_slice(&slice[0..8]);
-                    core::BlockNumber::from_be_bytes(number_be) + 1
-                })
+            .map_err::<Error, _>(Into::into)?
+            .map(|slice| {
+                if slice.len() != 8 {
+                    let errmsg = format!(
+                        "the stored block number should be 8 bytes, but got {} bytes",
+                        slice.len()
+                    );
+                    return Err(Error::storage(errmsg));
+                }
+                let mut number_be = [0u8; 8];
+                number_be.copy_from_slice(&slice[0..8]);
+                core::BlockNumber::from_be_bytes(number_be)
+                    .checked_add(1)
+                    .ok_or_else(|| Error::storage("the stored block number overflows"))
             })
+            .transpose()
     }
 
     pub(crate) fn add_cell(&self, op: packed::OutPoint, info: CellInfo) -> Result<()> {
@@ -151,24 +525,300 @@ impl Storage {
             .map_err(Into::into)
     }
 
-    pub(crate) fn spend_cell(&self, op: packed::OutPoint) -> Result<()> {
+    // Remove a cell which is not live any more.
+    //
+    // The block which spends it may not be synchronized yet, so the cell is recorded as removed by
+    // the last synchronized block, then it is restored if that block is rolled back.
+    pub(crate) fn rm_cell(&self, op: &packed::OutPoint) -> Result<()> {
+        let cf_cells = self.cf_handle(Self::CF_CELLS)?;
+        let cf_blocks = self.cf_handle(Self::CF_BLOCKS)?;
+        let info = match self.db.get_pinned_cf(cf_cells, op.as_slice())? {
+            Some(slice) => CellInfo::from_slice(&slice),
+            None => return Ok(()),
+        };
+        let mut batch = rocksdb::WriteBatch::default();
+        batch.delete_cf(cf_cells, op.as_slice());
+        if let Some(last_number) = self.get_next_number()?.and_then(|next| next.checked_sub(1)) {
+            let key = last_number.to_be_bytes();
+            if let Some(slice) = self.db.get_pinned_cf(cf_blocks, key)? {
+                let mut synced = SyncedBlock::from_slice(&slice)?;
+                synced.removed.push((op.clone(), info, false));
+                batch.put_cf(cf_blocks, key, synced.to_vec());
+            }
+        }
+        self.db.write(batch).map_err(Into::into)
+    }
+
+    // Spend the inputs of a sent transaction and count it into the statistics atomically.
+    pub(crate) fn spend_cells(&self, ops: &[packed::OutPoint], capacity: u64) -> Result<()> {
         let cf_cells = self.cf_handle(Self::CF_CELLS)?;
         let cf_cache = self.cf_handle(Self::CF_CACHE)?;
-        let cap = self.db.get_cf(cf_cells, op.as_slice())?.ok_or_else(|| {
-            let errmsg = format!("cell {} should exists", op);
-            Error::storage(errmsg)
-        })?;
-        self.db.delete_cf(cf_cells, op.as_slice())?;
+        let mut batch = rocksdb::WriteBatch::default();
+        for op in ops {
+            let cap = self.db.get_cf(cf_cells, op.as_slice())?.ok_or_else(|| {
+                let errmsg = format!("cell {} should exists", op);
+                Error::storage(errmsg)
+            })?;
+            batch.delete_cf(cf_cells, op.as_slice());
+            batch.put_cf(cf_cache, op.as_slice(), cap.as_slice());
+        }
+        self.incr_stats_into(&mut batch, 1, capacity)?;
+        self.db.write(batch).map_err(Into::into)
+    }
+
+    fn incr_stats_into(
+        &self,
+        batch: &mut rocksdb::WriteBatch,
+        txs: u64,
+        capacity: u64,
+    ) -> Result<()> {
+        let cf_stats = self.cf_handle(Self::CF_STATS)?;
+        let mut stats = self.get_stats()?;
+        stats.txs = stats.txs.saturating_add(txs);
+        stats.capacity = stats.capacity.saturating_add(capacity);
+        batch.put_cf(cf_stats, KEY_RUN_STATS, stats.to_vec());
+        Ok(())
+    }
+
+    pub(crate) fn get_stats(&self) -> Result<RunStats> {
+        // The column family doesn't exist when a legacy directory is loaded as read-only.
+        let cf_stats = match self.db.cf_handle(Self::CF_STATS) {
+            Some(cf) => cf,
+            None => return Ok(RunStats::default()),
+        };
         self.db
-            .put_cf(cf_cache, op.as_slice(), cap.as_slice())
-            .map_err(Into::into)
+            .get_pinned_cf(cf_stats, KEY_RUN_STATS)?
+            .map(|slice| RunStats::from_slice(&slice))
+            .transpose()
+            .map(Option::unwrap_or_default)
     }
 
-    pub(crate) fn rm_cell(&self, op: packed::OutPoint) -> Result<()> {
+    // Apply all changes of a block and save its number atomically.
+    //
+    // Returns the count of the removed cells which were stored.
+    pub(crate) fn apply_block_changes(
+        &self,
+        adds: Vec<(packed::OutPoint, CellInfo)>,
+        removes: Vec<packed::OutPoint>,
+        block_number: core::BlockNumber,
+        block_hash: H256,
+    ) -> Result<u64> {
         let cf_cells = self.cf_handle(Self::CF_CELLS)?;
         let cf_cache = self.cf_handle(Self::CF_CACHE)?;
-        self.db.delete_cf(cf_cells, op.as_slice())?;
-        self.db.delete_cf(cf_cache, op.as_slice())?;
+        let cf_block_cells = self.cf_handle(Self::CF_BLOCK_CELLS)?;
+        let mut removed = 0;
+        let mut synced = SyncedBlock {
+            hash: block_hash,
+            removed: Vec::new(),
+        };
+        let mut batch = rocksdb::WriteBatch::default();
+        for (op, info) in &adds {
+            batch.put_cf(cf_cells, op.as_slice(), info.to_vec().as_slice());
+            batch.put_cf(cf_block_cells, block_key(block_number, op.as_slice()), []);
+        }
+        let added = adds.iter().map(|(op, _)| op).collect::<HashSet<_>>();
+        for op in removes {
+            if added.contains(&op) {
+                removed += 1;
+            } else if let Some(slice) = self.db.get_pinned_cf(cf_cells, op.as_slice())? {
+                removed += 1;
+                synced
+                    .removed
+                    .push((op.clone(), CellInfo::from_slice(&slice), false));
+            } else if let Some(slice) = self.db.get_pinned_cf(cf_cache, op.as_slice())? {
+                removed += 1;
+                synced
+                    .removed
+                    .push((op.clone(), CellInfo::from_slice(&slice), true));
+            }
+            batch.delete_cf(cf_cells, op.as_slice());
+            batch.delete_cf(cf_cache, op.as_slice());
+        }
+        self.put_synced_block_into(&mut batch, block_number, &synced)?;
+        self.db.write(batch)?;
+        Ok(removed)
+    }
+
+    // Save the number and the hash of a block which has no changes.
+    pub(crate) fn put_prev_block(
+        &self,
+        block_number: core::BlockNumber,
+        block_hash: H256,
+    ) -> Result<()> {
+        let synced = SyncedBlock {
+            hash: block_hash,
+            removed: Vec::new(),
+        };
+        let mut batch = rocksdb::WriteBatch::default();
+        self.put_synced_block_into(&mut batch, block_number, &synced)?;
+        self.db.write(batch).map_err(Into::into)
+    }
+
+    fn put_synced_block_into(
+        &self,
+        batch: &mut rocksdb::WriteBatch,
+        block_number: core::BlockNumber,
+        synced: &SyncedBlock,
+    ) -> Result<()> {
+        let cf_blocks = self.cf_handle(Self::CF_BLOCKS)?;
+        batch.put_cf(cf_blocks, block_number.to_be_bytes(), synced.to_vec());
+        self.prune_into(batch, block_number)?;
+        batch.put(KEY_NEXT_BLOCK_NUMBER, block_number.to_be_bytes());
+        Ok(())
+    }
+
+    pub(crate) fn get_block_hash(&self, block_number: core::BlockNumber) -> Result<Option<H256>> {
+        let cf_blocks = self.cf_handle(Self::CF_BLOCKS)?;
+        self.db
+            .get_pinned_cf(cf_blocks, block_number.to_be_bytes())?
+            .map(|slice| SyncedBlock::from_slice(&slice).map(|synced| synced.hash))
+            .transpose()
+    }
+
+    // Roll back all synchronized blocks since the provided block number: remove the cells which
+    // were added by them, and restore the cells which were removed by them.
+    //
+    // The restored cells which were spent by the sent transactions are put into the cache, since
+    // those transactions could be committed again.
+    //
+    // Returns the counts of the removed cells and the restored cells.
+    pub(crate) fn rollback_blocks(&self, fork_number: core::BlockNumber) -> Result<(u64, u64)> {
+        let cf_cells = self.cf_handle(Self::CF_CELLS)?;
+        let cf_cache = self.cf_handle(Self::CF_CACHE)?;
+        let cf_blocks = self.cf_handle(Self::CF_BLOCKS)?;
+        let cf_block_cells = self.cf_handle(Self::CF_BLOCK_CELLS)?;
+        let mut batch = rocksdb::WriteBatch::default();
+        let from = fork_number.to_be_bytes();
+        let mut removed = 0;
+        let mode = rocksdb::IteratorMode::From(&from, rocksdb::Direction::Forward);
+        for (key, _) in self.db.iterator_cf(cf_block_cells, mode) {
+            let op = &key[8..];
+            for cf in &[cf_cells, cf_cache] {
+                if self.db.get_pinned_cf(cf, op)?.is_some() {
+                    batch.delete_cf(cf, op);
+                    removed += 1;
+                }
+            }
+            batch.delete_cf(cf_block_cells, key);
+        }
+        let mut restored = 0;
+        let mode = rocksdb::IteratorMode::From(&from, rocksdb::Direction::Forward);
+        for (key, value) in self.db.iterator_cf(cf_blocks, mode) {
+            let synced = SyncedBlock::from_slice(&value)?;
+            for (op, info, spent) in synced.removed {
+                if info.block_number >= fork_number {
+                    continue;
+                }
+                let cf = if spent { cf_cache } else { cf_cells };
+                batch.put_cf(cf, op.as_slice(), info.to_vec().as_slice());
+                restored += 1;
+            }
+            batch.delete_cf(cf_blocks, key);
+        }
+        if let Some(prev_number) = fork_number.checked_sub(1) {
+            batch.put(KEY_NEXT_BLOCK_NUMBER, prev_number.to_be_bytes());
+        } else {
+            batch.delete(KEY_NEXT_BLOCK_NUMBER);
+        }
+        self.db.write(batch)?;
+        Ok((removed, restored))
+    }
+
+    // Remove all cells and all synchronized blocks, then the next block to synchronize is the
+    // start block. The metadata, the sent transactions and the stats are kept.
+    //
+    // Returns the count of the removed cells.
+    pub(crate) fn reset_cells(&self) -> Result<u64> {
+        let mut batch = rocksdb::WriteBatch::default();
+        let mut removed = 0;
+        for cf_name in &[Self::CF_CELLS, Self::CF_CACHE] {
+            let cf = self.cf_handle(cf_name)?;
+            for (key, _) in self.db.full_iterator_cf(cf, rocksdb::IteratorMode::Start) {
+                batch.delete_cf(cf, key);
+                removed += 1;
+            }
+        }
+        for cf_name in &[Self::CF_BLOCKS, Self::CF_BLOCK_CELLS] {
+            let cf = self.cf_handle(cf_name)?;
+            for (key, _) in self.db.full_iterator_cf(cf, rocksdb::IteratorMode::Start) {
+                batch.delete_cf(cf, key);
+            }
+        }
+        batch.delete(KEY_NEXT_BLOCK_NUMBER);
+        self.db.write(batch)?;
+        Ok(removed)
+    }
+
+    pub(crate) fn has_cell(&self, op: &packed::OutPoint) -> Result<bool> {
+        let cf_cells = self.cf_handle(Self::CF_CELLS)?;
+        let cf_cache = self.cf_handle(Self::CF_CACHE)?;
+        let exists = self.db.get_pinned_cf(cf_cells, op.as_slice())?.is_some()
+            || self.db.get_pinned_cf(cf_cache, op.as_slice())?.is_some();
+        Ok(exists)
+    }
+
+    // Remove all unspent cells which are not in the provided set.
+    //
+    // The spent cells in the cache are kept, since they are not live when their transactions are
+    // pending, and they are required if those transactions are rejected.
+    pub(crate) fn retain_cells(&self, live: &HashSet<packed::OutPoint>) -> Result<u64> {
+        let cf_cells = self.cf_handle(Self::CF_CELLS)?;
+        let dead = self
+            .db
+            .full_iterator_cf(cf_cells, rocksdb::IteratorMode::Start)
+            .map(|(key, _)| packed::OutPoint::from_slice(&key).map_err(Error::storage))
+            .filter(|op| op.as_ref().map(|op| !live.contains(op)).unwrap_or(true))
+            .collect::<Result<Vec<_>>>()?;
+        let mut batch = rocksdb::WriteBatch::default();
+        for op in &dead {
+            batch.delete_cf(cf_cells, op.as_slice());
+        }
+        self.db.write(batch)?;
+        Ok(dead.len() as u64)
+    }
+
+    pub(crate) fn record_sent_tx(
+        &self,
+        tx_hash: H256,
+        inputs: &[packed::OutPoint],
+        sent_at: core::BlockNumber,
+    ) -> Result<()> {
+        let cf_txs = self.cf_handle(Self::CF_TXS)?;
+        let cf_block_txs = self.cf_handle(Self::CF_BLOCK_TXS)?;
+        let sent_tx = SentTx {
+            sent_at,
+            inputs: inputs.to_vec(),
+        };
+        let mut batch = rocksdb::WriteBatch::default();
+        batch.put_cf(cf_txs, tx_hash.as_bytes(), sent_tx.to_vec());
+        batch.put_cf(cf_block_txs, block_key(sent_at, tx_hash.as_bytes()), []);
+        self.db.write(batch).map_err(Into::into)
+    }
+
+    pub(crate) fn get_sent_tx(&self, tx_hash: &H256) -> Result<Option<SentTx>> {
+        let cf_txs = self.cf_handle(Self::CF_TXS)?;
+        self.db
+            .get_pinned_cf(cf_txs, tx_hash.as_bytes())?
+            .map(|slice| SentTx::from_slice(&slice))
+            .transpose()
+    }
+
+    pub(crate) fn count_cells(&self) -> Result<u64> {
+        let cf_cells = self.cf_handle(Self::CF_CELLS)?;
+        let count = self
+            .db
+            .full_iterator_cf(cf_cells, rocksdb::IteratorMode::Start)
+            .count();
+        Ok(count as u64)
+    }
+
+    // Flush the memtables of all column families into the disk.
+    pub(crate) fn flush(&self) -> Result<()> {
+        self.db.flush()?;
+        for cf_name in Self::CF_NAMES {
+            let cf = self.cf_handle(cf_name)?;
+            self.db.flush_cf(cf)?;
+        }
         Ok(())
     }
 
@@ -184,3 +834,157 @@ impl Storage {
             .collect()
     }
 }
+
+fn block_key(block_number: core::BlockNumber, suffix: &[u8]) -> Vec<u8> {
+    let mut key = Vec::with_capacity(8 + suffix.len());
+    key.extend_from_slice(&block_number.to_be_bytes());
+    key.extend_from_slice(suffix);
+    key
+}
+
+// The keys of the synchronized blocks and the indexes start with the block numbers.
+fn key_block_number(key: &[u8]) -> Result<core::BlockNumber> {
+    if key.len() < 8 {
+        let errmsg = format!(
+            "the stored key should start with a block number, but got {} bytes",
+            key.len()
+        );
+        return Err(Error::storage(errmsg));
+    }
+    let mut number_be = [0u8; 8];
+    number_be.copy_from_slice(&key[0..8]);
+    Ok(core::BlockNumber::from_be_bytes(number_be))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn out_point(tx_seed: u8, index: u32) -> packed::OutPoint {
+        packed::OutPoint::new_builder()
+            .tx_hash(H256([tx_seed; 32]).pack())
+            .index(index.pack())
+            .build()
+    }
+
+    fn cell(block_number: core::BlockNumber) -> CellInfo {
+        CellInfo::new(core::Capacity::shannons(100), H256([0xa; 32]), block_number)
+    }
+
+    #[test]
+    fn rollback_and_prune_blocks() {
+        let dir = tempfile::tempdir().unwrap();
+        let stg = Storage::init(dir.path().join("data"), &Default::default()).unwrap();
+        let (a, b, c) = (out_point(1, 0), out_point(1, 1), out_point(2, 0));
+        let adds = vec![(a.clone(), cell(1)), (b.clone(), cell(1))];
+        stg.apply_block_changes(adds, Vec::new(), 1, H256([1; 32]))
+            .unwrap();
+        stg.spend_cells(std::slice::from_ref(&b), 100).unwrap();
+        stg.record_sent_tx(H256([4; 32]), std::slice::from_ref(&b), 1)
+            .unwrap();
+        let adds = vec![(c.clone(), cell(2))];
+        let removes = vec![a.clone(), b.clone()];
+        assert_eq!(
+            stg.apply_block_changes(adds, removes, 2, H256([2; 32]))
+                .unwrap(),
+            2
+        );
+        assert_eq!(stg.rollback_blocks(2).unwrap(), (1, 2));
+        let live = stg
+            .load_cells()
+            .unwrap()
+            .into_iter()
+            .map(|input| input.out_point)
+            .collect::<Vec<_>>();
+        // The spent cell is restored into the cache.
+        assert_eq!(live, vec![a]);
+        assert!(stg.has_cell(&b).unwrap());
+        assert!(!stg.has_cell(&c).unwrap());
+        assert_eq!(stg.get_next_number().unwrap(), Some(2));
+        assert_eq!(stg.get_block_hash(2).unwrap(), None);
+
+        stg.record_sent_tx(H256([5; 32]), &[], 2).unwrap();
+        for number in 2..=MAX_ROLLBACK_BLOCKS + 2 {
+            stg.put_prev_block(number, H256([3; 32])).unwrap();
+        }
+        assert_eq!(stg.get_block_hash(1).unwrap(), None);
+        assert!(stg.get_block_hash(2).unwrap().is_some());
+        // The sent transactions are pruned with the blocks.
+        assert!(stg.get_sent_tx(&H256([4; 32])).unwrap().is_none());
+        assert!(stg.get_sent_tx(&H256([5; 32])).unwrap().is_some());
+        // The index of the cells is pruned with the blocks.
+        assert_eq!(stg.rollback_blocks(0).unwrap(), (0, 0));
+    }
+
+    #[test]
+    fn write_into_a_temporary_copy() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data");
+        let (a, b) = (out_point(1, 0), out_point(1, 1));
+        {
+            let stg = Storage::init(&path, &Default::default()).unwrap();
+            stg.add_cell(a.clone(), cell(1)).unwrap();
+        }
+        let copy = Storage::load_temporary_copy(&path, &Default::default()).unwrap();
+        let copy_dir = copy.temporary.as_ref().unwrap().0.clone();
+        assert!(copy.has_cell(&a).unwrap());
+        copy.add_cell(b.clone(), cell(2)).unwrap();
+        copy.spend_cells(std::slice::from_ref(&a), 100).unwrap();
+        drop(copy);
+        assert!(!copy_dir.exists());
+        let stg = Storage::load_read_only(&path).unwrap();
+        let live = stg
+            .load_cells()
+            .unwrap()
+            .into_iter()
+            .map(|input| input.out_point)
+            .collect::<Vec<_>>();
+        assert_eq!(live, vec![a]);
+        assert_eq!(stg.get_stats().unwrap().txs, 0);
+    }
+
+    #[test]
+    fn retain_only_unspent_cells() {
+        let dir = tempfile::tempdir().unwrap();
+        let stg = Storage::init(dir.path().join("data"), &Default::default()).unwrap();
+        let (a, b, c) = (out_point(1, 0), out_point(1, 1), out_point(1, 2));
+        let adds = vec![
+            (a.clone(), cell(1)),
+            (b.clone(), cell(1)),
+            (c.clone(), cell(1)),
+        ];
+        stg.apply_block_changes(adds, Vec::new(), 1, H256([1; 32]))
+            .unwrap();
+        stg.spend_cells(std::slice::from_ref(&c), 100).unwrap();
+        let live = vec![a.clone()].into_iter().collect();
+        assert_eq!(stg.retain_cells(&live).unwrap(), 1);
+        assert!(stg.has_cell(&a).unwrap());
+        assert!(!stg.has_cell(&b).unwrap());
+        // The spent cell is not live, but it is kept in the cache.
+        assert!(stg.has_cell(&c).unwrap());
+        assert_eq!(stg.count_cells().unwrap(), 1);
+    }
+
+    #[test]
+    fn restore_the_removed_dead_cells() {
+        let dir = tempfile::tempdir().unwrap();
+        let stg = Storage::init(dir.path().join("data"), &Default::default()).unwrap();
+        let (a, b) = (out_point(1, 0), out_point(1, 1));
+        let adds = vec![(a.clone(), cell(1)), (b.clone(), cell(1))];
+        stg.apply_block_changes(adds, Vec::new(), 1, H256([1; 32]))
+            .unwrap();
+        stg.put_prev_block(2, H256([2; 32])).unwrap();
+        // The cell is spent by a block which is not synchronized yet.
+        stg.rm_cell(&a).unwrap();
+        assert!(!stg.has_cell(&a).unwrap());
+        stg.rm_cell(&a).unwrap();
+        // The block which spends it is orphaned, the cell is live again.
+        assert_eq!(stg.rollback_blocks(2).unwrap(), (0, 1));
+        assert!(stg.has_cell(&a).unwrap());
+        assert_eq!(stg.count_cells().unwrap(), 2);
+        // The cell is added by the rolled back block, nothing to restore.
+        stg.rm_cell(&b).unwrap();
+        assert_eq!(stg.rollback_blocks(1).unwrap(), (1, 0));
+        assert_eq!(stg.count_cells().unwrap(), 0);
+    }
+}
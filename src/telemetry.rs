@@ -0,0 +1,130 @@
+use std::time::SystemTime;
+
+use ckb_types::H256;
+#[cfg(feature = "otlp")]
+use opentelemetry::{
+    trace::{Span as _, TraceContextExt as _, Tracer as _},
+    Context, KeyValue,
+};
+use url::Url;
+
+use crate::error::{Error, Result};
+
+pub(crate) struct Telemetry {
+    #[cfg(feature = "otlp")]
+    tracer: Option<opentelemetry_sdk::trace::Tracer>,
+}
+
+// The span of a transaction, the stages of the transaction are its children.
+pub(crate) struct TxSpan {
+    #[cfg(feature = "otlp")]
+    cx: Option<Context>,
+}
+
+impl Telemetry {
+    #[cfg(feature = "otlp")]
+    pub(crate) fn new(endpoint: Option<&Url>) -> Result<Self> {
+        use opentelemetry_otlp::WithExportConfig as _;
+        let tracer = endpoint
+            .map(|endpoint| {
+                log::debug!("export traces to [{}]", endpoint);
+                let exporter = opentelemetry_otlp::new_exporter()
+                    .http()
+                    .with_endpoint(endpoint.as_str());
+                opentelemetry_otlp::new_pipeline()
+                    .tracing()
+                    .with_exporter(exporter)
+                    .install_simple()
+                    .map_err(|err| {
+                        let errmsg =
+                            format!("failed to initialize the OTLP exporter since {}", err);
+                        Error::config(errmsg)
+                    })
+            })
+            .transpose()?;
+        Ok(Self { tracer })
+    }
+
+    #[cfg(not(feature = "otlp"))]
+    pub(crate) fn new(endpoint: Option<&Url>) -> Result<Self> {
+        if endpoint.is_some() {
+            let errmsg = "the OTLP endpoint requires the feature \"otlp\" to be enabled";
+            Err(Error::config(errmsg))
+        } else {
+            Ok(Self {})
+        }
+    }
+
+    #[cfg(feature = "otlp")]
+    pub(crate) fn start_tx(&self, started_at: SystemTime) -> TxSpan {
+        let cx = self.tracer.as_ref().map(|tracer| {
+            let span = tracer
+                .span_builder("transaction")
+                .with_start_time(started_at)
+                .start(tracer);
+            Context::current_with_span(span)
+        });
+        TxSpan { cx }
+    }
+
+    #[cfg(not(feature = "otlp"))]
+    pub(crate) fn start_tx(&self, _started_at: SystemTime) -> TxSpan {
+        TxSpan {}
+    }
+
+    // Record a stage which has already finished.
+    #[cfg(feature = "otlp")]
+    pub(crate) fn record_stage(&self, tx: &TxSpan, name: &'static str, started_at: SystemTime) {
+        if let (Some(tracer), Some(cx)) = (&self.tracer, &tx.cx) {
+            tracer
+                .span_builder(name)
+                .with_start_time(started_at)
+                .start_with_context(tracer, cx)
+                .end();
+        }
+    }
+
+    #[cfg(not(feature = "otlp"))]
+    pub(crate) fn record_stage(&self, _tx: &TxSpan, _name: &'static str, _started_at: SystemTime) {}
+
+    pub(crate) fn stage<T, F: FnOnce() -> T>(&self, tx: &TxSpan, name: &'static str, f: F) -> T {
+        let started_at = SystemTime::now();
+        let result = f();
+        self.record_stage(tx, name, started_at);
+        result
+    }
+}
+
+impl TxSpan {
+    #[cfg(feature = "otlp")]
+    pub(crate) fn set_tx_hash(&self, tx_hash: &H256) {
+        if let Some(ref cx) = self.cx {
+            cx.span()
+                .set_attribute(KeyValue::new("tx_hash", format!("{:#x}", tx_hash)));
+        }
+    }
+
+    #[cfg(not(feature = "otlp"))]
+    pub(crate) fn set_tx_hash(&self, _tx_hash: &H256) {}
+
+    #[cfg(feature = "otlp")]
+    pub(crate) fn end(self) {
+        if let Some(cx) = self.cx {
+            cx.span().end();
+        }
+    }
+
+    #[cfg(not(feature = "otlp"))]
+    pub(crate) fn end(self) {}
+}
+
+impl Drop for Telemetry {
+    fn drop(&mut self) {
+        #[cfg(feature = "otlp")]
+        {
+            if self.tracer.is_some() {
+                opentelemetry::global::shutdown_tracer_provider();
+            }
+        }
+    }
+}
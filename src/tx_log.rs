@@ -0,0 +1,90 @@
+use std::{
+    fs,
+    io::{BufRead as _, BufReader, BufWriter, Write as _},
+    path::Path,
+};
+
+use ckb_jsonrpc_types as rpc;
+use ckb_types::packed;
+
+use crate::error::{Error, Result};
+
+// Records the signed transactions in the order they are handled, one JSON object per line, to
+// replay them later without constructing them again.
+//
+// Each line is flushed once it is written, so the file is complete whenever the process is
+// stopped.
+pub(crate) struct TxLog {
+    writer: Option<BufWriter<fs::File>>,
+}
+
+impl TxLog {
+    pub(crate) fn new(path: Option<&Path>) -> Result<Self> {
+        let writer = path
+            .map(|path| {
+                fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .map(BufWriter::new)
+                    .map_err(|err| {
+                        let errmsg = format!(
+                            "failed to open the transactions log [{}] since {}",
+                            path.display(),
+                            err
+                        );
+                        Error::config(errmsg)
+                    })
+            })
+            .transpose()?;
+        Ok(Self { writer })
+    }
+
+    pub(crate) fn record(&mut self, stx: &packed::Transaction) -> Result<()> {
+        let writer = if let Some(ref mut writer) = self.writer {
+            writer
+        } else {
+            return Ok(());
+        };
+        let stx_json: rpc::Transaction = stx.clone().into();
+        serde_json::to_writer(&mut *writer, &stx_json).map_err(Error::runtime)?;
+        writer.write_all(b"\n").map_err(Error::runtime)?;
+        writer.flush().map_err(Error::runtime)
+    }
+}
+
+// Read the transactions from a log, with the line numbers, empty lines are skipped.
+pub(crate) fn read(path: &Path) -> Result<impl Iterator<Item = Result<(usize, rpc::Transaction)>>> {
+    let file = fs::File::open(path).map_err(|err| {
+        let errmsg = format!(
+            "failed to open the transactions log [{}] since {}",
+            path.display(),
+            err
+        );
+        Error::config(errmsg)
+    })?;
+    let iter = BufReader::new(file)
+        .lines()
+        .enumerate()
+        .filter_map(|(index, line)| {
+            let line_number = index + 1;
+            let line = match line {
+                Ok(line) => line,
+                Err(err) => return Some(Err(Error::runtime(err))),
+            };
+            if line.trim().is_empty() {
+                return None;
+            }
+            let result = serde_json::from_str(&line)
+                .map(|tx| (line_number, tx))
+                .map_err(|err| {
+                    let errmsg = format!(
+                        "failed to parse the transaction at line {} of the log since {}",
+                        line_number, err
+                    );
+                    Error::config(errmsg)
+                });
+            Some(result)
+        });
+    Ok(iter)
+}
@@ -0,0 +1,212 @@
+use hmac::{Hmac, Mac as _, NewMac as _};
+use sha2::Sha512;
+
+use crate::error::{Error, Result};
+
+// The BIP44 path of CKB accounts, without the index of each address.
+pub(crate) const DEFAULT_HD_PATH: &str = "m/44'/309'/0'/0";
+
+const HARDENED_OFFSET: u32 = 1 << 31;
+const MNEMONIC_PBKDF2_ROUNDS: u32 = 2048;
+
+type HmacSha512 = Hmac<Sha512>;
+
+// An extended private key of BIP32.
+struct ExtendedKey {
+    secret_key: secp256k1::SecretKey,
+    chain_code: [u8; 32],
+}
+
+// Derive the secret keys of "{path}/0" to "{path}/{count - 1}" from a BIP32 seed.
+pub(crate) fn derive_secret_keys(seed: &[u8], path: &str, count: u32) -> Result<Vec<Vec<u8>>> {
+    let indexes = parse_path(path)?;
+    let mut parent = ExtendedKey::master(seed)?;
+    for index in indexes {
+        parent = parent.child(index)?;
+    }
+    (0..count)
+        .map(|index| {
+            parent
+                .child(index)
+                .map(|child| child.secret_key[..].to_vec())
+        })
+        .collect()
+}
+
+// Convert the mnemonic to a BIP32 seed, as BIP39 does.
+//
+// The words are not checked against the word list, and only ASCII words are supported since
+// they are not normalized.
+pub(crate) fn mnemonic_to_seed(mnemonic: &str, passphrase: &str) -> Result<Vec<u8>> {
+    if !mnemonic.is_ascii() || !passphrase.is_ascii() {
+        return Err(Error::config(
+            "only ASCII mnemonic and passphrase are supported",
+        ));
+    }
+    let mnemonic = mnemonic.split_whitespace().collect::<Vec<_>>().join(" ");
+    let salt = format!("mnemonic{}", passphrase);
+    let mut seed = vec![0u8; 64];
+    pbkdf2::pbkdf2::<HmacSha512>(
+        mnemonic.as_bytes(),
+        salt.as_bytes(),
+        MNEMONIC_PBKDF2_ROUNDS,
+        &mut seed,
+    );
+    Ok(seed)
+}
+
+fn parse_path(path: &str) -> Result<Vec<u32>> {
+    let mut parts = path.split('/');
+    if parts.next() != Some("m") {
+        let errmsg = format!("the HD path [{}] should start with \"m\"", path);
+        return Err(Error::config(errmsg));
+    }
+    parts
+        .map(|part| {
+            let (number, hardened) = match part.strip_suffix(|c| c == '\'' || c == 'h') {
+                Some(number) => (number, true),
+                None => (part, false),
+            };
+            match number.parse::<u32>() {
+                Ok(index) if index < HARDENED_OFFSET => Ok(if hardened {
+                    index + HARDENED_OFFSET
+                } else {
+                    index
+                }),
+                _ => {
+                    let errmsg = format!("the HD path [{}] has an invalid index {}", path, part);
+                    Err(Error::config(errmsg))
+                }
+            }
+        })
+        .collect()
+}
+
+fn hmac_sha512(key: &[u8], data: &[&[u8]]) -> [u8; 64] {
+    let mut mac = HmacSha512::new_from_slice(key).expect("HMAC accepts keys of any size");
+    for chunk in data {
+        mac.update(chunk);
+    }
+    let mut output = [0u8; 64];
+    output.copy_from_slice(&mac.finalize().into_bytes());
+    output
+}
+
+impl ExtendedKey {
+    fn master(seed: &[u8]) -> Result<Self> {
+        if seed.len() < 16 || seed.len() > 64 {
+            let errmsg = format!(
+                "the HD seed should be 16 to 64 bytes, but got {} bytes",
+                seed.len()
+            );
+            return Err(Error::config(errmsg));
+        }
+        let output = hmac_sha512(b"Bitcoin seed", &[seed]);
+        Self::from_output(&output)
+    }
+
+    fn child(&self, index: u32) -> Result<Self> {
+        let index_bytes = index.to_be_bytes();
+        let output = if index >= HARDENED_OFFSET {
+            hmac_sha512(
+                &self.chain_code,
+                &[&[0], &self.secret_key[..], &index_bytes],
+            )
+        } else {
+            let secp = secp256k1::Secp256k1::signing_only();
+            let pubkey = secp256k1::PublicKey::from_secret_key(&secp, &self.secret_key);
+            hmac_sha512(&self.chain_code, &[&pubkey.serialize(), &index_bytes])
+        };
+        let mut child = Self::from_output(&output)?;
+        child
+            .secret_key
+            .add_assign(&self.secret_key[..])
+            .map_err(|err| {
+                let errmsg = format!("failed to derive the HD child key {} since {}", index, err);
+                Error::config(errmsg)
+            })?;
+        Ok(child)
+    }
+
+    fn from_output(output: &[u8; 64]) -> Result<Self> {
+        let secret_key = secp256k1::SecretKey::from_slice(&output[..32]).map_err(|err| {
+            let errmsg = format!("failed to derive the HD key since {}", err);
+            Error::config(errmsg)
+        })?;
+        let mut chain_code = [0u8; 32];
+        chain_code.copy_from_slice(&output[32..]);
+        Ok(Self {
+            secret_key,
+            chain_code,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(input: &str) -> Vec<u8> {
+        let mut output = vec![0u8; input.len() / 2];
+        faster_hex::hex_decode(input.as_bytes(), &mut output).unwrap();
+        output
+    }
+
+    // The test vector 1 of BIP32.
+    #[test]
+    fn derive_bip32_test_vector() {
+        let seed = hex("000102030405060708090a0b0c0d0e0f");
+        let master = ExtendedKey::master(&seed).unwrap();
+        assert_eq!(
+            master.secret_key[..],
+            hex("e8f32e723decf4051aefac8e2c93c9c5b214313817cdb01a1494b917c8436b35")[..]
+        );
+        assert_eq!(
+            master.chain_code[..],
+            hex("873dff81c02f525623fd1fe5167eac3a55a049de3d314bb42ee227ffed37d508")[..]
+        );
+        let keys = derive_secret_keys(&seed, "m/0'", 2).unwrap();
+        // m/0'/1
+        assert_eq!(
+            keys[1],
+            hex("3c6cb8d0f6a264c91ea8b5030fadaa8e538b020f0a387421a12de9319dc93368")
+        );
+        let keys = derive_secret_keys(&seed, "m/0h/1/2'", 3).unwrap();
+        // m/0'/1/2'/2
+        assert_eq!(
+            keys[2],
+            hex("0f479245fb19a38a1954c5c7c0ebab2f9bdfd96a17563ef28a6a4b1a2a764ef4")
+        );
+    }
+
+    // The test vector of the Trezor implementation of BIP39.
+    #[test]
+    fn mnemonic_trezor_test_vector() {
+        let mnemonic = "abandon abandon abandon abandon abandon abandon abandon abandon abandon \
+                        abandon abandon about";
+        let seed = mnemonic_to_seed(mnemonic, "TREZOR").unwrap();
+        assert_eq!(
+            seed,
+            hex("c55257c360c07c72029aebc1b53c05ed0362ada38ead3e3e9efa3708e53495531f09a6987599d18264c1e1c92f2cf141630c7a3c4ab7c81b2f001698e7463b04")
+        );
+    }
+
+    #[test]
+    fn parse_hd_paths() {
+        assert_eq!(
+            parse_path(DEFAULT_HD_PATH).unwrap(),
+            vec![
+                44 + HARDENED_OFFSET,
+                309 + HARDENED_OFFSET,
+                HARDENED_OFFSET,
+                0
+            ]
+        );
+        assert_eq!(parse_path("m").unwrap(), Vec::<u32>::new());
+        assert!(parse_path("44'/309'/0'/0").is_err());
+        assert!(parse_path("m/2147483647'").is_ok());
+        assert!(parse_path("m/2147483648").is_err());
+        assert!(parse_path("m/2147483648'").is_err());
+        assert!(parse_path("m/-1").is_err());
+    }
+}
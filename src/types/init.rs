@@ -1,5 +1,12 @@
-use std::{collections::HashMap, fmt, result::Result as StdResult, str::FromStr};
+use std::{
+    collections::HashMap,
+    env, fmt,
+    path::{Path, PathBuf},
+    result::Result as StdResult,
+    str::FromStr,
+};
 
+use bech32::FromBase32 as _;
 use ckb_crypto::secp;
 use ckb_hash::new_blake2b;
 use ckb_jsonrpc_types as rpc;
@@ -7,15 +14,43 @@ use ckb_types::{bytes, core, packed, prelude::*, H256};
 use serde::{Deserialize, Serialize};
 use tiny_keccak::Hasher as _;
 
-use super::LockInfo;
+use super::{
+    decrypt_keystore, derive_secret_keys, mnemonic_to_seed, multisig::SIGNATURE_SIZE,
+    KeystorePassword, LockInfo, Multisig, MultisigConfig, RunEnv, SimRng, StorageConfig,
+    DEFAULT_HD_PATH, KEYSTORE_PASSWORD_ENV,
+};
 use crate::error::{Error, Result};
 
+pub(super) const SECRET_KEY_SIZE: usize = 32;
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(deny_unknown_fields)]
 pub(crate) struct MetaData {
-    pub(crate) start_block: BlockMeta,
+    pub(crate) start_block: StartBlock,
     pub(crate) lock_scripts: HashMap<LockScriptId, Script>,
     pub(crate) accounts: Vec<Account>,
+    // The accounts which are derived from HD seeds, they are after the accounts above.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) derived_accounts: Vec<DerivedAccounts>,
+    // Only to create the storage, it is never stored.
+    #[serde(default, skip_serializing)]
+    pub(crate) storage: StorageConfig,
+    // To decrypt the secret keys in keystores, it is never stored.
+    #[serde(skip)]
+    pub(crate) keystore_password: Option<KeystorePassword>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(untagged)]
+pub(crate) enum StartBlock {
+    Block(BlockMeta),
+    Epoch(EpochMeta),
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct EpochMeta {
+    pub(crate) epoch: core::EpochNumber,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -31,13 +66,73 @@ pub(crate) struct Script {
     pub(crate) code_hash: H256,
     pub(crate) hash_type: ScriptHashType,
     pub(crate) cell_deps: Vec<CellDep>,
+    // Only for the lock "anyone_can_pay".
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) acp: Option<AcpConfig>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct AcpConfig {
+    // The minimum transfer amount of CKBytes is 10^min_ckb shannons.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) min_ckb: Option<u8>,
+    // The minimum transfer amount of UDT is 10^min_udt, it requires "min_ckb".
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) min_udt: Option<u8>,
+    // Spend the cells without signatures, leave the witnesses blank, then an output with the same
+    // lock receives their capacity plus the minimum transfer amount of CKBytes.
+    #[serde(default)]
+    pub(crate) unsigned: bool,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(deny_unknown_fields)]
 pub(crate) struct Account {
-    pub(crate) secret_key: rpc::JsonBytes,
+    pub(crate) secret_key: SecretKey,
     pub(crate) lock_id: LockScriptId,
+    // Only for the lock "secp256k1_multisig".
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) multisig: Option<MultisigConfig>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(untagged)]
+pub(crate) enum SecretKey {
+    Inline(rpc::JsonBytes),
+    // An encrypted keystore file, in the Web3 Secret Storage format.
+    Keystore { keystore: PathBuf },
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct DerivedAccounts {
+    // Only one of "seed" and "mnemonic" could be set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) seed: Option<rpc::JsonBytes>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) mnemonic: Option<String>,
+    // Only for the mnemonic.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) passphrase: Option<String>,
+    // The index of each account is appended to the path.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) path: Option<String>,
+    pub(crate) count: u32,
+    pub(crate) lock_id: LockScriptId,
+}
+
+pub(crate) struct CsvAccount {
+    pub(crate) account: Account,
+    pub(crate) expected_address: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct CsvAccountRecord {
+    secret_key_hex: String,
+    lock_id: LockScriptId,
+    #[serde(default)]
+    expected_address: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -74,12 +169,104 @@ pub(crate) enum LockScriptId {
     Secp256K1Blake160,
     #[serde(rename = "pwlock-k1-acpl")]
     PwLockK1Acpl,
+    #[serde(rename = "secp256k1_multisig")]
+    Secp256K1Multisig,
+    #[serde(rename = "anyone_can_pay")]
+    AnyoneCanPay,
+}
+
+impl CsvAccount {
+    pub(crate) fn load_from_csv<P: AsRef<Path>>(path: P) -> Result<Vec<Self>> {
+        let path = path.as_ref();
+        let mut reader = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_path(path)
+            .map_err(|err| {
+                Error::config(format!("failed to open {} since {}", path.display(), err))
+            })?;
+        reader
+            .deserialize::<CsvAccountRecord>()
+            .enumerate()
+            .map(|(index, record)| {
+                let record = record.map_err(|err| {
+                    let errmsg = format!("failed to parse {} since {}", path.display(), err);
+                    Error::config(errmsg)
+                })?;
+                let secret_key = H256::from_str(record.secret_key_hex.trim_start_matches("0x"))
+                    .map_err(|err| {
+                        let errmsg = format!(
+                            "failed to parse the secret key in row {} of {} since {}",
+                            index + 1,
+                            path.display(),
+                            err
+                        );
+                        Error::config(errmsg)
+                    })?;
+                let account = Account {
+                    secret_key: SecretKey::Inline(rpc::JsonBytes::from_vec(
+                        secret_key.as_bytes().to_vec(),
+                    )),
+                    lock_id: record.lock_id,
+                    multisig: None,
+                };
+                let expected_address = record.expected_address.filter(|addr| !addr.is_empty());
+                Ok(Self {
+                    account,
+                    expected_address,
+                })
+            })
+            .collect()
+    }
+}
+
+fn check_address(address: &str, script: &packed::Script) -> Result<()> {
+    let (_, data, _) = bech32::decode(address)
+        .map_err(|err| Error::config(format!("invalid address {} since {}", address, err)))?;
+    let payload = Vec::<u8>::from_base32(&data)
+        .map_err(|err| Error::config(format!("invalid address {} since {}", address, err)))?;
+    let args = script.args().raw_data();
+    let code_hash = script.code_hash();
+    let hash_type = script.hash_type();
+    let matched = match payload.first() {
+        // Short format: the code hash is referred by an index, only the args are checked.
+        Some(0x01) if payload.len() > 2 => payload[2..] == args[..],
+        // Full format with the hash type "data" or "type".
+        Some(t @ 0x02) | Some(t @ 0x04) if payload.len() >= 33 => {
+            let expected_hash_type = if *t == 0x02 {
+                core::ScriptHashType::Data
+            } else {
+                core::ScriptHashType::Type
+            };
+            payload[1..33] == code_hash.as_slice()[..]
+                && hash_type == expected_hash_type.into()
+                && payload[33..] == args[..]
+        }
+        // Full format with an explicit hash type byte.
+        Some(0x00) if payload.len() >= 34 => {
+            payload[1..33] == code_hash.as_slice()[..]
+                && payload[33] == hash_type.as_slice()[0]
+                && payload[34..] == args[..]
+        }
+        _ => {
+            let errmsg = format!("unsupported address format {}", address);
+            return Err(Error::config(errmsg));
+        }
+    };
+    if matched {
+        Ok(())
+    } else {
+        let errmsg = format!(
+            "address {} does not match the lock script {}",
+            address, script
+        );
+        Err(Error::config(errmsg))
+    }
 }
 
 impl FromStr for MetaData {
     type Err = serde_yaml::Error;
     fn from_str(s: &str) -> StdResult<Self, Self::Err> {
-        serde_yaml::from_str(&s)
+        serde_yaml::from_str(s)
     }
 }
 
@@ -99,6 +286,19 @@ impl fmt::Display for LockScriptId {
     }
 }
 
+impl FromStr for LockScriptId {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "secp256k1_blake160" => Ok(Self::Secp256K1Blake160),
+            "pwlock-k1-acpl" => Ok(Self::PwLockK1Acpl),
+            "secp256k1_multisig" => Ok(Self::Secp256K1Multisig),
+            "anyone_can_pay" => Ok(Self::AnyoneCanPay),
+            _ => Err(Error::config(format!("unknown lock script {}", s))),
+        }
+    }
+}
+
 impl From<ScriptHashType> for core::ScriptHashType {
     fn from(input: ScriptHashType) -> core::ScriptHashType {
         match input {
@@ -129,30 +329,207 @@ impl From<DepType> for packed::Byte {
 }
 
 impl MetaData {
+    pub(crate) fn start_block(&self) -> Result<&BlockMeta> {
+        match self.start_block {
+            StartBlock::Block(ref block) => Ok(block),
+            StartBlock::Epoch(ref epoch) => {
+                let errmsg = format!(
+                    "the start block is specified by epoch#{}, but it is not resolved",
+                    epoch.epoch
+                );
+                Err(Error::config(errmsg))
+            }
+        }
+    }
+
     pub(crate) fn accounts(&self) -> Result<HashMap<H256, LockInfo>> {
-        self.accounts
+        self.all_accounts()?
             .iter()
-            .map(|account| {
-                let sk_bytes = account.clone().secret_key.into_bytes();
-                let id = account.lock_id;
-                let args = id.generate_args(&sk_bytes)?;
-                let lock_script = self.lock_scripts.get(&id).ok_or_else(|| {
-                    let errmsg = format!("lock scripts are not enough, requires {}", id);
-                    Error::config(errmsg)
-                })?;
-                let hash_type: core::ScriptHashType = lock_script.hash_type.into();
-                let script = packed::Script::new_builder()
-                    .args(args.pack())
-                    .code_hash(lock_script.code_hash.pack())
-                    .hash_type(hash_type.into())
-                    .build();
-                let hash: H256 = script.calc_script_hash().unpack();
-                let lock_info = LockInfo::new(id, script, sk_bytes);
-                Ok((hash, lock_info))
-            })
+            .enumerate()
+            .map(|(index, account)| self.account(index, account))
+            .collect()
+    }
+
+    // The accounts, then the derived accounts.
+    pub(crate) fn all_accounts(&self) -> Result<Vec<Account>> {
+        let mut accounts = self.accounts.clone();
+        for derived in &self.derived_accounts {
+            accounts.extend(derived.derive()?);
+        }
+        Ok(accounts)
+    }
+
+    // Check all accounts and lock scripts, return all problems instead of the first one.
+    pub(crate) fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if self.accounts.is_empty() && self.derived_accounts.is_empty() {
+            problems.push("no accounts".to_owned());
+        }
+        problems.extend(self.storage.problems());
+        for (id, script) in &self.lock_scripts {
+            if script.cell_deps.is_empty() {
+                problems.push(format!("lock script [{}] has no cell deps", id));
+            }
+            if let Err(err) = script.acp(*id) {
+                problems.push(err.to_string());
+            }
+        }
+        let mut accounts = self.accounts.clone();
+        for derived in &self.derived_accounts {
+            match derived.derive() {
+                Ok(derived) => accounts.extend(derived),
+                Err(err) => problems.push(err.to_string()),
+            }
+        }
+        for (index, account) in accounts.iter().enumerate() {
+            if let Err(err) = self.account(index, account) {
+                problems.push(err.to_string());
+            }
+        }
+        problems
+    }
+
+    // Check whether the config of run is consistent with the metadata, return all problems.
+    // The accounts are resolved already, since decrypting the keystores is slow.
+    pub(crate) fn run_env_problems(
+        &self,
+        run_env: &RunEnv,
+        accounts: &HashMap<H256, LockInfo>,
+    ) -> Vec<String> {
+        let mut problems = Vec::new();
+        if let Ok(start_block) = self.start_block() {
+            if let Err(err) = run_env.check_delay_blocks(start_block.number) {
+                problems.push(err.to_string());
+            }
+        }
+        let generator = &run_env.generator;
+        let mut weighted_ids = generator
+            .locks_weights
+            .iter()
+            .filter(|(_, weight)| **weight > 0)
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        weighted_ids.sort();
+        for id in weighted_ids {
+            if !self.lock_scripts.contains_key(&id) {
+                problems.push(format!(
+                    "lock script [{}] in the locks weights is not in the metadata",
+                    id
+                ));
+            }
+        }
+        if let Err(err) = generator.lock_generator(accounts, &SimRng::new(run_env.rng_seed)) {
+            problems.push(err.to_string());
+        }
+        let mut used_ids = accounts
+            .values()
+            .map(|lock_info| lock_info.id)
+            .collect::<Vec<_>>();
+        used_ids.sort();
+        used_ids.dedup();
+        let lock_deps_dict = self.lock_deps_dict();
+        for id in used_ids {
+            if lock_deps_dict.get(&id).map(Vec::is_empty).unwrap_or(true) {
+                problems.push(format!(
+                    "the lock script [{}] of accounts has no cell deps",
+                    id
+                ));
+            }
+        }
+        problems
+    }
+
+    // The accounts which could be resolved, the problems of the others are reported by
+    // "problems()".
+    pub(crate) fn valid_accounts(&self) -> HashMap<H256, LockInfo> {
+        self.all_accounts()
+            .unwrap_or_else(|_| self.accounts.clone())
+            .iter()
+            .enumerate()
+            .filter_map(|(index, account)| self.account(index, account).ok())
             .collect()
     }
 
+    fn account(&self, index: usize, account: &Account) -> Result<(H256, LockInfo)> {
+        let sk_bytes = account.secret_key_bytes(self.keystore_password().as_ref())?;
+        if sk_bytes.len() != SECRET_KEY_SIZE {
+            let errmsg = format!(
+                "the secret key of account#{} [{}] should be {} bytes, but got {} bytes",
+                index,
+                account.lock_id,
+                SECRET_KEY_SIZE,
+                sk_bytes.len()
+            );
+            return Err(Error::config(errmsg));
+        }
+        // The other secret keys of the multisig could be in keystores, so resolve it only once.
+        let multisig = account.multisig(&sk_bytes, self.keystore_password().as_ref())?;
+        let script = self.lock_script_with(account, &sk_bytes, multisig.as_ref())?;
+        let hash: H256 = script.calc_script_hash().unpack();
+        let acp = self.script_meta(account.lock_id)?.acp(account.lock_id)?;
+        let lock_info = LockInfo::new(account.lock_id, script, sk_bytes, multisig, acp.cloned());
+        Ok((hash, lock_info))
+    }
+
+    pub(crate) fn merge_csv_accounts(&mut self, csv_accounts: Vec<CsvAccount>) -> Result<()> {
+        for CsvAccount {
+            account,
+            expected_address,
+        } in csv_accounts
+        {
+            if let Some(address) = expected_address {
+                let script = self.lock_script(&account)?;
+                check_address(&address, &script)?;
+            }
+            self.accounts.push(account);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn lock_script(&self, account: &Account) -> Result<packed::Script> {
+        let password = self.keystore_password();
+        let sk_bytes = account.secret_key_bytes(password.as_ref())?;
+        let multisig = account.multisig(&sk_bytes, password.as_ref())?;
+        self.lock_script_with(account, &sk_bytes, multisig.as_ref())
+    }
+
+    fn lock_script_with(
+        &self,
+        account: &Account,
+        sk_bytes: &bytes::Bytes,
+        multisig: Option<&Multisig>,
+    ) -> Result<packed::Script> {
+        let id = account.lock_id;
+        let lock_script = self.script_meta(id)?;
+        let args = match multisig {
+            Some(multisig) => multisig.args(),
+            None => id.generate_args(sk_bytes, lock_script.acp(id)?)?,
+        };
+        let hash_type: core::ScriptHashType = lock_script.hash_type.into();
+        let script = packed::Script::new_builder()
+            .args(args.pack())
+            .code_hash(lock_script.code_hash.pack())
+            .hash_type(hash_type.into())
+            .build();
+        Ok(script)
+    }
+
+    // The password from the arguments, or from the environment variable.
+    fn keystore_password(&self) -> Option<KeystorePassword> {
+        self.keystore_password.clone().or_else(|| {
+            env::var(KEYSTORE_PASSWORD_ENV)
+                .ok()
+                .map(KeystorePassword::new)
+        })
+    }
+
+    fn script_meta(&self, id: LockScriptId) -> Result<&Script> {
+        self.lock_scripts.get(&id).ok_or_else(|| {
+            let errmsg = format!("lock scripts are not enough, requires {}", id);
+            Error::config(errmsg)
+        })
+    }
+
     pub(crate) fn lock_deps_dict(&self) -> HashMap<LockScriptId, Vec<packed::CellDep>> {
         self.lock_scripts
             .iter()
@@ -182,25 +559,129 @@ impl Pack<packed::CellDep> for CellDep {
     }
 }
 
+impl SecretKey {
+    pub(crate) fn to_bytes(&self, password: Option<&KeystorePassword>) -> Result<bytes::Bytes> {
+        match self {
+            Self::Inline(ref secret_key) => Ok(secret_key.clone().into_bytes()),
+            Self::Keystore { ref keystore } => {
+                let password = password.ok_or_else(|| {
+                    let errmsg = format!(
+                        "the password of keystore {} is required, by \"--keystore-password\" \
+                        or the environment variable {}",
+                        keystore.display(),
+                        KEYSTORE_PASSWORD_ENV
+                    );
+                    Error::config(errmsg)
+                })?;
+                decrypt_keystore(keystore, password.as_str()).map(Into::into)
+            }
+        }
+    }
+}
+
+impl Account {
+    fn secret_key_bytes(&self, password: Option<&KeystorePassword>) -> Result<bytes::Bytes> {
+        self.secret_key.to_bytes(password)
+    }
+
+    fn multisig(
+        &self,
+        sk_bytes: &bytes::Bytes,
+        password: Option<&KeystorePassword>,
+    ) -> Result<Option<Multisig>> {
+        match (self.lock_id, &self.multisig) {
+            (LockScriptId::Secp256K1Multisig, Some(config)) => {
+                config.resolve(sk_bytes, password).map(Some)
+            }
+            (_, None) => Ok(None),
+            (lock_id, Some(_)) => {
+                let errmsg = format!(
+                    "the multisig config is only for the lock {}, but the lock is {}",
+                    LockScriptId::Secp256K1Multisig,
+                    lock_id
+                );
+                Err(Error::config(errmsg))
+            }
+        }
+    }
+}
+
+impl DerivedAccounts {
+    fn derive(&self) -> Result<Vec<Account>> {
+        let seed = match (&self.seed, &self.mnemonic, &self.passphrase) {
+            (Some(seed), None, None) => seed.as_bytes().to_vec(),
+            (None, Some(mnemonic), passphrase) => {
+                mnemonic_to_seed(mnemonic, passphrase.as_deref().unwrap_or(""))?
+            }
+            _ => {
+                return Err(Error::config(
+                    "only one of \"seed\" and \"mnemonic\" of the derived accounts should be \
+                    set, and \"passphrase\" is only for \"mnemonic\"",
+                ));
+            }
+        };
+        let path = self.path.as_deref().unwrap_or(DEFAULT_HD_PATH);
+        let accounts = derive_secret_keys(&seed, path, self.count)?
+            .into_iter()
+            .map(|secret_key| Account {
+                secret_key: SecretKey::Inline(rpc::JsonBytes::from_vec(secret_key)),
+                lock_id: self.lock_id,
+                multisig: None,
+            })
+            .collect();
+        Ok(accounts)
+    }
+}
+
+impl Script {
+    fn acp(&self, id: LockScriptId) -> Result<Option<&AcpConfig>> {
+        match (id, &self.acp) {
+            (LockScriptId::AnyoneCanPay, Some(config)) => {
+                if config.min_udt.is_some() && config.min_ckb.is_none() {
+                    let errmsg = format!("the lock {} requires \"min_ckb\" for \"min_udt\"", id);
+                    return Err(Error::config(errmsg));
+                }
+                Ok(Some(config))
+            }
+            (_, None) => Ok(None),
+            (id, Some(_)) => {
+                let errmsg = format!(
+                    "the acp config is only for the lock {}, but the lock is {}",
+                    LockScriptId::AnyoneCanPay,
+                    id
+                );
+                Err(Error::config(errmsg))
+            }
+        }
+    }
+}
+
 impl LockScriptId {
-    pub(crate) fn generate_args(self, sk_slice: &[u8]) -> Result<Vec<u8>> {
+    pub(crate) fn generate_args(self, sk_slice: &[u8], acp: Option<&AcpConfig>) -> Result<Vec<u8>> {
+        let pk = secp::Privkey::from_slice(sk_slice).pubkey()?;
+        self.pubkey_to_args(&pk, acp)
+    }
+
+    pub(crate) fn pubkey_to_args(
+        self,
+        pk: &secp::Pubkey,
+        acp: Option<&AcpConfig>,
+    ) -> Result<Vec<u8>> {
         let v = match self {
-            Self::Secp256K1Blake160 => {
-                let pk = secp::Privkey::from_slice(sk_slice).pubkey()?;
+            Self::Secp256K1Blake160 | Self::AnyoneCanPay => {
                 let data = pk.serialize();
                 {
                     let mut result = [0u8; 32];
                     let mut hasher = new_blake2b();
                     hasher.update(&data[..]);
                     hasher.finalize(&mut result);
-                    (&result[..20]).to_vec()
+                    result[..20].to_vec()
                 }
             }
             Self::PwLockK1Acpl => {
-                let pk = secp::Privkey::from_slice(sk_slice).pubkey()?;
                 let data = {
                     let mut temp = [4u8; 65];
-                    temp[1..65].copy_from_slice(&pk.as_bytes());
+                    temp[1..65].copy_from_slice(pk.as_bytes());
                     let pk_raw =
                         secp256k1::PublicKey::from_slice(&temp).map_err(secp::Error::from)?;
                     Vec::from(&pk_raw.serialize_uncompressed()[1..])
@@ -210,39 +691,63 @@ impl LockScriptId {
                     let mut hasher = tiny_keccak::Keccak::v256();
                     hasher.update(&data);
                     hasher.finalize(&mut result);
-                    (&result[12..]).to_vec()
+                    result[12..].to_vec()
                 }
             }
+            Self::Secp256K1Multisig => return Err(multisig_config_required()),
+        };
+        // The args of "anyone_can_pay" are optionally followed by the minimum transfer amounts.
+        let v = match acp {
+            Some(config) if self == Self::AnyoneCanPay => {
+                let mut v = v;
+                v.extend(config.min_ckb.iter().chain(config.min_udt.iter()));
+                v
+            }
+            _ => v,
         };
         Ok(v)
     }
 
-    pub(crate) fn sign(self, sk_slice: &[u8], data: &[u8]) -> Result<Vec<u8>> {
-        let signature = match self {
-            Self::Secp256K1Blake160 => {
-                let sk = secp::Privkey::from_slice(sk_slice);
-                let message = {
-                    let blank_signature = bytes::Bytes::from(vec![0u8; 65]);
-                    let witness_blank = packed::WitnessArgs::new_builder()
-                        .lock(Some(blank_signature).pack())
-                        .build();
-                    let witness_empty_len = witness_blank.as_bytes().len() as u64;
+    // The length of the lock in the witness after signed, the lock is filled with zeros of this
+    // length to compute the message to sign.
+    pub(crate) fn blank_witness_lock_len(self) -> Result<usize> {
+        match self {
+            Self::Secp256K1Blake160 | Self::AnyoneCanPay | Self::PwLockK1Acpl => Ok(SIGNATURE_SIZE),
+            // It depends on the count of signers.
+            Self::Secp256K1Multisig => Err(multisig_config_required()),
+        }
+    }
 
-                    let mut message = [0u8; 32];
-                    let mut hasher = new_blake2b();
-                    hasher.update(data);
-                    hasher.update(&witness_empty_len.to_le_bytes());
-                    hasher.update(&witness_blank.as_bytes());
-                    hasher.finalize(&mut message);
-                    message
-                };
-                sk.sign_recoverable(&message.into())
-                    .map(|sig| sig.serialize())?
+    // The message to sign, for the witnesses of a lock group.
+    pub(crate) fn signing_message(
+        self,
+        data: &[u8],
+        extra_witnesses: &[bytes::Bytes],
+    ) -> Result<[u8; 32]> {
+        let message = match self {
+            Self::Secp256K1Blake160 | Self::AnyoneCanPay => {
+                let blank_signature = bytes::Bytes::from(vec![0u8; self.blank_witness_lock_len()?]);
+                let witness_blank = packed::WitnessArgs::new_builder()
+                    .lock(Some(blank_signature).pack())
+                    .build();
+                let witness_empty_len = witness_blank.as_bytes().len() as u64;
+
+                let mut message = [0u8; 32];
+                let mut hasher = new_blake2b();
+                hasher.update(data);
+                hasher.update(&witness_empty_len.to_le_bytes());
+                hasher.update(&witness_blank.as_bytes());
+                for witness in extra_witnesses {
+                    hasher.update(&(witness.len() as u64).to_le_bytes());
+                    hasher.update(witness);
+                }
+                hasher.finalize(&mut message);
+                message
             }
             Self::PwLockK1Acpl => {
-                let sk = secp::Privkey::from_slice(sk_slice);
                 let message_raw = {
-                    let blank_signature = bytes::Bytes::from(vec![0u8; 65]);
+                    let blank_signature =
+                        bytes::Bytes::from(vec![0u8; self.blank_witness_lock_len()?]);
                     let witness_blank = packed::WitnessArgs::new_builder()
                         .lock(Some(blank_signature).pack())
                         .build();
@@ -253,22 +758,44 @@ impl LockScriptId {
                     hasher.update(data);
                     hasher.update(&witness_empty_len.to_le_bytes());
                     hasher.update(&witness_blank.as_bytes());
+                    for witness in extra_witnesses {
+                        hasher.update(&(witness.len() as u64).to_le_bytes());
+                        hasher.update(witness);
+                    }
                     hasher.finalize(&mut result);
                     result
                 };
-                let message = {
-                    let mut result = [0u8; 32];
-                    let mut hasher = tiny_keccak::Keccak::v256();
-                    let prefix = format!("\x19Ethereum Signed Message:\n{}", result.len());
-                    hasher.update(&prefix.as_bytes());
-                    hasher.update(&message_raw);
-                    hasher.finalize(&mut result);
-                    result
-                };
-                sk.sign_recoverable(&message.into())
-                    .map(|sig| sig.serialize())?
+                let mut result = [0u8; 32];
+                let mut hasher = tiny_keccak::Keccak::v256();
+                let prefix = format!("\x19Ethereum Signed Message:\n{}", result.len());
+                hasher.update(prefix.as_bytes());
+                hasher.update(&message_raw);
+                hasher.finalize(&mut result);
+                result
             }
+            Self::Secp256K1Multisig => return Err(multisig_config_required()),
         };
-        Ok(signature)
+        Ok(message)
     }
+
+    pub(crate) fn sign(
+        self,
+        sk_slice: &[u8],
+        data: &[u8],
+        extra_witnesses: &[bytes::Bytes],
+    ) -> Result<Vec<u8>> {
+        let sk = secp::Privkey::from_slice(sk_slice);
+        let message = self.signing_message(data, extra_witnesses)?;
+        sk.sign_recoverable(&message.into())
+            .map(|sig| sig.serialize())
+            .map_err(Into::into)
+    }
+}
+
+fn multisig_config_required() -> Error {
+    let errmsg = format!(
+        "the lock {} requires the multisig config",
+        LockScriptId::Secp256K1Multisig
+    );
+    Error::config(errmsg)
 }
@@ -0,0 +1,195 @@
+use std::{fmt, fs, path::Path};
+
+use aes::cipher::{NewCipher as _, StreamCipher as _};
+use serde::Deserialize;
+use tiny_keccak::Hasher as _;
+
+use super::SECRET_KEY_SIZE;
+use crate::error::{Error, Result};
+
+// The environment variable to provide the password of keystores.
+pub(crate) const KEYSTORE_PASSWORD_ENV: &str = "CKB_SIMULATOR_KEYSTORE_PASSWORD";
+
+type Aes128Ctr = ctr::Ctr128BE<aes::Aes128>;
+
+// Never print the password.
+#[derive(Clone)]
+pub(crate) struct KeystorePassword(String);
+
+// The Web3 Secret Storage format (version 3), only the fields which are required.
+#[derive(Deserialize)]
+struct Keystore {
+    #[serde(alias = "Crypto")]
+    crypto: KeystoreCrypto,
+}
+
+#[derive(Deserialize)]
+struct KeystoreCrypto {
+    cipher: String,
+    cipherparams: CipherParams,
+    ciphertext: String,
+    kdf: String,
+    kdfparams: ScryptParams,
+    mac: String,
+}
+
+#[derive(Deserialize)]
+struct CipherParams {
+    iv: String,
+}
+
+#[derive(Deserialize)]
+struct ScryptParams {
+    dklen: usize,
+    n: u64,
+    r: u32,
+    p: u32,
+    salt: String,
+}
+
+// Decrypt the secret key from a keystore file.
+pub(crate) fn decrypt_keystore(path: &Path, password: &str) -> Result<Vec<u8>> {
+    let content = fs::read_to_string(path).map_err(|err| {
+        let errmsg = format!("failed to read keystore {} since {}", path.display(), err);
+        Error::config(errmsg)
+    })?;
+    let keystore: Keystore = serde_json::from_str(&content).map_err(|err| {
+        let errmsg = format!("failed to parse keystore {} since {}", path.display(), err);
+        Error::config(errmsg)
+    })?;
+    keystore.crypto.decrypt(password).map_err(|err| {
+        let errmsg = format!(
+            "failed to decrypt keystore {} since {}",
+            path.display(),
+            err
+        );
+        Error::config(errmsg)
+    })
+}
+
+impl KeystorePassword {
+    pub(crate) fn new(password: String) -> Self {
+        Self(password)
+    }
+
+    pub(crate) fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for KeystorePassword {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "KeystorePassword(..)")
+    }
+}
+
+impl KeystoreCrypto {
+    fn decrypt(&self, password: &str) -> std::result::Result<Vec<u8>, String> {
+        if self.cipher != "aes-128-ctr" {
+            return Err(format!("unsupported cipher {}", self.cipher));
+        }
+        if self.kdf != "scrypt" {
+            return Err(format!("unsupported kdf {}", self.kdf));
+        }
+        let params = &self.kdfparams;
+        if params.dklen != 32 || !params.n.is_power_of_two() {
+            return Err("invalid scrypt params".to_owned());
+        }
+        let log_n = params.n.trailing_zeros() as u8;
+        let scrypt_params =
+            scrypt::Params::new(log_n, params.r, params.p).map_err(|err| err.to_string())?;
+        let salt = decode_hex(&params.salt)?;
+        let mut derived_key = [0u8; 32];
+        scrypt::scrypt(password.as_bytes(), &salt, &scrypt_params, &mut derived_key)
+            .map_err(|err| err.to_string())?;
+
+        let mut ciphertext = decode_hex(&self.ciphertext)?;
+        let mut mac = [0u8; 32];
+        let mut hasher = tiny_keccak::Keccak::v256();
+        hasher.update(&derived_key[16..32]);
+        hasher.update(&ciphertext);
+        hasher.finalize(&mut mac);
+        if mac[..] != decode_hex(&self.mac)?[..] {
+            return Err("the password is wrong".to_owned());
+        }
+
+        let iv = decode_hex(&self.cipherparams.iv)?;
+        if iv.len() != 16 {
+            return Err(format!(
+                "the iv should be 16 bytes, but got {} bytes",
+                iv.len()
+            ));
+        }
+        let mut cipher = Aes128Ctr::new(derived_key[..16].into(), iv[..].into());
+        cipher.apply_keystream(&mut ciphertext);
+        if ciphertext.len() != SECRET_KEY_SIZE {
+            return Err(format!(
+                "the secret key should be {} bytes, but got {} bytes",
+                SECRET_KEY_SIZE,
+                ciphertext.len()
+            ));
+        }
+        Ok(ciphertext)
+    }
+}
+
+fn decode_hex(input: &str) -> std::result::Result<Vec<u8>, String> {
+    let input = input.trim_start_matches("0x").as_bytes();
+    if !input.len().is_multiple_of(2) {
+        return Err("the length of the hex string should be even".to_owned());
+    }
+    let mut output = vec![0u8; input.len() / 2];
+    faster_hex::hex_decode(input, &mut output).map_err(|err| err.to_string())?;
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Encrypt the secret key with light scrypt params, as same as the keystores of the wallets.
+    fn encrypt(secret_key: &[u8], password: &str) -> String {
+        let salt = [1u8; 32];
+        let iv = [2u8; 16];
+        let mut derived_key = [0u8; 32];
+        let scrypt_params = scrypt::Params::new(4, 8, 1).unwrap();
+        scrypt::scrypt(password.as_bytes(), &salt, &scrypt_params, &mut derived_key).unwrap();
+        let mut ciphertext = secret_key.to_vec();
+        let mut cipher = Aes128Ctr::new(derived_key[..16].into(), iv[..].into());
+        cipher.apply_keystream(&mut ciphertext);
+        let mut mac = [0u8; 32];
+        let mut hasher = tiny_keccak::Keccak::v256();
+        hasher.update(&derived_key[16..32]);
+        hasher.update(&ciphertext);
+        hasher.finalize(&mut mac);
+        let hex = |bytes: &[u8]| faster_hex::hex_string(bytes).unwrap();
+        serde_json::json!({
+            "version": 3,
+            "crypto": {
+                "cipher": "aes-128-ctr",
+                "cipherparams": { "iv": hex(&iv) },
+                "ciphertext": hex(&ciphertext),
+                "kdf": "scrypt",
+                "kdfparams": { "dklen": 32, "n": 16, "r": 8, "p": 1, "salt": hex(&salt) },
+                "mac": hex(&mac),
+            },
+        })
+        .to_string()
+    }
+
+    #[test]
+    fn decrypt_with_the_password() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("keystore.json");
+        let secret_key = [0x11u8; SECRET_KEY_SIZE];
+        fs::write(&path, encrypt(&secret_key, "password")).unwrap();
+        assert_eq!(
+            decrypt_keystore(&path, "password").unwrap(),
+            secret_key.to_vec()
+        );
+        let err = decrypt_keystore(&path, "wrong password").unwrap_err();
+        assert!(err.to_string().contains("the password is wrong"));
+        let err = decrypt_keystore(&dir.path().join("missing.json"), "password").unwrap_err();
+        assert!(err.to_string().contains("failed to read keystore"));
+    }
+}
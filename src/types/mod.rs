@@ -1,14 +1,22 @@
+mod hd;
 mod init;
+mod keystore;
+mod multisig;
+mod profile;
 mod run;
 mod storage;
 
+pub(crate) use hd::*;
 pub(crate) use init::*;
+pub(crate) use keystore::*;
+pub(crate) use multisig::*;
 pub(crate) use run::*;
 pub(crate) use storage::*;
 
+use ckb_crypto::secp;
 use ckb_types::{bytes, packed};
 
-use crate::error::Result;
+use crate::error::{Error, Result};
 
 #[derive(Debug, Clone)]
 pub(crate) struct InputInfo {
@@ -21,6 +29,8 @@ pub(crate) struct LockInfo {
     pub(crate) id: LockScriptId,
     pub(crate) script: packed::Script,
     pub(crate) secret_key: bytes::Bytes,
+    pub(crate) multisig: Option<Multisig>,
+    pub(crate) acp: Option<AcpConfig>,
 }
 
 impl InputInfo {
@@ -33,15 +43,70 @@ impl InputInfo {
 }
 
 impl LockInfo {
-    pub(crate) fn new(id: LockScriptId, script: packed::Script, secret_key: bytes::Bytes) -> Self {
+    pub(crate) fn new(
+        id: LockScriptId,
+        script: packed::Script,
+        secret_key: bytes::Bytes,
+        multisig: Option<Multisig>,
+        acp: Option<AcpConfig>,
+    ) -> Self {
         Self {
             id,
             script,
             secret_key,
+            multisig,
+            acp,
         }
     }
 
-    pub(crate) fn sign(&self, data: &[u8]) -> Result<Vec<u8>> {
-        self.id.sign(&self.secret_key, data)
+    // The "anyone_can_pay" lock could be spent without signatures, then the witness is blank.
+    pub(crate) fn is_unsigned(&self) -> bool {
+        self.acp.as_ref().map(|acp| acp.unsigned).unwrap_or(false)
+    }
+
+    // The size of the lock in the witness after signed.
+    pub(crate) fn lock_size(&self) -> usize {
+        match self.multisig {
+            Some(ref multisig) => multisig.lock_size(),
+            // An account of the multisig lock always has the multisig config.
+            None => self
+                .id
+                .blank_witness_lock_len()
+                .unwrap_or(multisig::SIGNATURE_SIZE),
+        }
+    }
+
+    pub(crate) fn sign(&self, data: &[u8], extra_witnesses: &[bytes::Bytes]) -> Result<Vec<u8>> {
+        if let Some(ref multisig) = self.multisig {
+            multisig.sign(data, extra_witnesses)
+        } else {
+            self.id.sign(&self.secret_key, data, extra_witnesses)
+        }
+    }
+
+    // Check the lock in the witness is signed by the owner of the lock script.
+    pub(crate) fn verify(
+        &self,
+        data: &[u8],
+        extra_witnesses: &[bytes::Bytes],
+        lock: &[u8],
+    ) -> Result<()> {
+        let args = self.script.args().raw_data();
+        if let Some(ref multisig) = self.multisig {
+            if multisig.args() != args {
+                return Err(Error::crypto(
+                    "the multisig script doesn't match the lock args",
+                ));
+            }
+            return multisig.verify(data, extra_witnesses, lock);
+        }
+        let message = self.id.signing_message(data, extra_witnesses)?;
+        let pk = secp::Signature::from_slice(lock)?.recover(&message.into())?;
+        if self.id.pubkey_to_args(&pk, self.acp.as_ref())? != args {
+            return Err(Error::crypto(
+                "the recovered pubkey doesn't match the lock args",
+            ));
+        }
+        Ok(())
     }
 }
@@ -0,0 +1,271 @@
+use ckb_crypto::secp;
+use ckb_hash::{blake2b_256, new_blake2b};
+use ckb_types::{bytes, packed, prelude::*, H160};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    init::{SecretKey, SECRET_KEY_SIZE},
+    KeystorePassword,
+};
+use crate::error::{Error, Result};
+
+pub(super) const SIGNATURE_SIZE: usize = 65;
+
+// The config of an account with the secp256k1 multisig lock.
+//
+// The multisig script is "S | R | M | N | blake160(pubkey_1) | ... | blake160(pubkey_N)", where
+// "S" is always 0, "R" is "require_first_n", "M" is "threshold" and "N" is the count of all
+// pubkeys.
+//
+// The pubkeys are the ones of the account's secret key and "other_secret_keys" in order, then
+// the hashes in "other_pubkey_hashes" are appended, since those members never sign.
+// As same as the secret key of an account, each one of "other_secret_keys" is inline or loaded
+// from a keystore.
+// The first "M" secret keys sign the transactions, so the lock of the witness is the multisig
+// script followed by "M" signatures of 65 bytes.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct MultisigConfig {
+    #[serde(default)]
+    pub(crate) require_first_n: u8,
+    pub(crate) threshold: u8,
+    #[serde(default)]
+    pub(crate) other_secret_keys: Vec<SecretKey>,
+    #[serde(default)]
+    pub(crate) other_pubkey_hashes: Vec<H160>,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Multisig {
+    script: bytes::Bytes,
+    signers: Vec<bytes::Bytes>,
+}
+
+impl MultisigConfig {
+    pub(crate) fn resolve(
+        &self,
+        secret_key: &bytes::Bytes,
+        password: Option<&KeystorePassword>,
+    ) -> Result<Multisig> {
+        let mut secret_keys = vec![secret_key.clone()];
+        for (index, sk) in self.other_secret_keys.iter().enumerate() {
+            let sk_bytes = sk.to_bytes(password)?;
+            if sk_bytes.len() != SECRET_KEY_SIZE {
+                let errmsg = format!(
+                    "the other secret key#{} of the multisig should be {} bytes, but got {} bytes",
+                    index,
+                    SECRET_KEY_SIZE,
+                    sk_bytes.len()
+                );
+                return Err(Error::config(errmsg));
+            }
+            secret_keys.push(sk_bytes);
+        }
+        let mut pubkey_hashes = Vec::new();
+        for sk in &secret_keys {
+            let pk = secp::Privkey::from_slice(sk).pubkey()?;
+            pubkey_hashes.push(blake2b_256(pk.serialize())[..20].to_vec());
+        }
+        for hash in &self.other_pubkey_hashes {
+            pubkey_hashes.push(hash.as_bytes().to_vec());
+        }
+        let count = pubkey_hashes.len();
+        if count > usize::from(u8::MAX) {
+            let errmsg = format!("the multisig has {} pubkeys, at most 255", count);
+            return Err(Error::config(errmsg));
+        }
+        if self.threshold == 0 || usize::from(self.threshold) > count {
+            let errmsg = format!(
+                "the threshold ({}) of the multisig should be in [1, {}]",
+                self.threshold, count
+            );
+            return Err(Error::config(errmsg));
+        }
+        if self.require_first_n > self.threshold {
+            let errmsg = format!(
+                "the require_first_n ({}) of the multisig should not be greater than the \
+                threshold ({})",
+                self.require_first_n, self.threshold
+            );
+            return Err(Error::config(errmsg));
+        }
+        if secret_keys.len() < usize::from(self.threshold) {
+            let errmsg = format!(
+                "the multisig requires {} signatures, but only has {} secret keys",
+                self.threshold,
+                secret_keys.len()
+            );
+            return Err(Error::config(errmsg));
+        }
+        let mut script = vec![0, self.require_first_n, self.threshold, count as u8];
+        for hash in pubkey_hashes {
+            script.extend_from_slice(&hash);
+        }
+        secret_keys.truncate(usize::from(self.threshold));
+        Ok(Multisig {
+            script: script.into(),
+            signers: secret_keys,
+        })
+    }
+}
+
+impl Multisig {
+    pub(crate) fn args(&self) -> Vec<u8> {
+        blake2b_256(&self.script)[..20].to_vec()
+    }
+
+    pub(crate) fn lock_size(&self) -> usize {
+        self.script.len() + SIGNATURE_SIZE * self.signers.len()
+    }
+
+    pub(crate) fn sign(&self, data: &[u8], extra_witnesses: &[bytes::Bytes]) -> Result<Vec<u8>> {
+        let message = self.signing_message(data, extra_witnesses);
+        let mut lock = Vec::with_capacity(self.lock_size());
+        lock.extend_from_slice(&self.script);
+        for sk in &self.signers {
+            let signature = secp::Privkey::from_slice(sk).sign_recoverable(&message.into())?;
+            lock.extend_from_slice(&signature.serialize());
+        }
+        Ok(lock)
+    }
+
+    // Check the lock is the multisig script followed by the signatures of its members.
+    pub(crate) fn verify(
+        &self,
+        data: &[u8],
+        extra_witnesses: &[bytes::Bytes],
+        lock: &[u8],
+    ) -> Result<()> {
+        if lock.len() != self.lock_size() || !lock.starts_with(&self.script) {
+            return Err(Error::crypto(
+                "the lock is not the multisig script with signatures",
+            ));
+        }
+        let message = self.signing_message(data, extra_witnesses);
+        let pubkey_hashes = self.script[4..].chunks(20).collect::<Vec<_>>();
+        let mut signed = vec![false; pubkey_hashes.len()];
+        for signature in lock[self.script.len()..].chunks(SIGNATURE_SIZE) {
+            let pk = secp::Signature::from_slice(signature)?.recover(&message.into())?;
+            let pubkey_hash = &blake2b_256(pk.serialize())[..20];
+            let index = pubkey_hashes
+                .iter()
+                .position(|hash| *hash == pubkey_hash)
+                .ok_or_else(|| Error::crypto("a signer is not a member of the multisig"))?;
+            if signed[index] {
+                let errmsg = format!("the member#{} of the multisig signs more than once", index);
+                return Err(Error::crypto(errmsg));
+            }
+            signed[index] = true;
+        }
+        let require_first_n = usize::from(self.script[1]);
+        if let Some(index) = signed[..require_first_n].iter().position(|done| !done) {
+            let errmsg = format!(
+                "the member#{} of the multisig is required to sign, since the first {} members \
+                are required",
+                index, require_first_n
+            );
+            return Err(Error::crypto(errmsg));
+        }
+        Ok(())
+    }
+
+    fn signing_message(&self, data: &[u8], extra_witnesses: &[bytes::Bytes]) -> [u8; 32] {
+        let blank_lock = bytes::Bytes::from(vec![0u8; self.lock_size()]);
+        let witness_blank = packed::WitnessArgs::new_builder()
+            .lock(Some(blank_lock).pack())
+            .build();
+        let witness_empty_len = witness_blank.as_bytes().len() as u64;
+
+        let mut message = [0u8; 32];
+        let mut hasher = new_blake2b();
+        hasher.update(data);
+        hasher.update(&witness_empty_len.to_le_bytes());
+        hasher.update(&witness_blank.as_bytes());
+        for witness in extra_witnesses {
+            hasher.update(&(witness.len() as u64).to_le_bytes());
+            hasher.update(witness);
+        }
+        hasher.finalize(&mut message);
+        message
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ckb_jsonrpc_types as rpc;
+
+    use super::*;
+
+    fn config(require_first_n: u8, threshold: u8) -> MultisigConfig {
+        MultisigConfig {
+            require_first_n,
+            threshold,
+            other_secret_keys: vec![
+                SecretKey::Inline(rpc::JsonBytes::from_vec(vec![0x22; SECRET_KEY_SIZE])),
+                SecretKey::Inline(rpc::JsonBytes::from_vec(vec![0x33; SECRET_KEY_SIZE])),
+            ],
+            other_pubkey_hashes: Vec::new(),
+        }
+    }
+
+    fn secret_key(byte: u8) -> bytes::Bytes {
+        bytes::Bytes::from(vec![byte; SECRET_KEY_SIZE])
+    }
+
+    #[test]
+    fn verify_the_signers() {
+        let data = [1u8; 32];
+        let multisig = config(0, 2).resolve(&secret_key(0x11), None).unwrap();
+        let lock = multisig.sign(&data, &[]).unwrap();
+        multisig.verify(&data, &[], &lock).unwrap();
+        assert!(multisig.verify(&[2u8; 32], &[], &lock).is_err());
+
+        // The same member signs twice.
+        let mut duplicated = lock.clone();
+        let signatures_start = multisig.script.len();
+        let first = lock[signatures_start..signatures_start + SIGNATURE_SIZE].to_vec();
+        duplicated[signatures_start + SIGNATURE_SIZE..].copy_from_slice(&first);
+        assert!(multisig.verify(&data, &[], &duplicated).is_err());
+
+        // A signer which is not a member.
+        let outsider = Multisig {
+            script: multisig.script.clone(),
+            signers: vec![secret_key(0x11), secret_key(0x44)],
+        };
+        let lock = outsider.sign(&data, &[]).unwrap();
+        assert!(multisig.verify(&data, &[], &lock).is_err());
+    }
+
+    #[test]
+    fn verify_the_required_first_members() {
+        let data = [1u8; 32];
+        let multisig = config(1, 2).resolve(&secret_key(0x11), None).unwrap();
+        multisig
+            .verify(&data, &[], &multisig.sign(&data, &[]).unwrap())
+            .unwrap();
+        // The last two members sign, but the first one is required.
+        let without_first = Multisig {
+            script: multisig.script.clone(),
+            signers: vec![secret_key(0x22), secret_key(0x33)],
+        };
+        let lock = without_first.sign(&data, &[]).unwrap();
+        assert!(multisig.verify(&data, &[], &lock).is_err());
+        let optional = config(0, 2).resolve(&secret_key(0x11), None).unwrap();
+        let without_first = Multisig {
+            script: optional.script.clone(),
+            signers: vec![secret_key(0x22), secret_key(0x33)],
+        };
+        let lock = without_first.sign(&data, &[]).unwrap();
+        optional.verify(&data, &[], &lock).unwrap();
+    }
+
+    #[test]
+    fn require_the_password_of_keystores() {
+        let mut config = config(0, 2);
+        config.other_secret_keys[1] = SecretKey::Keystore {
+            keystore: "keystore.json".into(),
+        };
+        let err = config.resolve(&secret_key(0x11), None).unwrap_err();
+        assert!(err.to_string().contains("the password of keystore"));
+    }
+}
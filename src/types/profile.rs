@@ -0,0 +1,101 @@
+use serde_yaml::Value;
+
+use super::{MetaData, RunEnv};
+use crate::error::{Error, Result};
+
+// The built-in profiles, as the documents of the run config, the fields which are not set take
+// their defaults.
+const LIGHT: &str = include_str!("profiles/light.yaml");
+const HEAVY: &str = include_str!("profiles/heavy.yaml");
+const BURSTY: &str = include_str!("profiles/bursty.yaml");
+
+impl RunEnv {
+    pub(crate) fn from_profile(name: &str, overrides: &str) -> Result<Self> {
+        let base = match name {
+            "light" => LIGHT,
+            "heavy" => HEAVY,
+            "bursty" => BURSTY,
+            _ => {
+                let errmsg = format!(
+                    "unknown profile [{}], available profiles are: light, heavy, bursty",
+                    name
+                );
+                return Err(Error::config(errmsg));
+            }
+        };
+        let mut value = serde_yaml::from_str(base).map_err(Error::config)?;
+        if !overrides.trim().is_empty() {
+            let overrides = serde_yaml::from_str(overrides).map_err(Error::config)?;
+            merge_yaml(&mut value, overrides);
+        }
+        serde_yaml::from_value(value).map_err(Error::config)
+    }
+
+    // The profiles have no weights since the locks are in the metadata, so weight the locks of
+    // all accounts equally if no weights are overridden.
+    pub(crate) fn seed_locks_weights(&mut self, metadata: &MetaData) -> Result<()> {
+        if self.generator.has_positive_weight() {
+            return Ok(());
+        }
+        for account in metadata.all_accounts()? {
+            self.generator.locks_weights.insert(account.lock_id, 1);
+        }
+        Ok(())
+    }
+}
+
+fn merge_yaml(base: &mut Value, overrides: Value) {
+    match (base, overrides) {
+        (Value::Mapping(base), Value::Mapping(overrides)) => {
+            for (key, value) in overrides {
+                if let Some(base_value) = base.get_mut(&key) {
+                    merge_yaml(base_value, value);
+                } else {
+                    base.insert(key, value);
+                }
+            }
+        }
+        (base, overrides) => *base = overrides,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::LockScriptId;
+
+    #[test]
+    fn load_profiles_with_overrides() {
+        for name in &["light", "heavy", "bursty"] {
+            let mut run_env = RunEnv::from_profile(name, "").unwrap();
+            // The weights are seeded from the metadata.
+            run_env
+                .generator
+                .locks_weights
+                .insert(LockScriptId::Secp256K1Blake160, 1);
+            assert!(run_env.problems().is_empty(), "profile {}", name);
+        }
+        assert!(RunEnv::from_profile("unknown", "").is_err());
+        let overrides = r#"
+delay_blocks: 10
+generator:
+  tx_fee: ~
+  fee_rate: 1000
+  locks_weights:
+    secp256k1_blake160: 1
+client:
+  pending_depth: 5
+"#;
+        let run_env = RunEnv::from_profile("bursty", overrides).unwrap();
+        assert_eq!(run_env.delay_blocks, 10);
+        assert!(run_env.generator.tx_fee.is_none());
+        assert_eq!(run_env.generator.fee_rate, Some(1000));
+        assert_eq!(run_env.generator.locks_weights.len(), 1);
+        // The fields which are not overridden are kept.
+        assert_eq!(run_env.generator.outputs_limit, 32);
+        assert_eq!(run_env.client.pending_depth, Some(5));
+        assert_eq!(run_env.client.idle_interval, 10000);
+        // Unknown fields are still rejected.
+        assert!(RunEnv::from_profile("light", "unknown: 1").is_err());
+    }
+}
@@ -1,31 +1,194 @@
-use std::{collections::HashMap, fmt, result::Result as StdResult, str::FromStr};
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    fmt, iter,
+    net::SocketAddr,
+    rc::Rc,
+    result::Result as StdResult,
+    str::FromStr,
+    time,
+};
 
-use ckb_types::H256;
-use rand::{distributions::WeightedIndex, thread_rng};
+use ckb_jsonrpc_types as rpc;
+use ckb_types::{core, packed, prelude::*, H256};
+use rand::{
+    distributions::{Uniform, WeightedIndex},
+    rngs::StdRng,
+    seq::SliceRandom as _,
+    thread_rng, Rng as _, RngCore, SeedableRng as _,
+};
 use rand_distr::{Distribution as _, Normal};
 use serde::{Deserialize, Serialize};
 
-use super::{LockInfo, LockScriptId};
-use crate::error::{Error, Result};
+use super::{CellDep, LockInfo, LockScriptId, Script, ScriptHashType, StorageConfig};
+use crate::{
+    client::{DEFAULT_MAX_RETRIES, DEFAULT_REQUEST_TIMEOUT},
+    error::{Error, Result},
+};
+
+// The occupied capacity (CKBytes) of a cell with a lock script which has 20 bytes args.
+const MIN_OUTPUT_CAPACITY: u32 = 61;
+// No chain is reorganized so deep, about 11 days of blocks on the mainnet.
+const MAX_DELAY_BLOCKS: u64 = 100_000;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(deny_unknown_fields)]
 pub(crate) struct RunEnv {
     pub(crate) delay_blocks: u64,
+    #[serde(default)]
+    pub(crate) skip_empty_blocks: bool,
+    // Synchronize at most this count of blocks in each round, so transactions are sent while
+    // catching up.
+    pub(crate) max_sync_blocks_per_round: Option<u64>,
+    // Do not synchronize the blocks which are created in this count of seconds.
+    pub(crate) min_tip_age_secs: Option<u64>,
+    #[serde(default)]
+    pub(crate) cell_source: CellSource,
+    // Replay the same random choices when it is set.
+    pub(crate) rng_seed: Option<u64>,
+    // Serve the metrics in the Prometheus format when it is set.
+    pub(crate) metrics_addr: Option<SocketAddr>,
+    // Only synchronize but not send transactions in these epochs.
+    #[serde(default)]
+    pub(crate) pause_epochs: Vec<u64>,
+    // Stop after sent this count of transactions successfully.
+    pub(crate) max_transactions: Option<u64>,
+    // Consolidate the smallest cells when more cells than this are tracked.
+    pub(crate) max_live_cells: Option<usize>,
+    #[serde(default)]
+    pub(crate) storage: StorageConfig,
     pub(crate) generator: GeneratorConfig,
     pub(crate) client: ClientConfig,
 }
 
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum CellSource {
+    #[default]
+    BlockSync,
+    Indexer(IndexerConfig),
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct IndexerConfig {
+    // Use the JSON-RPC of the CKB node when it is not set.
+    pub(crate) url: Option<String>,
+    pub(crate) page_size: u32,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(deny_unknown_fields)]
 pub(crate) struct GeneratorConfig {
+    #[serde(default)]
+    pub(crate) mode: GeneratorMode,
     pub(crate) inputs_limit: usize,
-    pub(crate) inputs_size_normal_distribution: NormalDistributionConfig,
+    // Deprecated, use "input_size_distribution" instead.
+    pub(crate) inputs_size_normal_distribution: Option<NormalDistributionConfig>,
+    pub(crate) input_size_distribution: Option<InputSizeDistribution>,
+    pub(crate) max_locks_per_tx: Option<usize>,
+    pub(crate) max_selecting_inputs: Option<usize>,
     pub(crate) outputs_limit: usize,
-    pub(crate) output_capacity: u32,
+    // A shorthand for the same "output_capacity_min" and "output_capacity_max".
+    pub(crate) output_capacity: Option<u32>,
+    pub(crate) output_capacity_min: Option<u32>,
+    pub(crate) output_capacity_max: Option<u32>,
     pub(crate) output_min_capacity: u32,
-    pub(crate) tx_fee: u64,
+    pub(crate) output_data_size: Option<u32>,
+    pub(crate) tx_fee: Option<u64>,
+    // Shannons per 1000 bytes, an alternative to the fixed "tx_fee".
+    pub(crate) fee_rate: Option<u64>,
+    // Add a random value in "0..=tx_fee_jitter" shannons to the fee of each transaction.
+    pub(crate) tx_fee_jitter: Option<u64>,
     pub(crate) locks_weights: HashMap<LockScriptId, usize>,
+    // The weights of accounts by their lock hashes, override the weights of their lock scripts.
+    #[serde(default)]
+    pub(crate) account_weights: HashMap<H256, usize>,
+    #[serde(default)]
+    pub(crate) exclude_zero_weight_inputs: bool,
+    pub(crate) external_locks: Option<ExternalLocks>,
+    pub(crate) dao_deposit: Option<DaoDepositConfig>,
+    #[serde(default)]
+    pub(crate) change_lock: ChangeLock,
+    pub(crate) max_total_output_data_bytes: Option<usize>,
+    #[serde(default)]
+    pub(crate) witness_padding: WitnessPadding,
+    pub(crate) parallel_sign_threshold: Option<usize>,
+    #[serde(default)]
+    pub(crate) change_minimum: ChangeMinimum,
+    #[serde(default)]
+    pub(crate) capacity_split: CapacitySplit,
+    #[serde(default)]
+    pub(crate) cell_selection: CellSelectionStrategy,
+    pub(crate) output_capacity_jitter: Option<CapacityJitter>,
+    pub(crate) output_type_script: Option<OutputTypeScript>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ChangeLock {
+    SameAsFirstInput,
+    #[default]
+    Sampled,
+    Fixed(rpc::Script),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum FeePolicy {
+    Fixed(u64),
+    Rate(u64),
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum GeneratorMode {
+    #[default]
+    Split,
+    // Merge as many inputs as allowed into one output.
+    Consolidate,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum CapacitySplit {
+    #[default]
+    ChangeFirst,
+    EvenWithRemainderLast,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum CellSelectionStrategy {
+    // The order of the out points, as they are stored.
+    #[default]
+    StorageOrder,
+    SmallestFirst,
+    LargestFirst,
+    Random,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum CapacityJitter {
+    Shannons(u64),
+    Percent(u8),
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ChangeMinimum {
+    MergeIntoOutput,
+    #[default]
+    DropOutput,
+    AddInput,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum WitnessPadding {
+    #[default]
+    None,
+    MatchInputs,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -34,6 +197,57 @@ pub(crate) struct ClientConfig {
     pub(crate) idle_interval: u64,
     pub(crate) success_interval: u64,
     pub(crate) failure_interval: u64,
+    pub(crate) pending_depth: Option<usize>,
+    pub(crate) after_sync_cooldown_ms: Option<u64>,
+    pub(crate) tip_poll_min_interval_ms: Option<u64>,
+    // Wait for the new tips pushed by the node instead of polling, only for WebSocket.
+    #[serde(default)]
+    pub(crate) subscribe_new_tip: bool,
+    pub(crate) reconnect: Option<ReconnectConfig>,
+    pub(crate) request_timeout_millis: Option<u64>,
+    pub(crate) max_retries: Option<u32>,
+    pub(crate) outputs_validator: Option<rpc::OutputsValidator>,
+    // Replace the "success_interval" with a rate limiter when it is set.
+    pub(crate) target_tps: Option<f64>,
+    // Abort when the version of the CKB node is older than it, such as "0.40.0".
+    pub(crate) min_node_version: Option<String>,
+    // Sign and send transactions in several workers.
+    pub(crate) send_concurrency: Option<usize>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ReconnectConfig {
+    pub(crate) retries: usize,
+    pub(crate) interval: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct DaoDepositConfig {
+    pub(crate) script: Script,
+    pub(crate) ratio: u8,
+}
+
+// The type script of all generated outputs.
+// The lock scripts which are not owned by any account, the capacity of outputs with them is sent
+// away.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ExternalLocks {
+    // The weight of all external locks, then each of them is chosen evenly.
+    pub(crate) weight: usize,
+    pub(crate) scripts: Vec<rpc::Script>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct OutputTypeScript {
+    pub(crate) code_hash: H256,
+    pub(crate) hash_type: ScriptHashType,
+    #[serde(default)]
+    pub(crate) args: rpc::JsonBytes,
+    pub(crate) cell_deps: Vec<CellDep>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -43,17 +257,47 @@ pub(crate) struct NormalDistributionConfig {
     pub(crate) std_dev: u8,
 }
 
-pub(crate) struct InputSizeGenerator(Normal<f32>);
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum InputSizeDistribution {
+    Normal(NormalDistributionConfig),
+    Uniform(UniformDistributionConfig),
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct UniformDistributionConfig {
+    pub(crate) min: u8,
+    pub(crate) max: u8,
+}
+
+pub(crate) struct InputSizeGenerator {
+    distribution: InputSizeSampler,
+    rng: SimRng,
+}
+
+enum InputSizeSampler {
+    Normal(Normal<f32>),
+    Uniform(Uniform<f32>),
+}
 
 pub(crate) struct LockGenerator {
-    items: Vec<(H256, usize)>,
+    // The locks of accounts, the external locks share the last weight of the index.
+    items: Vec<(packed::Script, usize)>,
+    external_locks: Vec<packed::Script>,
     index: WeightedIndex<usize>,
+    rng: SimRng,
 }
 
+// The random number generator for all random choices, the thread-local generator is used when
+// there is no seed.
+#[derive(Clone)]
+pub(crate) struct SimRng(Option<Rc<RefCell<StdRng>>>);
+
 impl FromStr for RunEnv {
     type Err = serde_yaml::Error;
     fn from_str(s: &str) -> StdResult<Self, Self::Err> {
-        serde_yaml::from_str(&s)
+        serde_yaml::from_str(s)
     }
 }
 
@@ -65,40 +309,493 @@ impl fmt::Display for RunEnv {
     }
 }
 
+impl ClientConfig {
+    pub(crate) fn request_timeout(&self) -> time::Duration {
+        self.request_timeout_millis
+            .map(time::Duration::from_millis)
+            .unwrap_or(DEFAULT_REQUEST_TIMEOUT)
+    }
+
+    pub(crate) fn max_retries(&self) -> u32 {
+        self.max_retries.unwrap_or(DEFAULT_MAX_RETRIES)
+    }
+
+    // The interval between two transactions to hold the target TPS.
+    pub(crate) fn target_interval(&self) -> Result<Option<time::Duration>> {
+        self.target_tps
+            .map(|tps| {
+                if tps.is_finite() && tps > 0.0 {
+                    Ok(time::Duration::from_secs_f64(1.0 / tps))
+                } else {
+                    let errmsg = format!("client.target_tps ({}) should be positive", tps);
+                    Err(Error::config(errmsg))
+                }
+            })
+            .transpose()
+    }
+
+    pub(crate) fn send_concurrency(&self) -> Result<Option<usize>> {
+        match self.send_concurrency {
+            Some(0) => Err(Error::config("client.send_concurrency should be positive")),
+            concurrency => Ok(concurrency),
+        }
+    }
+
+    pub(crate) fn min_node_version(&self) -> Result<Option<(u64, u64, u64)>> {
+        self.min_node_version
+            .as_ref()
+            .map(|version| {
+                parse_version(version).ok_or_else(|| {
+                    let errmsg = format!(
+                        "client.min_node_version ({}) should be as \"major.minor.patch\"",
+                        version
+                    );
+                    Error::config(errmsg)
+                })
+            })
+            .transpose()
+    }
+}
+
+// Parse the leading "major.minor.patch" of a version, such as "0.40.0 (abcdef 2021-01-01)".
+pub(crate) fn parse_version(version: &str) -> Option<(u64, u64, u64)> {
+    let mut numbers = version
+        .trim()
+        .trim_start_matches('v')
+        .split(|c: char| !c.is_ascii_digit() && c != '.')
+        .next()?
+        .split('.')
+        .map(|number| number.parse::<u64>().ok());
+    let major = numbers.next()??;
+    let minor = numbers.next()??;
+    let patch = numbers.next()??;
+    Some((major, minor, patch))
+}
+
+impl RunEnv {
+    // Check whether "delay_blocks" is reasonable for the start block.
+    pub(crate) fn check_delay_blocks(&self, start_block: core::BlockNumber) -> Result<()> {
+        if self.delay_blocks > MAX_DELAY_BLOCKS {
+            let errmsg = format!(
+                "delay_blocks ({}) is too large, it should be at most {}",
+                self.delay_blocks, MAX_DELAY_BLOCKS
+            );
+            return Err(Error::config(errmsg));
+        }
+        if start_block.checked_add(self.delay_blocks).is_none() {
+            let errmsg = format!(
+                "delay_blocks ({}) is too large since the start block is #{}",
+                self.delay_blocks, start_block
+            );
+            return Err(Error::config(errmsg));
+        }
+        Ok(())
+    }
+
+    // Check the values which can not be checked when parsing, return all problems.
+    pub(crate) fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if let CellSource::Indexer(ref indexer) = self.cell_source {
+            if let Some(ref url) = indexer.url {
+                if let Err(err) = url::Url::parse(url) {
+                    problems.push(format!(
+                        "cell_source.indexer.url ({}) is invalid since {}",
+                        url, err
+                    ));
+                }
+            }
+            if indexer.page_size == 0 {
+                problems.push("cell_source.indexer.page_size should be positive".to_owned());
+            }
+        }
+        if self.max_sync_blocks_per_round == Some(0) {
+            problems.push("max_sync_blocks_per_round should be positive".to_owned());
+        }
+        if self.max_transactions == Some(0) {
+            problems.push("max_transactions should be positive".to_owned());
+        }
+        if self.max_live_cells == Some(0) {
+            problems.push("max_live_cells should be positive".to_owned());
+        }
+        problems.extend(self.storage.problems());
+        let generator = &self.generator;
+        if generator.inputs_limit == 0 {
+            problems.push("generator.inputs_limit should be positive".to_owned());
+        }
+        if generator.outputs_limit == 0 {
+            problems.push("generator.outputs_limit should be positive".to_owned());
+        }
+        if generator.output_min_capacity < MIN_OUTPUT_CAPACITY {
+            problems.push(format!(
+                "generator.output_min_capacity ({}) should not be less than {}",
+                generator.output_min_capacity, MIN_OUTPUT_CAPACITY
+            ));
+        }
+        match generator.output_capacity_range() {
+            Ok((min, _)) if generator.output_min_capacity > min => {
+                problems.push(format!(
+                    "generator.output_min_capacity ({}) should not be greater than \
+                    the output capacity ({})",
+                    generator.output_min_capacity, min
+                ));
+            }
+            Ok((min, _)) if generator.output_min_bytes() > u64::from(min) => {
+                problems.push(format!(
+                    "the output capacity ({}) should not be less than \
+                    generator.output_min_capacity ({}) plus generator.output_data_size ({}) \
+                    and generator.output_type_script ({})",
+                    min,
+                    generator.output_min_capacity,
+                    generator.output_data_size.unwrap_or(0),
+                    generator.output_type_script_bytes()
+                ));
+            }
+            Ok(_) => {}
+            Err(err) => problems.push(err.to_string()),
+        }
+        if !generator.has_positive_weight() {
+            problems.push(
+                "generator.locks_weights, generator.account_weights or \
+                generator.external_locks.weight should have a positive weight"
+                    .to_owned(),
+            );
+        }
+        if let Some(ref external) = generator.external_locks {
+            if external.weight > 0 && external.scripts.is_empty() {
+                problems.push(
+                    "generator.external_locks.scripts should not be empty since it has a weight"
+                        .to_owned(),
+                );
+            }
+            for (index, script) in external.scripts.iter().enumerate() {
+                // The capacity, the code hash, the hash type and the args.
+                let occupied = 8 + 32 + 1 + script.args.len() as u64;
+                if occupied > u64::from(generator.output_min_capacity) {
+                    problems.push(format!(
+                        "generator.external_locks.scripts[{}] occupies {} CKBytes, \
+                        which is more than generator.output_min_capacity ({})",
+                        index, occupied, generator.output_min_capacity
+                    ));
+                }
+            }
+        }
+        if let Some(ref dao) = generator.dao_deposit {
+            if dao.ratio > 100 {
+                problems.push(format!(
+                    "generator.dao_deposit.ratio ({}) should not be greater than 100",
+                    dao.ratio
+                ));
+            }
+        }
+        if let Some(CapacityJitter::Percent(percent)) = generator.output_capacity_jitter {
+            if percent > 100 {
+                problems.push(format!(
+                    "generator.output_capacity_jitter.percent ({}) should not be greater than 100",
+                    percent
+                ));
+            }
+        }
+        if let Err(err) = generator.input_size_generator(&SimRng::new(None)) {
+            problems.push(err.to_string());
+        }
+        if let Err(err) = generator.fee_policy() {
+            problems.push(err.to_string());
+        }
+        if let Err(err) = self.client.target_interval() {
+            problems.push(err.to_string());
+        }
+        if let Err(err) = self.client.min_node_version() {
+            problems.push(err.to_string());
+        }
+        if let Err(err) = self.client.send_concurrency() {
+            problems.push(err.to_string());
+        }
+        problems
+    }
+}
+
 impl GeneratorConfig {
-    pub(crate) fn input_size_generator(&self) -> Result<InputSizeGenerator> {
-        InputSizeGenerator::new(
-            self.inputs_size_normal_distribution.mean,
-            self.inputs_size_normal_distribution.std_dev,
-        )
+    pub(crate) fn has_positive_weight(&self) -> bool {
+        self.locks_weights.values().any(|weight| *weight > 0)
+            || self.account_weights.values().any(|weight| *weight > 0)
+            || self
+                .external_locks
+                .as_ref()
+                .map(|external| external.weight > 0)
+                .unwrap_or(false)
+    }
+
+    // The same config, but spends the smallest cells into one output.
+    pub(crate) fn consolidating(&self) -> Self {
+        Self {
+            mode: GeneratorMode::Consolidate,
+            cell_selection: CellSelectionStrategy::SmallestFirst,
+            ..self.clone()
+        }
+    }
+
+    // The minimum capacity (CKBytes) of an output, includes its data and its type script.
+    pub(crate) fn output_min_bytes(&self) -> u64 {
+        u64::from(self.output_min_capacity)
+            + u64::from(self.output_data_size.unwrap_or(0))
+            + self.output_type_script_bytes()
+    }
+
+    // The occupied capacity (CKBytes) of the type script of an output.
+    pub(crate) fn output_type_script_bytes(&self) -> u64 {
+        self.output_type_script
+            .as_ref()
+            .map(OutputTypeScript::occupied_bytes)
+            .unwrap_or(0)
+    }
+
+    // The range of the capacity (CKBytes) of each output, both inclusive.
+    pub(crate) fn output_capacity_range(&self) -> Result<(u32, u32)> {
+        match (
+            self.output_capacity,
+            self.output_capacity_min,
+            self.output_capacity_max,
+        ) {
+            (Some(capacity), None, None) => Ok((capacity, capacity)),
+            (None, Some(min), Some(max)) if min <= max => Ok((min, max)),
+            (None, Some(min), Some(max)) => {
+                let errmsg = format!(
+                    "generator.output_capacity_min ({}) should not be greater than \
+                    generator.output_capacity_max ({})",
+                    min, max
+                );
+                Err(Error::config(errmsg))
+            }
+            (None, None, None) => Err(Error::config(
+                "generator.output_capacity or \
+                generator.output_capacity_min and generator.output_capacity_max are required",
+            )),
+            (Some(_), _, _) => Err(Error::config(
+                "generator.output_capacity and \
+                generator.output_capacity_min or generator.output_capacity_max should not be both set",
+            )),
+            (None, _, _) => Err(Error::config(
+                "generator.output_capacity_min and generator.output_capacity_max should be both set",
+            )),
+        }
+    }
+
+    pub(crate) fn fee_policy(&self) -> Result<FeePolicy> {
+        match (self.tx_fee, self.fee_rate) {
+            (Some(fee), None) => Ok(FeePolicy::Fixed(fee)),
+            (None, Some(rate)) => Ok(FeePolicy::Rate(rate)),
+            (None, None) => Err(Error::config(
+                "generator.tx_fee or generator.fee_rate is required",
+            )),
+            (Some(_), Some(_)) => Err(Error::config(
+                "generator.tx_fee and generator.fee_rate should not be both set",
+            )),
+        }
+    }
+
+    pub(crate) fn input_size_generator(&self, rng: &SimRng) -> Result<InputSizeGenerator> {
+        let distribution = match (
+            &self.inputs_size_normal_distribution,
+            &self.input_size_distribution,
+        ) {
+            (Some(normal), None) | (None, Some(InputSizeDistribution::Normal(normal))) => {
+                InputSizeSampler::normal(normal.mean, normal.std_dev)?
+            }
+            (None, Some(InputSizeDistribution::Uniform(uniform))) => {
+                InputSizeSampler::uniform(uniform.min, uniform.max)?
+            }
+            (None, None) => {
+                return Err(Error::config(
+                    "generator.input_size_distribution is required",
+                ))
+            }
+            (Some(_), Some(_)) => {
+                return Err(Error::config(
+                    "generator.input_size_distribution and \
+                    generator.inputs_size_normal_distribution should not be both set",
+                ))
+            }
+        };
+        Ok(InputSizeGenerator {
+            distribution,
+            rng: rng.clone(),
+        })
+    }
+
+    pub(crate) fn zero_weight_accounts(&self, accounts: &HashMap<H256, LockInfo>) -> HashSet<H256> {
+        accounts
+            .iter()
+            .filter(|(hash, info)| self.account_weight(hash, info) == 0)
+            .map(|(hash, _)| hash.to_owned())
+            .collect()
     }
 
     pub(crate) fn lock_generator(
         &self,
         accounts: &HashMap<H256, LockInfo>,
+        rng: &SimRng,
     ) -> Result<LockGenerator> {
-        let items = accounts
+        let mut unknown_hashes = self
+            .account_weights
+            .keys()
+            .filter(|hash| !accounts.contains_key(hash))
+            .collect::<Vec<_>>();
+        if !unknown_hashes.is_empty() {
+            unknown_hashes.sort();
+            let errmsg = format!(
+                "accounts {} in the account weights are not found",
+                unknown_hashes
+                    .into_iter()
+                    .map(|hash| format!("{:#x}", hash))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+            return Err(Error::config(errmsg));
+        }
+        let mut weights = accounts
             .iter()
-            .map(|(hash, info)| {
-                let weight = self.locks_weights.get(&info.id).cloned().unwrap_or(0);
-                (hash.to_owned(), weight)
-            })
+            .map(|(hash, info)| (hash.to_owned(), self.account_weight(hash, info)))
             .collect::<Vec<_>>();
-        LockGenerator::new(items)
+        // The order of a hash map is random, sort it to make the choices reproducible.
+        weights.sort();
+        let items = weights
+            .into_iter()
+            .map(|(hash, weight)| (accounts[&hash].script.clone(), weight))
+            .collect();
+        let external = self
+            .external_locks
+            .as_ref()
+            .map(|external| {
+                let scripts = external
+                    .scripts
+                    .iter()
+                    .map(|script| {
+                        let script: packed::Script = script.clone().into();
+                        let hash: H256 = script.calc_script_hash().unpack();
+                        if accounts.contains_key(&hash) {
+                            let errmsg =
+                                format!("external lock {:#x} is the lock of an account", hash);
+                            Err(Error::config(errmsg))
+                        } else {
+                            Ok(script)
+                        }
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                Ok::<_, Error>((scripts, external.weight))
+            })
+            .transpose()?;
+        LockGenerator::new(items, external, rng.clone())
+    }
+
+    fn account_weight(&self, lock_hash: &H256, info: &LockInfo) -> usize {
+        self.account_weights
+            .get(lock_hash)
+            .or_else(|| self.locks_weights.get(&info.id))
+            .cloned()
+            .unwrap_or(0)
     }
 }
 
-impl InputSizeGenerator {
-    fn new(mean: u8, std_dev: u8) -> Result<Self> {
+impl CapacityJitter {
+    pub(crate) fn apply(&self, capacity: u64, rng: &SimRng) -> u64 {
+        let range = match self {
+            Self::Shannons(shannons) => *shannons,
+            Self::Percent(percent) => capacity / 100 * u64::from((*percent).min(100)),
+        };
+        if range == 0 {
+            capacity
+        } else {
+            let offset =
+                rng.with(|rng| rng.gen_range(0, range.saturating_mul(2).saturating_add(1)));
+            capacity.saturating_add(offset).saturating_sub(range)
+        }
+    }
+}
+
+impl DaoDepositConfig {
+    pub(crate) fn should_deposit(&self, rng: &SimRng) -> bool {
+        rng.with(|rng| rng.gen_ratio(u32::from(self.ratio), 100))
+    }
+
+    pub(crate) fn type_script(&self) -> packed::Script {
+        packed::Script::new_builder()
+            .code_hash(self.script.code_hash.pack())
+            .hash_type(self.script.hash_type.into())
+            .build()
+    }
+}
+
+impl OutputTypeScript {
+    pub(crate) fn script(&self) -> packed::Script {
+        packed::Script::new_builder()
+            .code_hash(self.code_hash.pack())
+            .hash_type(self.hash_type.into())
+            .args(self.args.clone().into_bytes().pack())
+            .build()
+    }
+
+    // The code hash, the hash type and the args.
+    fn occupied_bytes(&self) -> u64 {
+        32 + 1 + self.args.len() as u64
+    }
+}
+
+impl SimRng {
+    pub(crate) fn new(seed: Option<u64>) -> Self {
+        Self(seed.map(|seed| Rc::new(RefCell::new(StdRng::seed_from_u64(seed)))))
+    }
+
+    // A random number between the min and the max, both inclusive.
+    pub(crate) fn gen_between(&self, min: u64, max: u64) -> u64 {
+        if min >= max {
+            min
+        } else {
+            self.with(|rng| rng.gen_range(min, max.saturating_add(1)))
+        }
+    }
+
+    pub(crate) fn shuffle<T>(&self, items: &mut [T]) {
+        self.with(|rng| items.shuffle(rng))
+    }
+
+    fn with<T, F: FnOnce(&mut dyn RngCore) -> T>(&self, f: F) -> T {
+        if let Some(ref rng) = self.0 {
+            f(&mut *rng.borrow_mut())
+        } else {
+            f(&mut thread_rng())
+        }
+    }
+}
+
+impl InputSizeSampler {
+    fn normal(mean: u8, std_dev: u8) -> Result<Self> {
         Normal::new(f32::from(mean), f32::from(std_dev))
             .map_err(Error::runtime)
-            .map(Self)
+            .map(Self::Normal)
+    }
+
+    fn uniform(min: u8, max: u8) -> Result<Self> {
+        if max == 0 || min > max {
+            let errmsg = format!(
+                "the uniform distribution [{}, {}] of the input size should be positive and \
+                the min should not be greater than the max",
+                min, max
+            );
+            return Err(Error::config(errmsg));
+        }
+        let uniform = Uniform::new_inclusive(f32::from(min), f32::from(max));
+        Ok(Self::Uniform(uniform))
     }
+}
 
+impl InputSizeGenerator {
     pub(crate) fn generate(&self) -> usize {
         let mut ret;
         loop {
-            ret = self.0.sample(&mut thread_rng());
+            ret = self.rng.with(|rng| match self.distribution {
+                InputSizeSampler::Normal(ref normal) => normal.sample(rng),
+                InputSizeSampler::Uniform(ref uniform) => uniform.sample(rng),
+            });
             if ret > 0.0 && ret < 1000.0 {
                 break;
             }
@@ -108,14 +805,42 @@ impl InputSizeGenerator {
 }
 
 impl LockGenerator {
-    fn new(items: Vec<(H256, usize)>) -> Result<Self> {
-        let index = WeightedIndex::new(items.iter().map(|item| item.1)).map_err(Error::runtime)?;
-        Ok(Self { items, index })
+    fn new(
+        items: Vec<(packed::Script, usize)>,
+        external: Option<(Vec<packed::Script>, usize)>,
+        rng: SimRng,
+    ) -> Result<Self> {
+        let (external_locks, external_weight) = external
+            .filter(|(scripts, weight)| !scripts.is_empty() && *weight > 0)
+            .unwrap_or_default();
+        if items.iter().all(|item| item.1 == 0) && external_weight == 0 {
+            let errmsg = "no account has a positive weight, please check the locks weights";
+            return Err(Error::config(errmsg));
+        }
+        let weights = items.iter().map(|item| item.1);
+        let index = if external_locks.is_empty() {
+            WeightedIndex::new(weights)
+        } else {
+            WeightedIndex::new(weights.chain(iter::once(external_weight)))
+        }
+        .map_err(Error::runtime)?;
+        Ok(Self {
+            items,
+            external_locks,
+            index,
+            rng,
+        })
     }
 
-    pub(crate) fn generate(&self) -> H256 {
-        self.items[self.index.sample(&mut thread_rng())]
-            .0
-            .to_owned()
+    pub(crate) fn generate(&self) -> packed::Script {
+        let index = self.rng.with(|rng| self.index.sample(rng));
+        if let Some((script, _)) = self.items.get(index) {
+            script.to_owned()
+        } else {
+            let index = self
+                .rng
+                .gen_between(0, self.external_locks.len() as u64 - 1);
+            self.external_locks[index as usize].to_owned()
+        }
     }
 }
@@ -1,32 +1,247 @@
 use ckb_types::{core, packed, prelude::*, H256};
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Error, Result};
+
+// Tune the RocksDB, the explicit options override the profile.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct StorageConfig {
+    #[serde(default)]
+    pub(crate) profile: StorageProfile,
+    // In bytes.
+    pub(crate) write_buffer_size: Option<usize>,
+    // -1 means no limit.
+    pub(crate) max_open_files: Option<i32>,
+    pub(crate) max_background_jobs: Option<i32>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum StorageProfile {
+    #[default]
+    Small,
+    Large,
+}
 
 #[derive(Debug, Clone)]
 pub(crate) struct CellInfo {
     pub(crate) capacity: core::Capacity,
     pub(crate) lock_hash: H256,
+    pub(crate) block_number: core::BlockNumber,
+}
+
+impl StorageConfig {
+    pub(crate) fn write_buffer_size(&self) -> usize {
+        self.write_buffer_size.unwrap_or(match self.profile {
+            StorageProfile::Small => (1 << 20) * 8,
+            StorageProfile::Large => (1 << 20) * 64,
+        })
+    }
+
+    pub(crate) fn max_open_files(&self) -> i32 {
+        self.max_open_files.unwrap_or(match self.profile {
+            StorageProfile::Small => 64,
+            StorageProfile::Large => 1024,
+        })
+    }
+
+    pub(crate) fn max_background_jobs(&self) -> i32 {
+        self.max_background_jobs.unwrap_or(match self.profile {
+            StorageProfile::Small => 4,
+            StorageProfile::Large => 8,
+        })
+    }
+
+    pub(crate) fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if self.write_buffer_size() == 0 {
+            problems.push("storage.write_buffer_size should be positive".to_owned());
+        }
+        if self.max_open_files() == 0 || self.max_open_files() < -1 {
+            problems.push("storage.max_open_files should be positive or -1".to_owned());
+        }
+        if self.max_background_jobs() <= 0 {
+            problems.push("storage.max_background_jobs should be positive".to_owned());
+        }
+        problems
+    }
 }
 
 impl CellInfo {
-    pub(crate) fn new(capacity: core::Capacity, lock_hash: H256) -> Self {
+    pub(crate) const SIZE: usize = 8 + 32 + 8;
+    pub(crate) const LEGACY_SIZE: usize = 8 + 32;
+
+    pub(crate) fn new(
+        capacity: core::Capacity,
+        lock_hash: H256,
+        block_number: core::BlockNumber,
+    ) -> Self {
         Self {
             capacity,
             lock_hash,
+            block_number,
         }
     }
 
     pub(crate) fn to_vec(&self) -> Vec<u8> {
-        let mut output = [0u8; 8 + 32];
+        let mut output = [0u8; Self::SIZE];
         let cap: packed::Uint64 = self.capacity.pack();
         let hash: packed::Byte32 = self.lock_hash.pack();
-        (&mut output[0..8]).copy_from_slice(cap.as_slice());
-        (&mut output[8..40]).copy_from_slice(hash.as_slice());
+        let number: packed::Uint64 = self.block_number.pack();
+        output[0..8].copy_from_slice(cap.as_slice());
+        output[8..40].copy_from_slice(hash.as_slice());
+        output[40..48].copy_from_slice(number.as_slice());
         output.to_vec()
     }
 
     pub(crate) fn from_slice(slice: &[u8]) -> Self {
+        let number: core::BlockNumber =
+            packed::Uint64::new_unchecked(slice[40..48].to_vec().into()).unpack();
+        Self::from_legacy_slice(slice, number)
+    }
+
+    /// Decode the layout without the block number, which was used before the block number was
+    /// stored.
+    pub(crate) fn from_legacy_slice(slice: &[u8], block_number: core::BlockNumber) -> Self {
         let cap: core::Capacity =
-            packed::Uint64::new_unchecked((&slice[0..8]).to_vec().into()).unpack();
-        let hash: H256 = packed::Byte32::new_unchecked((&slice[8..40]).to_vec().into()).unpack();
-        Self::new(cap, hash)
+            packed::Uint64::new_unchecked(slice[0..8].to_vec().into()).unpack();
+        let hash: H256 = packed::Byte32::new_unchecked(slice[8..40].to_vec().into()).unpack();
+        Self::new(cap, hash, block_number)
+    }
+}
+
+// A transaction which was sent, with the tip block number at that time.
+#[derive(Debug, Clone)]
+pub(crate) struct SentTx {
+    pub(crate) sent_at: core::BlockNumber,
+    pub(crate) inputs: Vec<packed::OutPoint>,
+}
+
+impl SentTx {
+    pub(crate) fn to_vec(&self) -> Vec<u8> {
+        let number: packed::Uint64 = self.sent_at.pack();
+        let inputs = packed::OutPointVec::new_builder()
+            .set(self.inputs.clone())
+            .build();
+        let mut output = Vec::with_capacity(8 + inputs.as_slice().len());
+        output.extend_from_slice(number.as_slice());
+        output.extend_from_slice(inputs.as_slice());
+        output
+    }
+
+    pub(crate) fn from_slice(slice: &[u8]) -> Result<Self> {
+        if slice.len() < 8 {
+            let errmsg = format!(
+                "the stored sent transaction should be at least 8 bytes, but got {} bytes",
+                slice.len()
+            );
+            return Err(Error::storage(errmsg));
+        }
+        let sent_at: core::BlockNumber =
+            packed::Uint64::new_unchecked(slice[0..8].to_vec().into()).unpack();
+        let inputs = packed::OutPointVec::from_slice(&slice[8..])
+            .map_err(Error::storage)?
+            .into_iter()
+            .collect();
+        Ok(Self { sent_at, inputs })
+    }
+}
+
+// A synchronized block, with the stored cells which were removed by it, to roll back the block
+// when it becomes orphaned.
+#[derive(Debug, Clone)]
+pub(crate) struct SyncedBlock {
+    pub(crate) hash: H256,
+    // The cells, and whether they were spent by the sent transactions, which are in the cache.
+    pub(crate) removed: Vec<(packed::OutPoint, CellInfo, bool)>,
+}
+
+impl SyncedBlock {
+    const REMOVED_SIZE: usize = 36 + CellInfo::SIZE + 1;
+    const LEGACY_REMOVED_SIZE: usize = 36 + CellInfo::SIZE;
+
+    pub(crate) fn to_vec(&self) -> Vec<u8> {
+        let mut output = Vec::with_capacity(32 + Self::REMOVED_SIZE * self.removed.len());
+        output.extend_from_slice(self.hash.as_bytes());
+        for (op, info, spent) in &self.removed {
+            output.extend_from_slice(op.as_slice());
+            output.extend_from_slice(&info.to_vec());
+            output.push(u8::from(*spent));
+        }
+        output
+    }
+
+    pub(crate) fn from_slice(slice: &[u8]) -> Result<Self> {
+        Self::from_slice_with(slice, Self::REMOVED_SIZE, |chunk| {
+            chunk[Self::LEGACY_REMOVED_SIZE] != 0
+        })
+    }
+
+    /// Decode the layout without the spent flags, which was used before the flags were stored,
+    /// the cells are treated as not spent.
+    pub(crate) fn from_legacy_slice(slice: &[u8]) -> Result<Self> {
+        Self::from_slice_with(slice, Self::LEGACY_REMOVED_SIZE, |_| false)
+    }
+
+    fn from_slice_with<F>(slice: &[u8], removed_size: usize, spent: F) -> Result<Self>
+    where
+        F: Fn(&[u8]) -> bool,
+    {
+        if slice.len() < 32 || !(slice.len() - 32).is_multiple_of(removed_size) {
+            let errmsg = format!(
+                "the stored synchronized block has an unexpected size {} bytes",
+                slice.len()
+            );
+            return Err(Error::storage(errmsg));
+        }
+        let hash = H256::from_slice(&slice[0..32]).map_err(Error::storage)?;
+        let removed = slice[32..]
+            .chunks(removed_size)
+            .map(|chunk| {
+                let op = packed::OutPoint::from_slice(&chunk[0..36]).map_err(Error::storage)?;
+                let info = CellInfo::from_slice(&chunk[36..]);
+                Ok((op, info, spent(chunk)))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { hash, removed })
+    }
+}
+
+// The statistics of all runs on a data directory.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct RunStats {
+    pub(crate) txs: u64,
+    // The total capacity of the outputs of the sent transactions, in shannons.
+    pub(crate) capacity: u64,
+}
+
+impl RunStats {
+    const SIZE: usize = 8 + 8;
+
+    pub(crate) fn to_vec(&self) -> Vec<u8> {
+        let mut output = Vec::with_capacity(Self::SIZE);
+        output.extend_from_slice(&self.txs.to_le_bytes());
+        output.extend_from_slice(&self.capacity.to_le_bytes());
+        output
+    }
+
+    pub(crate) fn from_slice(slice: &[u8]) -> Result<Self> {
+        if slice.len() != Self::SIZE {
+            let errmsg = format!(
+                "the stored statistics should be {} bytes, but got {} bytes",
+                Self::SIZE,
+                slice.len()
+            );
+            return Err(Error::storage(errmsg));
+        }
+        let mut txs_le = [0u8; 8];
+        let mut capacity_le = [0u8; 8];
+        txs_le.copy_from_slice(&slice[0..8]);
+        capacity_le.copy_from_slice(&slice[8..16]);
+        Ok(Self {
+            txs: u64::from_le_bytes(txs_le),
+            capacity: u64::from_le_bytes(capacity_le),
+        })
     }
 }